@@ -0,0 +1,97 @@
+//! Generator of synthetic catalogs for the benchmarks.
+//!
+//! The scale is configured with environment variables, so that the same benchmarks
+//! can be run quickly during development and at realistic scale before a release:
+//! * BENCH_YEARS - number of years (default 2)
+//! * BENCH_DAYS_PER_YEAR - number of days with photos in each year (default 365)
+//! * BENCH_PHOTOS_PER_DAY - number of photos in each day (default 20)
+//!
+//! E.g. 20 years of a photo enthusiast:
+//! `BENCH_YEARS=20 BENCH_PHOTOS_PER_DAY=200 cargo bench`
+
+#![allow(dead_code)]
+
+use std::env;
+
+use photo_sync_tst::catalog::CatalogNode;
+use photo_sync_tst::local_storage::{object_id, Data, LocalStorage, Peer};
+use photo_sync_tst::opaque_date::YearMonthDay;
+
+const FIRST_YEAR: u32 = 2000;
+const DAYS_IN_MONTH: [u32; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+#[derive(Debug, Clone, Copy)]
+pub struct DatasetConfig {
+    pub years: u32,
+    pub days_per_year: u32,
+    pub photos_per_day: u32,
+}
+
+impl DatasetConfig {
+    pub fn from_env() -> Self {
+        DatasetConfig {
+            years: env_or("BENCH_YEARS", 2),
+            days_per_year: env_or("BENCH_DAYS_PER_YEAR", 365).min(365),
+            photos_per_day: env_or("BENCH_PHOTOS_PER_DAY", 20),
+        }
+    }
+
+    pub fn total_photos(&self) -> u64 {
+        self.years as u64 * self.days_per_year as u64 * self.photos_per_day as u64
+    }
+
+    /// All days of the dataset, in ascending order.
+    pub fn days(&self) -> Vec<YearMonthDay> {
+        let days_of_year = (1..=12u32)
+            .flat_map(|m| (1..=DAYS_IN_MONTH[m as usize - 1]).map(move |d| m * 100 + d))
+            .take(self.days_per_year as usize)
+            .collect::<Vec<_>>();
+        (FIRST_YEAR..FIRST_YEAR + self.years)
+            .flat_map(|y| days_of_year.iter().map(move |md| y * 10000 + md))
+            .collect()
+    }
+
+    /// The busiest day of the dataset, that is used for single photo additions.
+    pub fn large_day(&self) -> YearMonthDay {
+        FIRST_YEAR * 10000 + 101
+    }
+}
+
+fn env_or(name: &str, default: u32) -> u32 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Object IDs of the photos of given day.
+/// IDs are SHA-256 hashes like the real ones, and are the same on every run.
+pub fn photo_ids(ymd: YearMonthDay, count: u32) -> Vec<Data> {
+    (0..count)
+        .map(|i| object_id(&[ymd.to_be_bytes(), i.to_be_bytes()].concat()))
+        .collect()
+}
+
+/// A photo that is not a part of any generated day.
+pub fn extra_photo_id(n: u64) -> Data {
+    object_id(&[b"extra".as_slice(), &n.to_be_bytes()].concat())
+}
+
+/// Fills the storage with the dataset, all photos are labeled with given peer.
+pub fn populate_storage(storage: &LocalStorage, config: &DatasetConfig, peer: &Peer) {
+    for ymd in config.days() {
+        let photos = photo_ids(ymd, config.photos_per_day)
+            .into_iter()
+            .map(|id| (id, vec![peer.clone()]))
+            .collect::<Vec<_>>();
+        storage.add_photos_to_day(ymd, &photos).unwrap();
+    }
+}
+
+/// Fills the node with the dataset as photos kept by the node itself, so they have signed claims.
+pub fn populate_node(node: &CatalogNode, config: &DatasetConfig) {
+    for ymd in config.days() {
+        node.add_photos(ymd, &photo_ids(ymd, config.photos_per_day))
+            .unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Benchmarks of the local storage: ingestion of photo IDs and checksum queries.
+
+mod dataset;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use dataset::{extra_photo_id, populate_storage, DatasetConfig};
+use photo_sync_tst::local_storage::LocalStorage;
+
+fn bench_ingest(c: &mut Criterion) {
+    let config = DatasetConfig::from_env();
+    let peer = vec![1u8; 32];
+    let mut group = c.benchmark_group("ingest");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(config.total_photos()));
+    group.bench_function("bulk", |b| {
+        b.iter_batched(
+            || LocalStorage::test_new().unwrap(),
+            |storage| populate_storage(&storage, &config, &peer),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn bench_add_into_large_day(c: &mut Criterion) {
+    let config = DatasetConfig::from_env();
+    let peer = vec![1u8; 32];
+    let storage = LocalStorage::test_new().unwrap();
+    populate_storage(&storage, &config, &peer);
+
+    let mut n = 0u64;
+    c.bench_function("add_single_photo_into_large_day", |b| {
+        b.iter(|| {
+            n += 1;
+            let photo = (extra_photo_id(n), vec![peer.clone()]);
+            storage
+                .add_photos_to_day(config.large_day(), &[photo])
+                .unwrap()
+        })
+    });
+}
+
+fn bench_checksums(c: &mut Criterion) {
+    let config = DatasetConfig::from_env();
+    let storage = LocalStorage::test_new().unwrap();
+    populate_storage(&storage, &config, &vec![1u8; 32]);
+    let year = config.large_day() / 10000;
+    let year_month = config.large_day() / 100;
+
+    let mut group = c.benchmark_group("checksums");
+    group.bench_function("years", |b| {
+        b.iter(|| storage.get_years_checksums().unwrap())
+    });
+    group.bench_function("months", |b| {
+        b.iter(|| storage.get_months_checksum(black_box(year)).unwrap())
+    });
+    group.bench_function("days", |b| {
+        b.iter(|| storage.get_days_checksum(black_box(year_month)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_ingest,
+    bench_add_into_large_day,
+    bench_checksums
+);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! Benchmarks of the synchronization between two nodes.
+
+mod dataset;
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use dataset::{extra_photo_id, populate_node, DatasetConfig};
+use photo_sync_tst::catalog::CatalogNode;
+
+/// Empty node pulls the whole catalog from a populated one.
+fn bench_full_sync(c: &mut Criterion) {
+    let config = DatasetConfig::from_env();
+    let source = Arc::new(CatalogNode::test_new("source").unwrap());
+    populate_node(&source, &config);
+
+    let mut group = c.benchmark_group("sync");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(config.total_photos()));
+    group.bench_function("full_into_empty_node", |b| {
+        b.iter_batched(
+            || {
+                let node = CatalogNode::test_new("empty").unwrap();
+                node.add_peer(source.clone()).unwrap();
+                node
+            },
+            |node| node.sync_with_peers().unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+/// Nodes are in sync, and one photo is added on one of them.
+fn bench_incremental_sync(c: &mut Criterion) {
+    let config = DatasetConfig::from_env();
+    let source = Arc::new(CatalogNode::test_new("source").unwrap());
+    populate_node(&source, &config);
+    let replica = CatalogNode::test_new("replica").unwrap();
+    replica.add_peer(source.clone()).unwrap();
+    replica.sync_with_peers().unwrap();
+
+    let mut n = 0u64;
+    c.bench_function("sync/incremental_after_one_change", |b| {
+        b.iter_batched(
+            || {
+                n += 1;
+                source
+                    .add_photos(config.large_day(), &[extra_photo_id(n)])
+                    .unwrap();
+            },
+            |_| replica.sync_with_peers().unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group!(benches, bench_full_sync, bench_incremental_sync);
+criterion_main!(benches);
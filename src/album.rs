@@ -0,0 +1,120 @@
+//! Named albums, that reference object IDs across many days, and are synchronized between peers
+//! alongside the date tree.
+//!
+//! An album is an add-wins observed-remove set (OR-set) of object IDs.
+//! Each addition of an object gets a unique tag, and a removal marks only the tags it has observed.
+//! So when one peer removes an object, while another peer concurrently adds it again,
+//! the new tag survives the merge, and the object stays in the album.
+//! Merge is a union of the tags, where a removed tag stays removed,
+//! so peers converge regardless of the order the states are exchanged in.
+//!
+//! Each album has its own checksum. Peers compare the checksums of all albums by name,
+//! and exchange the states of the albums that differ (see [`CatalogNode::sync_with_peers`](crate::catalog::CatalogNode::sync_with_peers)).
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::HashAlgorithm;
+use crate::local_storage::{Checksum, Data};
+
+/// Unique tag of an addition of an object to an album
+pub type Tag = Vec<u8>;
+
+/// Addition of an object to an album: (object ID, tag of the addition, whether it has been removed)
+pub type AlbumEntry = (Data, Tag, bool);
+
+/// Replicated state of an album.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Album {
+    pub name: String,
+    /// Additions of objects, sorted by object ID and tag
+    pub entries: Vec<AlbumEntry>,
+}
+
+impl Album {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Album {
+            name: name.into(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Objects of the album, i.e. the ones that have an addition that hasn't been removed. Sorted.
+    pub fn objects(&self) -> Vec<Data> {
+        let mut result: Vec<Data> = self
+            .entries
+            .iter()
+            .filter(|(_, _, removed)| !removed)
+            .map(|(id, _, _)| id.clone())
+            .collect();
+        result.dedup();
+        result
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.entries
+            .iter()
+            .any(|(data, _, removed)| data == id && !removed)
+    }
+
+    /// Adds the object with given tag, unless the album already contains it.
+    /// Returns whether the album has changed.
+    pub(crate) fn add(&mut self, id: &[u8], tag: Tag) -> bool {
+        if self.contains(id) {
+            return false;
+        }
+        self.merge(&[(id.to_vec(), tag, false)])
+    }
+
+    /// Removes all the observed additions of the object. Returns whether the album has changed.
+    pub(crate) fn remove(&mut self, id: &[u8]) -> bool {
+        let mut changed = false;
+        for (data, _, removed) in self.entries.iter_mut() {
+            if data == id && !*removed {
+                *removed = true;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Merges the entries of another replica of the album. Returns whether the album has changed.
+    pub(crate) fn merge(&mut self, entries: &[AlbumEntry]) -> bool {
+        let mut merged: BTreeMap<(Data, Tag), bool> = self
+            .entries
+            .drain(..)
+            .map(|(id, tag, removed)| ((id, tag), removed))
+            .collect();
+        let mut changed = false;
+        for (id, tag, removed) in entries {
+            let known = merged.entry((id.clone(), tag.clone())).or_insert_with(|| {
+                changed = true;
+                *removed
+            });
+            if *removed && !*known {
+                *known = true;
+                changed = true;
+            }
+        }
+        self.entries = merged
+            .into_iter()
+            .map(|((id, tag), removed)| (id, tag, removed))
+            .collect();
+        changed
+    }
+
+    /// Checksum of the album state. Removed additions are included, so that peers exchange removals too.
+    /// Each ID and tag is prefixed with its length to avoid ambiguity between them.
+    pub(crate) fn checksum(&self, algorithm: HashAlgorithm) -> Checksum {
+        let mut hasher = algorithm.hasher();
+        for (id, tag, removed) in &self.entries {
+            hasher.update(&(id.len() as u32).to_be_bytes());
+            hasher.update(id);
+            hasher.update(&(tag.len() as u32).to_be_bytes());
+            hasher.update(tag);
+            hasher.update(&[*removed as u8]);
+        }
+        hasher.finalize()
+    }
+}
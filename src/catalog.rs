@@ -1,41 +1,148 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::ops::Deref;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
+use std::sync::TryLockError;
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use crate::album::Album;
+use crate::hashing::HashAlgorithm;
+use crate::identity::LocationClaim;
+use crate::identity::Proposal;
+use crate::identity::{AlbumProposal, NodeIdentity};
+use crate::local_storage::object_id;
+use crate::local_storage::ChangeFeed;
 use crate::local_storage::Checksum;
 use crate::local_storage::Data;
+use crate::local_storage::DayChange;
+use crate::local_storage::DayData;
 use crate::local_storage::LocalStorage;
 use crate::local_storage::Peer;
+use crate::local_storage::Photo;
+use crate::local_storage::TransferDirection;
+use crate::membership::MemberState;
+use crate::membership::MemberUpdate;
+use crate::membership::Membership;
+use crate::membership::PeerConnector;
+use crate::metadata::ObjectMetadata;
+use crate::metrics::write_metric;
+use crate::metrics::InstrumentedPeer;
+use crate::metrics::Metrics;
+use crate::opaque_date::ym_to_y;
 use crate::opaque_date::ymd_interval_for_y;
 use crate::opaque_date::ymd_interval_for_ym;
+use crate::opaque_date::ymd_to_ym;
 use crate::opaque_date::Year;
 use crate::opaque_date::YearMonth;
 use crate::opaque_date::YearMonthDay;
+use crate::replication::suggest_fetchers;
+use crate::replication::ReplicationPolicy;
+use crate::replication::ReplicationReport;
+use crate::replication::UnderReplicated;
+use crate::sync_options::PeerOptions;
+use crate::sync_options::PeerProgress;
+use crate::sync_options::ScopeFilter;
+use crate::sync_options::SyncDirection;
+use crate::sync_options::SyncOptions;
+use crate::sync_options::SyncScope;
+use crate::trace_context::current_trace_id;
+use crate::trace_context::format_peer;
+use crate::trace_context::format_trace_id;
+use crate::trace_context::in_trace;
+use crate::trace_context::new_trace_id;
+use crate::trace_context::Partition;
+use anyhow::anyhow;
 use anyhow::Result;
 use itertools::Itertools;
+use rand::seq::IteratorRandom;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tracing::field;
 
 use log::debug;
 
-#[derive(Error, Debug)]
+/// Errors are plain data, so that they are sent over the network as they are, see [`transport`](crate::transport).
+#[derive(Error, Debug, Serialize, Deserialize)]
 pub enum DistStoreError {
     #[error("The syncronization is already in process")]
     SyncInProcess,
+    #[error("Proposal is not signed by the proposer {proposer:?}")]
+    InvalidProposalSignature { proposer: Peer },
+    #[error("Location label {peer:?} of object {data:?} is not signed by the labeled peer")]
+    UnsignedLocationClaim { data: Data, peer: Peer },
+    #[error("Peer {peer:?} is not in the allow-list")]
+    UnauthorizedPeer { peer: Peer },
+    #[error("Expected peer {expected:?}, but the remote side is {actual:?}")]
+    UnexpectedPeerIdentity { expected: Peer, actual: Peer },
+    #[error("Peer {peer:?} failed to prove it owns the key")]
+    InvalidIdentityProof { peer: Peer },
+    #[error("Content of object {claimed:?} hashes to {actual:?}")]
+    ObjectIdMismatch { claimed: Data, actual: Data },
+    #[error("Peer {peer:?} sent content that doesn't match object {id:?}")]
+    CorruptedBlobFromPeer { peer: Peer, id: Data },
+    #[error("Synchronization failed with peers {failed:?}")]
+    PartialSync { failed: Vec<Peer> },
+    #[error("Local storage is inconsistent: {reason}")]
+    InconsistentStorage { reason: String },
+    #[error("Peer {peer:?} calculates checksums with {remote}, but this node uses {local}")]
+    HashAlgorithmMismatch {
+        peer: Peer,
+        local: HashAlgorithm,
+        remote: HashAlgorithm,
+    },
+    #[error("The synchronization has been cancelled")]
+    SyncCancelled,
+    #[error("Node is read-only, proposals of peers are refused")]
+    ReadOnlyNode,
+    #[error("Node can't be a peer of itself")]
+    SelfPeering,
+    #[error("Peer {peer:?} has already been added")]
+    DuplicatePeer { peer: Peer },
+    #[error("Album {name} already exists")]
+    AlbumAlreadyExists { name: String },
+    #[error("Album {name} doesn't exist")]
+    UnknownAlbum { name: String },
 }
 
+/// Key of the node secret key in the storage settings.
+/// The key is stored unencrypted, so the catalog database must be readable only by the node itself.
+const META_NODE_KEY: &str = "node_key";
+
 /// Represents a remote peer we can exchange photos with.
 /// It is assumed that in real system, this trait should be implemented
 /// using a network client, that communicates with another instance
 /// of [`DistributedObjStorage`](DistributedObjStorage).
-pub trait RemotePeer {
-    /// Returns ID of the peer. The ID should not change between session of connection to peer.
+/// Peers are shared by the threads of the node (e.g. the background repair), hence `Send + Sync`.
+pub trait RemotePeer: Send + Sync {
+    /// Returns ID of the peer, that is the public key of the peer.
+    /// The ID should not change between session of connection to peer.
     fn id(&self) -> Vec<u8>;
 
     /// Callback for a peer to notified that it has been added by calling peer.
+    /// The peer adds the caller in return, unless it knows it already.
+    /// Args:
+    /// * peer - handle the peer can use to reach the caller
     fn notify_added_by(&self, peer: Arc<dyn RemotePeer>);
 
+    /// Returns the hash algorithm of the peer checksum tree.
+    /// Checksums of peers with different algorithms can't be compared.
+    fn hash_algorithm(&self) -> Result<HashAlgorithm>;
+
     /// Return all year partitions along with treir checksum.
     /// If thecksum of a remote partition is same as the checksum for same partition locally,
     /// then no synchonization for given year is required.
@@ -55,12 +162,70 @@ pub trait RemotePeer {
         ymd_to: YearMonthDay,
     ) -> Result<Vec<YearMonthDay>>;
 
-    /// Return object IDs for given day.
+    /// For given interval, return a checksum of the days in it and their checksums.
+    /// Used to compare the parts of years and months that are in the sync scope.
+    fn get_range_checksum(&self, ymd_from: YearMonthDay, ymd_to: YearMonthDay) -> Result<Checksum>;
+
+    /// Return object IDs for given day, along with their metadata records (see [`metadata`](crate::metadata)).
     /// Each object ID is associated with a list of peers that have the object on their host.
-    fn get_data(&self, ymd: u32) -> Result<Option<Vec<(Data, Vec<Peer>)>>>;
+    fn get_data(&self, ymd: u32) -> Result<Option<DayData>>;
+
+    /// Return signed claims for the location labels of given day.
+    fn get_location_claims(&self, ymd: YearMonthDay) -> Result<Vec<LocationClaim>>;
 
     /// Propose list of object IDs for given day to the peer.
-    fn propose(&self, ymd: u32, data: &[(Data, Vec<Peer>)]) -> Result<Vec<u8>>;
+    /// The peer rejects the proposal if it is not properly signed,
+    /// or if some of the location labels are not claimed by labeled peers.
+    fn propose(&self, proposal: &Proposal) -> Result<Vec<u8>>;
+
+    /// Return binary content of the object, if the peer keeps it on its host.
+    fn get_blob(&self, id: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Returns changes of the days made on the peer after given sequence number of its change log.
+    /// Allows to synchronize recent changes without walking the checksum tree.
+    fn changes_since(&self, seq: u64) -> Result<ChangeFeed>;
+
+    /// Exchanges membership views (see [`membership`](crate::membership)):
+    /// the peer merges members known by the caller, and returns members known by the peer.
+    fn gossip(&self, members: Vec<MemberUpdate>) -> Result<Vec<MemberUpdate>>;
+
+    /// Checks whether the target peer can be reached from this peer.
+    /// Used to tell a failed peer from a broken connection to it.
+    fn probe(&self, target: &[u8]) -> Result<bool>;
+
+    /// Returns names of the albums along with their checksums, sorted by name.
+    fn get_albums_checksums(&self) -> Result<Vec<(String, Checksum)>>;
+
+    /// Returns the replicated state of the album, `None` if the peer doesn't have it.
+    fn get_album(&self, name: &str) -> Result<Option<Album>>;
+
+    /// Merges the state of the album into the album of the peer, creating it if needed.
+    /// The state must be signed by the proposing node, same as proposals of days.
+    /// Returns the checksum of the merged album.
+    fn merge_album(&self, proposal: &AlbumProposal) -> Result<Checksum>;
+}
+
+/// Days that differ between the node and a peer, see [`CatalogNode::plan_sync`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerSyncPlan {
+    pub peer: Peer,
+    /// Days to pull from the peer, sorted
+    pub pull: Vec<YearMonthDay>,
+    /// Days to push to the peer, sorted
+    pub push: Vec<YearMonthDay>,
+}
+
+/// Notification about changes of the catalog, see [`CatalogNode::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CatalogEvent {
+    /// Photos or labels were added to a day, either locally or by a peer (e.g. during a sync)
+    DayChanged(DayChange),
+    SyncStarted,
+    /// Synchronization with all peers is over
+    SyncFinished {
+        /// IDs of the peers the synchronization has failed with
+        failed: Vec<Peer>,
+    },
 }
 
 /// Represents a local instance of a distributed object IDs storage.
@@ -68,137 +233,1282 @@ pub trait RemotePeer {
 /// and can synchronize this list with other peers.
 pub struct CatalogNode {
     name: String,
+    identity: NodeIdentity,
     storage: LocalStorage,
     peers: RwLock<Vec<Arc<dyn RemotePeer>>>,
     sync_mutex: Mutex<()>,
+    replication_policy: RwLock<ReplicationPolicy>,
+    subscribers: Mutex<Vec<mpsc::Sender<CatalogEvent>>>,
+    metrics: Metrics,
+    membership: Membership,
+    connector: RwLock<Option<Arc<dyn PeerConnector>>>,
+    peer_options: RwLock<BTreeMap<Peer, PeerOptions>>,
+    read_only: AtomicBool,
 }
 
 impl CatalogNode {
     pub fn new<S: Into<String>, P: AsRef<Path>>(name: S, path: P) -> Result<CatalogNode> {
-        Ok(CatalogNode {
-            name: name.into(),
-            storage: LocalStorage::new(path)?,
-            peers: RwLock::new(Vec::new()),
-            sync_mutex: Mutex::new(()),
-        })
+        Self::with_storage(name.into(), LocalStorage::new(path)?)
+    }
+
+    /// Same as [`new`](Self::new), but the checksum tree of the catalog uses the given hash algorithm,
+    /// see [`LocalStorage::new_with_hash_algorithm`].
+    pub fn new_with_hash_algorithm<S: Into<String>, P: AsRef<Path>>(
+        name: S,
+        path: P,
+        algorithm: HashAlgorithm,
+    ) -> Result<CatalogNode> {
+        let storage = LocalStorage::new_with_hash_algorithm(path, algorithm)?;
+        Self::with_storage(name.into(), storage)
+    }
+
+    /// Opens the node and restores the peers recorded in the catalog database, see [`restore_peers`](Self::restore_peers).
+    /// The connector is also used to connect members learned from the gossip.
+    /// Args:
+    /// * name - name of the node
+    /// * path - catalog DB file
+    /// * connector - creates the connector of the node, e.g. [`NetworkConnector::new`](crate::transport::NetworkConnector::new)
+    pub fn open_with_connector<S, P, F>(name: S, path: P, connector: F) -> Result<Arc<CatalogNode>>
+    where
+        S: Into<String>,
+        P: AsRef<Path>,
+        F: FnOnce(&Arc<CatalogNode>) -> Arc<dyn PeerConnector>,
+    {
+        let node = Arc::new(Self::new(name, path)?);
+        node.set_peer_connector(connector(&node));
+        node.restore_peers()?;
+        Ok(node)
     }
 
     pub fn test_new(name: &str) -> Result<CatalogNode> {
+        Self::with_storage(name.into(), LocalStorage::test_new()?)
+    }
+
+    pub fn test_new_with_hash_algorithm(
+        name: &str,
+        algorithm: HashAlgorithm,
+    ) -> Result<CatalogNode> {
+        let storage = LocalStorage::test_new_with_hash_algorithm(algorithm)?;
+        Self::with_storage(name.into(), storage)
+    }
+
+    /// The node keypair is kept in the storage, so that the node ID survives restarts.
+    /// The secret key is not encrypted: anyone who can read the database file can impersonate the node,
+    /// so the file should be protected by the file system permissions.
+    fn with_storage(name: String, storage: LocalStorage) -> Result<CatalogNode> {
+        let identity = match storage.get_meta(META_NODE_KEY)? {
+            Some(secret) => NodeIdentity::from_secret_bytes(secret.as_slice().try_into()?),
+            None => {
+                let identity = NodeIdentity::generate();
+                storage.put_meta(META_NODE_KEY, &identity.secret_bytes())?;
+                identity
+            }
+        };
         Ok(CatalogNode {
-            name: name.into(),
-            storage: LocalStorage::test_new()?,
+            name,
+            membership: Membership::new(identity.peer_id()),
+            identity,
+            storage,
             peers: RwLock::new(Vec::new()),
             sync_mutex: Mutex::new(()),
+            replication_policy: RwLock::new(ReplicationPolicy::default()),
+            subscribers: Mutex::new(Vec::new()),
+            metrics: Metrics::default(),
+            connector: RwLock::new(None),
+            peer_options: RwLock::new(BTreeMap::new()),
+            read_only: AtomicBool::new(false),
         })
     }
 
     /// Adding a peer.
     /// In real system a disconnection of a peer should be handled,
     /// but it is out of the scope of this task.
-    pub fn add_peer(&self, peer: Arc<dyn RemotePeer>) {
+    /// The peer is recorded in the catalog database, see [`restore_peers`](Self::restore_peers).
+    /// Fails with [`DistStoreError::DuplicatePeer`] if a peer with the same ID has already been added,
+    /// and with [`DistStoreError::SelfPeering`] if it is this node.
+    /// To make the peer aware of this node as well, call [`RemotePeer::notify_added_by`] afterwards.
+    pub fn add_peer(&self, peer: Arc<dyn RemotePeer>) -> Result<()> {
+        self.add_peer_at(peer, None, None)
+    }
+
+    /// Same as [`add_peer`](Self::add_peer), but also records the address the peer can be reached at after a restart.
+    pub fn add_peer_with_address(&self, peer: Arc<dyn RemotePeer>, address: &str) -> Result<()> {
+        self.add_peer_at(peer, Some(address), None)
+    }
+
+    /// Same as [`add_peer`](Self::add_peer), but the peer is synchronized according to the options,
+    /// e.g. only in one direction. The options are recorded along with the peer.
+    /// Args:
+    /// * peer - the peer to add
+    /// * address - address the peer can be reached at after a restart, if known
+    /// * options - options of the synchronization with the peer
+    pub fn add_peer_with_options(
+        &self,
+        peer: Arc<dyn RemotePeer>,
+        address: Option<&str>,
+        options: PeerOptions,
+    ) -> Result<()> {
+        self.add_peer_at(peer, address, Some(options))
+    }
+
+    /// Peers added without options (e.g. connected from the gossip, or restored after a restart)
+    /// keep the options recorded for them, if any.
+    fn add_peer_at(
+        &self,
+        peer: Arc<dyn RemotePeer>,
+        address: Option<&str>,
+        options: Option<PeerOptions>,
+    ) -> Result<()> {
+        let id = peer.id();
+        if id == self.id() {
+            return Err(DistStoreError::SelfPeering.into());
+        }
+        let effective = match &options {
+            Some(options) => options.clone(),
+            None => self
+                .storage
+                .get_peer(&id)?
+                .map(|record| record.options())
+                .unwrap_or_default(),
+        };
         {
-            // It only one level of locking, safe to unwrap
+            // The options lock is never taken before the peers lock, safe to unwrap
             let mut guard = self.peers.write().unwrap();
+            if guard.iter().any(|p| p.id() == id) {
+                return Err(DistStoreError::DuplicatePeer { peer: id }.into());
+            }
+            self.peer_options
+                .write()
+                .unwrap()
+                .insert(id.clone(), effective);
             guard.push(peer);
         }
+        self.membership.add_known(id.clone());
+        // The peer is still usable until the restart, so failing to persist it is not fatal
+        if let Err(e) = self.storage.put_peer(&id, address, options.as_ref()) {
+            debug!("Failed to record peer {:?}: {}", id, e);
+        }
+        Ok(())
+    }
+
+    /// Removes the peer, so that the node doesn't synchronize with it anymore,
+    /// and forgets it in the catalog database, so that it is not restored after a restart.
+    /// A peer that is still a gossip member may be connected again, unless it is revoked as well,
+    /// see [`LocalStorage::revoke_peer`].
+    /// Returns `false` if the peer is unknown.
+    pub fn remove_peer(&self, peer: &[u8]) -> Result<bool> {
+        let removed = {
+            let mut guard = self.peers.write().unwrap();
+            let count = guard.len();
+            guard.retain(|p| p.id() != peer);
+            self.peer_options.write().unwrap().remove(peer);
+            guard.len() != count
+        };
+        let recorded = self.storage.remove_peer(peer)?;
+        Ok(removed || recorded)
+    }
+
+    /// Options the peer has been added with, `None` for unknown peers.
+    pub fn peer_options(&self, peer: &[u8]) -> Option<PeerOptions> {
+        self.peer_options.read().unwrap().get(peer).cloned()
+    }
+
+    fn peer_direction(&self, peer: &[u8]) -> SyncDirection {
+        self.peer_options(peer)
+            .map(|options| options.direction)
+            .unwrap_or_default()
+    }
+
+    fn peer_scope(&self, peer: &[u8]) -> SyncScope {
+        self.peer_options(peer)
+            .map(|options| options.scope)
+            .unwrap_or_default()
+    }
+
+    /// In the read-only mode, proposals of peers are refused with [`DistStoreError::ReadOnlyNode`],
+    /// while the node can still pull days from its peers.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Gossip membership view of the node.
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    /// Sets the connector used to connect members learned from the gossip and peers restored after a restart.
+    /// Without a connector, gossiped members are only tracked in the membership view.
+    pub fn set_peer_connector(&self, connector: Arc<dyn PeerConnector>) {
+        *self.connector.write().unwrap() = Some(connector);
+    }
+
+    /// Performs one round of the gossip membership protocol, is expected to be called periodically.
+    /// Exchanges the membership view with a random live peer and connects members learned from it.
+    /// Members learned from the gossip of other nodes since the previous round are connected too.
+    /// If the peer doesn't answer, other peers are asked to probe it, and if none of them can reach it,
+    /// the peer becomes suspected.
+    pub fn gossip_round(&self) {
+        self.membership.expire_suspects();
+        self.connect_members();
+        let peers = self.live_peers();
+        let mut rng = rand::thread_rng();
+        let Some(target) = peers.choose(&mut rng) else {
+            return;
+        };
+        let target_id = target.id();
+        let instrumented = InstrumentedPeer::new(target.as_ref(), &self.metrics);
+        match instrumented.gossip(self.membership.members()) {
+            Ok(members) => {
+                self.membership.merge(members);
+                self.connect_members();
+            }
+            Err(e) => {
+                debug!("Gossip with peer {:?} failed: {}", target_id, e);
+                let indirect_probes = self.membership.config().indirect_probes;
+                let reachable = peers
+                    .iter()
+                    .filter(|p| p.id() != target_id)
+                    .choose_multiple(&mut rng, indirect_probes)
+                    .into_iter()
+                    .any(|p| {
+                        InstrumentedPeer::new(p.as_ref(), &self.metrics)
+                            .probe(&target_id)
+                            .unwrap_or(false)
+                    });
+                if !reachable {
+                    self.membership.suspect(&target_id);
+                }
+            }
+        }
     }
 
+    /// Connects live members that are not peers of this node yet, if there is a connector.
+    /// Members that can't be connected (e.g. their address is not known yet) are retried next time.
+    fn connect_members(&self) {
+        let Some(connector) = self.connector.read().unwrap().clone() else {
+            return;
+        };
+        let known = self.peer_ids();
+        for member in self.membership.members() {
+            if member.peer == self.id()
+                || member.state == MemberState::Dead
+                || known.contains(&member.peer)
+            {
+                continue;
+            }
+            let addresses = member.address.iter().cloned().collect_vec();
+            let added = connector
+                .connect(&member.peer, &addresses)
+                .and_then(|peer| self.add_peer_at(peer, member.address.as_deref(), None));
+            if let Err(e) = added {
+                debug!("Failed to connect member {:?}: {}", member.peer, e);
+            }
+        }
+    }
+
+    /// Re-creates the peers recorded in the catalog database, using the connector
+    /// set with [`set_peer_connector`](Self::set_peer_connector).
+    /// It is done on startup by [`open_with_connector`](Self::open_with_connector).
+    /// Peers that can't be connected are skipped, and stay recorded for the next attempt.
+    /// Returns the number of restored peers.
+    pub fn restore_peers(&self) -> Result<usize> {
+        let connector = self
+            .connector
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("Peer connector is not set"))?;
+        let known = self.peer_ids();
+        let mut restored = 0;
+        for record in self.storage.get_peers()? {
+            if known.contains(&record.id) {
+                continue;
+            }
+            // The peer keeps the options recorded for it
+            match connector
+                .connect(&record.id, &record.addresses)
+                .and_then(|peer| self.add_peer_at(peer, None, None))
+            {
+                Ok(()) => restored += 1,
+                Err(e) => debug!("Failed to restore peer {:?}: {}", record.id, e),
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Peers that are not known to be dead
+    fn live_peers(&self) -> Vec<Arc<dyn RemotePeer>> {
+        self.peers_snapshot()
+            .into_iter()
+            .filter(|p| self.membership.is_live(&p.id()))
+            .collect()
+    }
+
+    /// IDs of the peers the node synchronizes with.
+    pub fn peer_ids(&self) -> Vec<Peer> {
+        self.peers_snapshot().iter().map(|p| p.id()).collect()
+    }
+
+    /// ID of the node, that is the public key of the node keypair.
     pub fn id(&self) -> Vec<u8> {
-        self.name.as_bytes().to_vec()
+        self.identity.peer_id()
+    }
+
+    pub(crate) fn identity(&self) -> &NodeIdentity {
+        &self.identity
+    }
+
+    /// Human readable name of the node, for logging.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds object IDs of the photos kept on this host.
+    /// The IDs are labeled with this node, and the labels are signed by the node key.
+    /// Returns resulting checksum of the day.
+    pub fn add_photos(&self, ymd: YearMonthDay, ids: &[Data]) -> Result<Checksum> {
+        self.add_photos_with_metadata(ymd, ids, &[])
+    }
+
+    /// Same as [`add_photos`](Self::add_photos), but also attaches metadata records to the objects.
+    pub fn add_photos_with_metadata(
+        &self,
+        ymd: YearMonthDay,
+        ids: &[Data],
+        metadata: &[(Data, ObjectMetadata)],
+    ) -> Result<Checksum> {
+        let photos = ids
+            .iter()
+            .map(|id| (id.clone(), vec![self.id()]))
+            .collect_vec();
+        let claims = ids
+            .iter()
+            .map(|id| self.identity.claim_location(id))
+            .collect_vec();
+        self.merge_photos(ymd, &photos, &claims, metadata)
+    }
+
+    /// Merges metadata records into the records of the objects of the day, see [`metadata`](crate::metadata).
+    /// Records of objects that are not in the day are dropped.
+    pub fn merge_metadata(
+        &self,
+        ymd: YearMonthDay,
+        metadata: &[(Data, ObjectMetadata)],
+    ) -> Result<Checksum> {
+        self.merge_photos(ymd, &[], &[], metadata)
+    }
+
+    /// Returns metadata records of the objects of the day, sorted by object ID.
+    /// Objects without a record are not listed.
+    pub fn get_metadata(&self, ymd: YearMonthDay) -> Result<Vec<(Data, ObjectMetadata)>> {
+        self.storage.get_metadata(ymd)
+    }
+
+    /// Merges photos into the local storage and notifies subscribers if the day has changed.
+    fn merge_photos(
+        &self,
+        ymd: YearMonthDay,
+        photos: &[Photo],
+        claims: &[LocationClaim],
+        metadata: &[(Data, ObjectMetadata)],
+    ) -> Result<Checksum> {
+        let (checksum, change) = self.storage.merge_photos(ymd, photos, claims, metadata)?;
+        if let Some(change) = change {
+            self.emit(CatalogEvent::DayChanged(change));
+        }
+        Ok(checksum)
     }
 
-    /// Performs the synchronization with all know peers.
+    /// Counters of the synchronization and of the calls made to peers.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Renders the metrics of the node in the Prometheus text exposition format,
+    /// including the current number of objects, days and peers.
+    pub fn render_metrics(&self) -> Result<String> {
+        let (days, objects) = self.storage.count_days_and_objects()?;
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "photo_sync_objects",
+            "gauge",
+            "Number of objects in the catalog",
+            objects,
+        );
+        write_metric(
+            &mut out,
+            "photo_sync_days",
+            "gauge",
+            "Number of days that have objects",
+            days,
+        );
+        write_metric(
+            &mut out,
+            "photo_sync_peers",
+            "gauge",
+            "Number of known peers",
+            self.peers_snapshot().len() as u64,
+        );
+        self.metrics.render(&mut out);
+        Ok(out)
+    }
+
+    /// Subscribes to the catalog events.
+    /// Events are delivered in the order they happened, to drop the subscription just drop the receiver.
+    /// Changes made directly through [`storage`](Self::storage) are not reported.
+    pub fn subscribe(&self) -> mpsc::Receiver<CatalogEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends the event to all subscribers, forgetting the ones that have dropped their receivers.
+    fn emit(&self, event: CatalogEvent) {
+        let mut subscribers = match self.subscribers.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Local part of the catalog, e.g. for querying what objects a given peer keeps.
+    pub fn storage(&self) -> &LocalStorage {
+        &self.storage
+    }
+
+    /// Performs the synchronization with live peers: all of them,
+    /// or a random subset if [`MembershipConfig::sync_fanout`](crate::membership::MembershipConfig::sync_fanout) is set.
+    /// Peers considered dead by the gossip membership are skipped.
     /// To do that it compares checksums for years, then year/months and year/month/days.
     /// Data for days are have different checksums is synchronized between peers.
     /// Checksums are recalculated after the syncronization.
+    /// A failure (or even a panic) of one peer doesn't stop the synchronization with other peers,
+    /// failed peers are reported with [`DistStoreError::PartialSync`] after all peers are processed.
     pub fn sync_with_peers(&self) -> Result<()> {
+        self.sync_with_peers_with(&SyncOptions::default())
+    }
+
+    /// Same as [`sync_with_peers`](Self::sync_with_peers), but can be cancelled and reports the progress,
+    /// see [`sync_options`](crate::sync_options).
+    /// A cancelled sync stops before the next day is transferred, and fails with [`DistStoreError::SyncCancelled`].
+    pub fn sync_with_peers_with(&self, options: &SyncOptions) -> Result<()> {
         let _guard = match self.sync_mutex.try_lock() {
             Ok(guard) => guard,
-            _ => return Err(DistStoreError::SyncInProcess.into()),
+            // The mutex doesn't protect any data, so a panic during a previous sync doesn't matter
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(DistStoreError::SyncInProcess.into()),
         };
+        // The sync can be a part of a wider trace, e.g. when it is triggered by a request of a peer
+        let trace_id = current_trace_id().unwrap_or_else(new_trace_id);
+        let span = tracing::info_span!(
+            "sync_with_peers",
+            node = %self.name,
+            trace_id = %format_trace_id(Some(trace_id)),
+        );
+        let _enter = span.enter();
         debug!("Starting synchronization with peers");
+        self.emit(CatalogEvent::SyncStarted);
+        let started = Instant::now();
 
-        let peers: Vec<Arc<dyn RemotePeer>> = {
-            let peers_guard = &self.peers.read().unwrap();
-            peers_guard.deref().clone()
-        };
+        let mut peers = self.live_peers();
+        if let Some(fanout) = self.membership.config().sync_fanout {
+            peers = peers
+                .choose_multiple(&mut rand::thread_rng(), fanout)
+                .cloned()
+                .collect();
+        }
 
-        // Cyclomatic complexity is not great, but in this case it makes the alrorithm clearer
+        let mut failed = Vec::new();
+        let mut cancelled = false;
         for peer in peers {
-            let (missing_on_local, missing_on_remote, diff_y) =
-                calc_diff(&self.get_years_checksums()?, &peer.get_years_checksums()?);
-            fill_gaps(peer.as_ref(), self, missing_on_local, ymd_interval_for_y)?;
-            fill_gaps(self, peer.as_ref(), missing_on_remote, ymd_interval_for_y)?;
-
-            for y in diff_y {
-                let (missing_on_local, missing_on_remote, diff_ym) =
-                    calc_diff(&self.get_months_checksum(y)?, &peer.get_months_checksum(y)?);
-                fill_gaps(peer.as_ref(), self, missing_on_local, ymd_interval_for_ym)?;
-                fill_gaps(self, peer.as_ref(), missing_on_remote, ymd_interval_for_ym)?;
-
-                for ym in diff_ym {
-                    let (mut missing_on_local, mut missing_on_remote, diff_ymd) =
-                        calc_diff(&self.get_days_checksum(ym)?, &peer.get_days_checksum(ym)?);
-                    missing_on_local.extend(&diff_ymd);
-                    missing_on_remote.extend(&diff_ymd);
-                    fill_ymd_gaps(peer.as_ref(), self, missing_on_local)?;
-                    fill_ymd_gaps(self, peer.as_ref(), missing_on_remote)?;
+            if options.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+            let span = tracing::info_span!(
+                "sync_with_peer",
+                peer = %format_peer(&peer.id()),
+                error = field::Empty,
+            );
+            let _enter = span.enter();
+            let instrumented = InstrumentedPeer::new(peer.as_ref(), &self.metrics);
+            let progress = PeerProgress::new(options, peer.id());
+            let res = in_trace(Some(trace_id), || {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    self.sync_with_peer(&instrumented, options.scope(), &progress)
+                }))
+                .unwrap_or_else(|_| Err(anyhow!("Peer panicked during the synchronization")))
+            });
+            if let Err(e) = res {
+                span.record("error", field::display(&e));
+                if matches!(
+                    e.downcast_ref::<DistStoreError>(),
+                    Some(DistStoreError::SyncCancelled)
+                ) {
+                    cancelled = true;
+                    break;
                 }
+                debug!("Synchronization with peer {:?} failed: {}", peer.id(), e);
+                failed.push(peer.id());
             }
         }
         debug!("Finished synchronization with peers");
+        self.metrics.record_sync(started.elapsed(), failed.len());
+        self.emit(CatalogEvent::SyncFinished {
+            failed: failed.clone(),
+        });
+
+        if cancelled {
+            return Err(DistStoreError::SyncCancelled.into());
+        }
+        if !failed.is_empty() {
+            return Err(DistStoreError::PartialSync { failed }.into());
+        }
         Ok(())
     }
 
-    /// This function should be responsible for retrieving a photo file by the ID.
-    /// The implementation lies out of the scope of this concept.
-    pub fn retrive_photo(_ymd: YearMonthDay, _hash: Data) -> Result<Vec<u8>> {
+    /// Compares the checksum trees with live peers, without transferring anything,
+    /// and returns the days that a synchronization would transfer.
+    /// The change logs are not used, and days transferred by an interrupted sync are included.
+    pub fn plan_sync(&self) -> Result<Vec<PeerSyncPlan>> {
+        self.plan_sync_with(&SyncOptions::default())
+    }
+
+    /// Same as [`plan_sync`](Self::plan_sync), but limited to the scope of the options, and can be cancelled.
+    pub fn plan_sync_with(&self, options: &SyncOptions) -> Result<Vec<PeerSyncPlan>> {
+        self.live_peers()
+            .iter()
+            .map(|peer| {
+                let instrumented = InstrumentedPeer::new(peer.as_ref(), &self.metrics);
+                self.check_hash_algorithm(&instrumented)?;
+                let progress = PeerProgress::new(options, peer.id());
+                let peer_scope = self.peer_scope(&peer.id());
+                let scope = ScopeFilter {
+                    peer: &peer_scope,
+                    sync: options.scope(),
+                };
+                let direction = self.peer_direction(&peer.id());
+                Ok(self
+                    .diff_tree(&instrumented, direction, scope, &progress)?
+                    .plan)
+            })
+            .collect()
+    }
+
+    /// Otherwise all checksums would differ, and the whole catalog would be transferred
+    fn check_hash_algorithm(&self, peer: &dyn RemotePeer) -> Result<()> {
+        let (local, remote) = (self.storage.hash_algorithm(), peer.hash_algorithm()?);
+        if local != remote {
+            return Err(DistStoreError::HashAlgorithmMismatch {
+                peer: peer.id(),
+                local,
+                remote,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Days changed since the previous sync are transferred first, using the change logs of both sides.
+    /// Then the checksum tree is compared, that is cheap if the logs had all the changes,
+    /// and catches everything the logs missed (e.g. a truncated log).
+    /// Only the days in the scope of the peer and in the scope of the sync are transferred.
+    fn sync_with_peer(
+        &self,
+        peer: &dyn RemotePeer,
+        sync_scope: &SyncScope,
+        progress: &PeerProgress,
+    ) -> Result<()> {
+        progress.enter(None)?;
+        self.check_hash_algorithm(peer)?;
+
+        let peer_id = peer.id();
+        let peer_scope = self.peer_scope(&peer_id);
+        let scope = ScopeFilter {
+            peer: &peer_scope,
+            sync: sync_scope,
+        };
+        let pull = Checkpoints {
+            storage: &self.storage,
+            peer: &peer_id,
+            direction: TransferDirection::Pull,
+        };
+        let push = Checkpoints {
+            direction: TransferDirection::Push,
+            ..pull
+        };
+        let direction = self.peer_direction(&peer_id);
+        let (mut pulled, pushed) = self.storage.get_sync_cursor(&peer_id)?;
+        // Days of a pruned feed are not known, the checksum tree catches them
+        let feed_days = |feed: &ChangeFeed| {
+            changed_days(feed)
+                .into_iter()
+                .filter(|ymd| scope.contains_day(*ymd))
+                .collect_vec()
+        };
+        // Feeds of the directions the peer is not synchronized in are not read, and their cursors stay.
+        // Read before pulling, so that the days received from the peer in this sync are not sent back in it.
+        // The received days are logged locally though, so the next sync proposes them to the peer,
+        // which finds nothing new in them.
+        let mut push_days = BTreeSet::new();
+        let mut local_seq = pushed;
+        if direction.allows(TransferDirection::Push) {
+            loop {
+                let feed = self.storage.changes_since(local_seq)?;
+                push_days.extend(feed_days(&feed));
+                local_seq = feed.last_seq;
+                if !feed.has_more {
+                    break;
+                }
+            }
+        }
+        // Cursors are moved as soon as a page of the feed is transferred, so that a restarted sync doesn't transfer it again.
+        // It is safe even if the rest of the sync fails, as the checksum tree is walked anyway.
+        if direction.allows(TransferDirection::Pull) {
+            loop {
+                let feed = peer.changes_since(pulled)?;
+                let pull_days = feed_days(&feed);
+                progress.add_days(pull_days.len());
+                fill_ymd_gaps(&self.identity, peer, self, pull_days, &pull, &[], progress)?;
+                pulled = feed.last_seq;
+                self.storage.set_sync_cursor(&peer_id, pulled, pushed)?;
+                if !feed.has_more {
+                    break;
+                }
+            }
+        }
+        progress.add_days(push_days.len());
+        fill_ymd_gaps(
+            &self.identity,
+            self,
+            peer,
+            push_days.into_iter().collect(),
+            &push,
+            &[],
+            progress,
+        )?;
+        self.storage.set_sync_cursor(&peer_id, pulled, local_seq)?;
+
+        let diff = self.diff_tree(peer, direction, scope, progress)?;
+        progress.add_days(diff.plan.pull.len() + diff.plan.push.len());
+        fill_ymd_gaps(
+            &self.identity,
+            peer,
+            self,
+            diff.plan.pull,
+            &pull,
+            &diff.remote_days,
+            progress,
+        )?;
+        fill_ymd_gaps(
+            &self.identity,
+            self,
+            peer,
+            diff.plan.push,
+            &push,
+            &[],
+            progress,
+        )?;
+
+        self.sync_albums(peer, direction, progress)?;
+
+        // After a one-way sync the days still differ, and the checkpoints keep them from being transferred again
+        if direction == SyncDirection::Bidirectional {
+            self.storage.clear_sync_checkpoints(&peer_id)?;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.storage
+            .record_peer_sync(&peer_id, now, diff.remote_years)
+    }
+
+    /// Exchanges the states of the albums whose checksums differ, see [`album`](crate::album).
+    /// Albums are not partitioned by date, so they are not limited by the sync scope.
+    /// Whole states are exchanged, so after a one-way sync the albums still differ,
+    /// and are sent again on the next one. Removed additions are kept as tombstones,
+    /// as a peer that hasn't seen the removal would add them back otherwise.
+    fn sync_albums(
+        &self,
+        peer: &dyn RemotePeer,
+        direction: SyncDirection,
+        progress: &PeerProgress,
+    ) -> Result<()> {
+        let _enter = tracing::debug_span!("sync_albums").entered();
+        let local: BTreeMap<String, Checksum> =
+            self.storage.get_albums_checksums()?.into_iter().collect();
+        let remote: BTreeMap<String, Checksum> = peer.get_albums_checksums()?.into_iter().collect();
+        let differ = local
+            .keys()
+            .chain(remote.keys())
+            .filter(|name| local.get(*name) != remote.get(*name))
+            .collect::<BTreeSet<_>>();
+        for name in differ {
+            progress.enter(None)?;
+            if direction.allows(TransferDirection::Pull) && remote.contains_key(name) {
+                if let Some(album) = peer.get_album(name)? {
+                    self.storage.merge_album(&album)?;
+                }
+            }
+            // After the pull the local album includes the remote one, so both sides end up equal
+            if direction.allows(TransferDirection::Push) {
+                if let Some(proposal) = self.propose_album(name)? {
+                    peer.merge_album(&proposal)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the checksum trees of the node and the peer, and finds the days that differ.
+    /// Partitions that are equal on both sides are skipped, so after an interruption
+    /// the walk resumes from the partitions that are not transferred yet,
+    /// and the checkpoints skip the days that still differ, but have been transferred in one of the directions.
+    /// Only the days of the given direction and in the scope are listed.
+    fn diff_tree(
+        &self,
+        peer: &dyn RemotePeer,
+        direction: SyncDirection,
+        scope: ScopeFilter,
+        progress: &PeerProgress,
+    ) -> Result<TreeDiff> {
+        let (pulls, pushes) = (
+            direction.allows(TransferDirection::Pull),
+            direction.allows(TransferDirection::Push),
+        );
+        // Cyclomatic complexity is not great, but in this case it makes the alrorithm clearer
+        let remote_years = peer.get_years_checksums()?;
+        let mut diff = TreeDiff {
+            plan: PeerSyncPlan {
+                peer: peer.id(),
+                ..PeerSyncPlan::default()
+            },
+            remote_days: Vec::new(),
+            remote_years: remote_years.clone(),
+        };
+        let (missing_on_local, missing_on_remote, diff_y) = calc_scoped_diff(
+            &self.get_years_checksums()?,
+            &remote_years,
+            |y| scope.clip(ymd_interval_for_y(y)).is_some(),
+            |y| scope.covers(ymd_interval_for_y(y)),
+            |y| self.same_in_scope(peer, scope.clip(ymd_interval_for_y(y))),
+        )?;
+        if pulls {
+            diff.plan.pull.extend(missing_days(
+                peer,
+                self,
+                missing_on_local,
+                ymd_interval_for_y,
+                scope,
+                progress,
+            )?);
+        }
+        if pushes {
+            diff.plan.push.extend(missing_days(
+                self,
+                peer,
+                missing_on_remote,
+                ymd_interval_for_y,
+                scope,
+                progress,
+            )?);
+        }
+
+        for y in diff_y {
+            progress.enter(Some(Partition::Year(y)))?;
+            let (missing_on_local, missing_on_remote, diff_ym) = calc_scoped_diff(
+                &self.get_months_checksum(y)?,
+                &peer.get_months_checksum(y)?,
+                |ym| scope.clip(ymd_interval_for_ym(ym)).is_some(),
+                |ym| scope.covers(ymd_interval_for_ym(ym)),
+                |ym| self.same_in_scope(peer, scope.clip(ymd_interval_for_ym(ym))),
+            )?;
+            if pulls {
+                diff.plan.pull.extend(missing_days(
+                    peer,
+                    self,
+                    missing_on_local,
+                    ymd_interval_for_ym,
+                    scope,
+                    progress,
+                )?);
+            }
+            if pushes {
+                diff.plan.push.extend(missing_days(
+                    self,
+                    peer,
+                    missing_on_remote,
+                    ymd_interval_for_ym,
+                    scope,
+                    progress,
+                )?);
+            }
+
+            for ym in diff_ym {
+                progress.enter(Some(Partition::Month(ym)))?;
+                let remote_days = peer.get_days_checksum(ym)?;
+                let (missing_on_local, missing_on_remote, diff_ymd) = calc_scoped_diff(
+                    &self.get_days_checksum(ym)?,
+                    &remote_days,
+                    |ymd| scope.contains_day(ymd),
+                    |_| true,
+                    |_| Ok(false),
+                )?;
+                if pulls {
+                    diff.plan
+                        .pull
+                        .extend(missing_on_local.iter().chain(&diff_ymd));
+                }
+                if pushes {
+                    diff.plan
+                        .push
+                        .extend(missing_on_remote.iter().chain(&diff_ymd));
+                }
+                // Listed in the order of the days, so the whole list stays sorted
+                diff.remote_days.extend(remote_days);
+            }
+        }
+        diff.plan.pull.sort();
+        diff.plan.push.sort();
+        Ok(diff)
+    }
+
+    /// Whether the days of the interval, i.e. days of a year or month in the scope, are the same on the node and the peer.
+    fn same_in_scope(
+        &self,
+        peer: &dyn RemotePeer,
+        interval: Option<(YearMonthDay, YearMonthDay)>,
+    ) -> Result<bool> {
+        match interval {
+            Some((from, to)) => {
+                Ok(self.get_range_checksum(from, to)? == peer.get_range_checksum(from, to)?)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Returns the state of the album signed by the node, to be merged by a peer with
+    /// [`RemotePeer::merge_album`], `None` if there is no such album.
+    pub fn propose_album(&self, name: &str) -> Result<Option<AlbumProposal>> {
+        Ok(self
+            .storage
+            .get_album(name)?
+            .map(|album| AlbumProposal::new(&self.identity, album)))
+    }
+
+    /// Creates an empty album, that is synchronized with the peers, see [`album`](crate::album).
+    pub fn create_album(&self, name: &str) -> Result<()> {
+        self.storage.create_album(name)?;
+        Ok(())
+    }
+
+    /// Adds objects to the album. An object removed concurrently by a peer stays in the album.
+    pub fn add_to_album(&self, name: &str, ids: &[Data]) -> Result<()> {
+        self.storage.add_to_album(name, ids)?;
+        Ok(())
+    }
+
+    pub fn remove_from_album(&self, name: &str, ids: &[Data]) -> Result<()> {
+        self.storage.remove_from_album(name, ids)?;
+        Ok(())
+    }
+
+    /// Adds a photo taken at given day, that is kept on this host.
+    /// The photo content is stored locally and its ID is labeled with this node.
+    /// The size of the content is recorded in the metadata of the photo.
+    /// Returns the object ID of the photo.
+    pub fn ingest_photo(&self, ymd: YearMonthDay, bytes: &[u8]) -> Result<Data> {
+        let metadata = ObjectMetadata::default().with_size(bytes.len() as u64);
+        self.keep_photo(ymd, bytes, &[(object_id(bytes), metadata)])
+    }
+
+    /// Stores the content of the photo, and labels it as kept by this node, within one transaction.
+    /// Returns the object ID of the photo.
+    fn keep_photo(
+        &self,
+        ymd: YearMonthDay,
+        bytes: &[u8],
+        metadata: &[(Data, ObjectMetadata)],
+    ) -> Result<Data> {
+        let id = object_id(bytes);
+        let photos = [(id.clone(), vec![self.id()])];
+        let claims = [self.identity.claim_location(&id)];
+        let (_, change) = self
+            .storage
+            .merge_photos_with_blob(ymd, bytes, &photos, &claims, metadata)?;
+        if let Some(change) = change {
+            self.emit(CatalogEvent::DayChanged(change));
+        }
+        Ok(id)
+    }
+
+    pub fn set_replication_policy(&self, policy: ReplicationPolicy) {
+        *self.replication_policy.write().unwrap() = policy;
+    }
+
+    pub fn replication_policy(&self) -> ReplicationPolicy {
+        self.replication_policy.read().unwrap().clone()
+    }
+
+    /// Returns objects that are kept by less peers than the replication policy requires,
+    /// along with suggestions which reachable peers (including this node) should fetch them.
+    /// A peer is considered reachable if it responds to a year checksums request.
+    pub fn replication_report(&self) -> Result<ReplicationReport> {
+        let policy = self.replication_policy();
+        let mut candidates = vec![self.id()];
+        for peer in self.peers_snapshot() {
+            match peer.get_years_checksums() {
+                Ok(_) => candidates.push(peer.id()),
+                Err(e) => debug!("Peer {:?} is unreachable: {}", peer.id(), e),
+            }
+        }
+
+        let mut report = ReplicationReport::default();
+        for ymd in self
+            .storage
+            .get_existing_days_in_range(0, YearMonthDay::MAX)?
+        {
+            let target = policy.target_for(ym_to_y(ymd_to_ym(ymd)));
+            for (data, holders) in self.storage.get_photos(ymd)?.unwrap_or_default() {
+                if holders.len() >= target {
+                    continue;
+                }
+                let suggested = suggest_fetchers(&data, &holders, &candidates, target);
+                report.under_replicated.push(UnderReplicated {
+                    ymd,
+                    data,
+                    holders,
+                    target,
+                    suggested,
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    /// Fetches content of under-replicated objects that this node is suggested to keep,
+    /// and labels them as kept by this node. The new labels reach other peers with the next sync.
+    /// Objects that can't be fetched from any of the holders are skipped until the next attempt.
+    /// Returns the number of fetched objects.
+    pub fn repair_replication(&self) -> Result<usize> {
+        let report = self.replication_report()?;
+        let peers = self.peers_snapshot();
+        let self_id = self.id();
+        let mut repaired = 0;
+        for entry in report.suggested_for(&self_id) {
+            let blob = match self.storage.get_blob(&entry.data)? {
+                Some(blob) => Some(blob),
+                None => self.fetch_blob(&peers, &entry.holders, &entry.data, &mut None)?,
+            };
+            let Some(blob) = blob else {
+                debug!("Object {:?} is not available on any holder", entry.data);
+                continue;
+            };
+            self.keep_photo(entry.ymd, &blob, &[])?;
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+
+    /// Starts a background thread that periodically calls [`repair_replication`](Self::repair_replication).
+    /// The thread stops when returned handle is dropped, or the node itself is dropped.
+    pub fn start_background_repair(self: &Arc<Self>, interval: Duration) -> BackgroundRepair {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let node: Weak<CatalogNode> = Arc::downgrade(self);
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let Some(node) = node.upgrade() else {
+                    break;
+                };
+                match node.repair_replication() {
+                    Ok(repaired) => debug!("Background repair fetched {} objects", repaired),
+                    Err(e) => debug!("Background repair failed: {}", e),
+                }
+            }
+        });
+        BackgroundRepair {
+            stop_tx: Some(stop_tx),
+            thread: Some(thread),
+        }
+    }
+
+    fn peers_snapshot(&self) -> Vec<Arc<dyn RemotePeer>> {
+        let peers_guard = &self.peers.read().unwrap();
+        peers_guard.deref().clone()
+    }
+
+    /// Retrieves content of a photo by its ID.
+    /// The content received from peers is verified against the ID, but not stored locally.
+    /// Fails with [`DistStoreError::CorruptedBlobFromPeer`] if some peers have sent the content,
+    /// but none of them a valid one.
+    /// Args:
+    /// * ymd - day the photo belongs to, used to find peers that keep the photo
+    /// * id - object ID of the photo
+    pub fn retrive_photo(&self, ymd: YearMonthDay, id: &[u8]) -> Result<Option<Vec<u8>>> {
         // 1 - Check locally
+        if let Some(blob) = self.storage.get_blob(id)? {
+            return Ok(Some(blob));
+        }
         // 2 - Check known peers
+        let peers = self.peers_snapshot();
+        let holders = self
+            .storage
+            .get_photos(ymd)?
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(data, _)| data == id)
+            .map(|(_, holders)| holders)
+            .unwrap_or_default();
+        let mut corrupted = None;
+        if let Some(blob) = self.fetch_blob(&peers, &holders, id, &mut corrupted)? {
+            return Ok(Some(blob));
+        }
         // 3 - If known peers now available, check for other peers
-        todo!()
+        let others = peers
+            .iter()
+            .map(|p| p.id())
+            .filter(|p| !holders.contains(p))
+            .collect_vec();
+        if let Some(blob) = self.fetch_blob(&peers, &others, id, &mut corrupted)? {
+            return Ok(Some(blob));
+        }
+        match corrupted {
+            Some(e) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Tries to download the object content from the peers that keep it.
+    /// Content is verified against the object ID, peers that send a wrong content
+    /// are recorded as misbehaving, and the next holder is tried.
+    /// Args:
+    /// * corrupted - set to the error of the last peer that has sent a wrong content
+    fn fetch_blob(
+        &self,
+        peers: &[Arc<dyn RemotePeer>],
+        holders: &[Peer],
+        id: &[u8],
+        corrupted: &mut Option<DistStoreError>,
+    ) -> Result<Option<Vec<u8>>> {
+        for peer in peers.iter().filter(|p| holders.contains(&p.id())) {
+            match InstrumentedPeer::new(peer.as_ref(), &self.metrics).get_blob(id) {
+                Ok(Some(blob)) if object_id(&blob) == id => return Ok(Some(blob)),
+                Ok(Some(_)) => {
+                    let e = DistStoreError::CorruptedBlobFromPeer {
+                        peer: peer.id(),
+                        id: id.to_vec(),
+                    };
+                    debug!("{}", e);
+                    self.storage.record_misbehavior(&peer.id())?;
+                    *corrupted = Some(e);
+                }
+                Ok(None) => debug!("Peer {:?} doesn't have object {:?}", peer.id(), id),
+                Err(e) => debug!("Failed to fetch {:?} from {:?}: {}", id, peer.id(), e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Handle of the background replication repair thread.
+/// Dropping the handle stops the thread.
+pub struct BackgroundRepair {
+    stop_tx: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl BackgroundRepair {
+    /// Stops the background repair and waits until the current repair round is finished.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // Dropping the sender wakes up the thread
+        self.stop_tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
     }
 }
 
-/// For given dates performs the data interchange between peers.
-/// Arg:
-/// * src - peer we synchronizing data from
-/// * dst - peer that receives data
-/// * dates - the transfer should be performed for
-/// * date_to_interval - function that converts given date to a renge of year/month/day partitions
-///   it is required to make the sync function to be able to work with both year and year/month partitions.
-fn fill_gaps(
+impl Drop for BackgroundRepair {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// For given partitions (years or months) that are missing on the destination peer,
+/// lists the days the source peer has.
+fn missing_days(
     src: &dyn RemotePeer,
     dst: &dyn RemotePeer,
     dates: Vec<u32>,
     date_to_interval: fn(u32) -> (YearMonthDay, YearMonthDay),
-) -> Result<()> {
+    scope: ScopeFilter,
+    progress: &PeerProgress,
+) -> Result<Vec<YearMonthDay>> {
+    let mut result = Vec::new();
     for d in dates {
         let (start, end) = date_to_interval(d);
-        let days = src.get_existing_days_in_range(start, end)?;
-        for ymd in days {
-            if let Some(photos) = src.get_data(ymd)? {
-                dst.propose(ymd, &photos)?;
-            }
+        let span = tracing::debug_span!(
+            "missing_days",
+            src = %format_peer(&src.id()),
+            dst = %format_peer(&dst.id()),
+            year = field::Empty,
+            month = field::Empty,
+            day = field::Empty,
+        );
+        // The interval is either a month or a whole year
+        let partition = if ymd_to_ym(start) == ymd_to_ym(end) {
+            Partition::Month(ymd_to_ym(start))
+        } else {
+            Partition::Year(ym_to_y(ymd_to_ym(start)))
+        };
+        partition.record(&span);
+        let _enter = span.enter();
+        progress.enter(Some(partition))?;
+        // Partitions out of the scope are dropped by the diff
+        if let Some((start, end)) = scope.clip((start, end)) {
+            result.extend(src.get_existing_days_in_range(start, end)?);
         }
     }
-    Ok(())
+    Ok(result)
 }
 
 /// For given year/month/day partitions performs the data interchange between peers.
+/// Args:
+/// * known - checksums of the source days, if the caller has them (see [`Checkpoints::source_checksum`])
 fn fill_ymd_gaps(
+    proposer: &NodeIdentity,
     src: &dyn RemotePeer,
     dst: &dyn RemotePeer,
     ymds: Vec<YearMonthDay>,
+    checkpoints: &Checkpoints,
+    known: &[(YearMonthDay, Checksum)],
+    progress: &PeerProgress,
 ) -> Result<()> {
+    let _enter = tracing::debug_span!(
+        "fill_ymd_gaps",
+        src = %format_peer(&src.id()),
+        dst = %format_peer(&dst.id()),
+        days = ymds.len(),
+    )
+    .entered();
     for ymd in ymds {
-        if let Some(photos) = src.get_data(ymd)? {
-            dst.propose(ymd, &photos)?;
-        }
+        transfer_day(proposer, src, dst, ymd, checkpoints, known, progress)?;
+    }
+    Ok(())
+}
+
+/// Proposes the day data of the source peer, along with location claims, to the destination peer.
+/// Days that have been transferred already, and haven't changed since, are skipped.
+fn transfer_day(
+    proposer: &NodeIdentity,
+    src: &dyn RemotePeer,
+    dst: &dyn RemotePeer,
+    ymd: YearMonthDay,
+    checkpoints: &Checkpoints,
+    known: &[(YearMonthDay, Checksum)],
+    progress: &PeerProgress,
+) -> Result<()> {
+    progress.enter(Some(Partition::Day(ymd)))?;
+    let span = tracing::debug_span!(
+        "transfer_day",
+        year = field::Empty,
+        month = field::Empty,
+        day = field::Empty,
+    );
+    Partition::Day(ymd).record(&span);
+    let _enter = span.enter();
+    let checksum = checkpoints.source_checksum(ymd, known)?;
+    if checksum.is_some() && checksum == checkpoints.get(ymd)? {
+        debug!("Day {} has already been transferred", ymd);
+        progress.complete_day(ymd);
+        return Ok(());
+    }
+    if let Some(day) = src.get_data(ymd)? {
+        let claims = src.get_location_claims(ymd)?;
+        dst.propose(&Proposal::new_with_metadata(
+            proposer,
+            ymd,
+            day.photos,
+            claims,
+            day.metadata,
+        ))?;
+    }
+    if let Some(checksum) = checksum {
+        checkpoints.set(ymd, &checksum)?;
     }
+    progress.complete_day(ymd);
     Ok(())
 }
 
+/// Result of the checksum tree comparison with a peer.
+struct TreeDiff {
+    plan: PeerSyncPlan,
+    /// Checksums of the peer days listed during the comparison, sorted
+    remote_days: Vec<(YearMonthDay, Checksum)>,
+    /// Year checksums of the peer, as they were before the synchronization
+    remote_years: Vec<(Year, Checksum)>,
+}
+
+/// Days transferred in one direction during a synchronization with a peer,
+/// so that they are not transferred again if the synchronization is interrupted and restarted.
+/// A checkpoint keeps the checksum of the source day: merges only add object IDs and labels,
+/// so while the source day stays the same, the destination still has everything from it.
+/// Years and months are not recorded: after a one-way transfer of their days their checksums still differ,
+/// as the destination may have days the source doesn't, so they couldn't be confirmed by a checksum.
+/// Comparing them again costs a checksum call per partition, without transferring day data.
+struct Checkpoints<'a> {
+    storage: &'a LocalStorage,
+    peer: &'a [u8],
+    direction: TransferDirection,
+}
+
+impl Checkpoints<'_> {
+    /// Checksum of the source day, if it is known without extra calls to the peer.
+    /// Local days are read right before the transfer, as they can change during the sync.
+    /// Args:
+    /// * known - checksums of the source days, sorted, that the caller has got from the peer
+    fn source_checksum(
+        &self,
+        ymd: YearMonthDay,
+        known: &[(YearMonthDay, Checksum)],
+    ) -> Result<Option<Checksum>> {
+        match self.direction {
+            TransferDirection::Push => self.storage.get_day_checksum(ymd),
+            TransferDirection::Pull => Ok(known
+                .binary_search_by_key(&ymd, |(d, _)| *d)
+                .ok()
+                .map(|i| known[i].1.clone())),
+        }
+    }
+
+    fn get(&self, ymd: YearMonthDay) -> Result<Option<Checksum>> {
+        self.storage
+            .get_sync_checkpoint(self.peer, self.direction, ymd)
+    }
+
+    fn set(&self, ymd: YearMonthDay, checksum: &[u8]) -> Result<()> {
+        self.storage
+            .set_sync_checkpoint(self.peer, self.direction, ymd, checksum)
+    }
+}
+
+/// Days mentioned in the change feed, each day once.
+fn changed_days(feed: &ChangeFeed) -> Vec<YearMonthDay> {
+    feed.changes
+        .iter()
+        .map(|c| c.ymd)
+        .sorted()
+        .dedup()
+        .collect()
+}
+
 /// Takes two sorted sequences of pairs (data, checksum)
 /// and returns triplet:
 /// * pairs that exist in second sequence but absent in the first one
@@ -241,6 +1551,38 @@ fn calc_diff(
     (missing_on_local, missing_on_remote, different)
 }
 
+/// Same as [`calc_diff`], but only for the partitions in the scope.
+/// Checksums of partially covered partitions include days out of the scope,
+/// so the ones that differ are kept only if their days in the scope differ too.
+/// Args:
+/// * in_scope - whether any days of the partition are in the scope
+/// * covered - whether all the days of the partition are in the scope
+/// * same_in_scope - whether the days of the partition in the scope are the same on both sides
+fn calc_scoped_diff(
+    local: &[(u32, Checksum)],
+    remote: &[(u32, Checksum)],
+    in_scope: impl Fn(u32) -> bool,
+    covered: impl Fn(u32) -> bool,
+    same_in_scope: impl Fn(u32) -> Result<bool>,
+) -> Result<(Vec<u32>, Vec<u32>, Vec<u32>)> {
+    let scoped = |checksums: &[(u32, Checksum)]| {
+        checksums
+            .iter()
+            .filter(|(d, _)| in_scope(*d))
+            .cloned()
+            .collect_vec()
+    };
+    let (local, remote) = (scoped(local), scoped(remote));
+    let (missing_on_local, missing_on_remote, all_different) = calc_diff(&local, &remote);
+    let mut different = Vec::new();
+    for d in all_different {
+        if covered(d) || !same_in_scope(d)? {
+            different.push(d);
+        }
+    }
+    Ok((missing_on_local, missing_on_remote, different))
+}
+
 /// Implementation of remote peer functionality for a local instance of catalog itself.
 /// Designed for testing purposes.
 /// For production, we probaly need an implementation of `RemotePeer` that interacts with peers over the network.
@@ -251,7 +1593,47 @@ impl RemotePeer for CatalogNode {
 
     fn notify_added_by(&self, peer: Arc<dyn RemotePeer>) {
         debug!("Peer {:?}, has been added by {:?}", self.id(), peer.id());
-        // Here we can make a cross reference if needed
+        if let Err(e) = self.add_peer(peer) {
+            debug!("Peer {:?} is not added back: {}", self.id(), e);
+        }
+    }
+
+    fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        Ok(self.storage.hash_algorithm())
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<ChangeFeed> {
+        self.storage.changes_since(seq)
+    }
+
+    /// New members are connected by the next [`gossip_round`](CatalogNode::gossip_round),
+    /// so that the caller doesn't wait for the connections.
+    fn gossip(&self, members: Vec<MemberUpdate>) -> Result<Vec<MemberUpdate>> {
+        self.membership.merge(members);
+        Ok(self.membership.members())
+    }
+
+    fn probe(&self, target: &[u8]) -> Result<bool> {
+        let peer = self.peers_snapshot().into_iter().find(|p| p.id() == target);
+        // Any call that reaches the target will do, this one is the cheapest
+        Ok(peer.is_some_and(|p| p.hash_algorithm().is_ok()))
+    }
+
+    fn get_albums_checksums(&self) -> Result<Vec<(String, Checksum)>> {
+        self.storage.get_albums_checksums()
+    }
+
+    fn get_album(&self, name: &str) -> Result<Option<Album>> {
+        self.storage.get_album(name)
+    }
+
+    /// Refused with [`DistStoreError::ReadOnlyNode`] in the read-only mode, same as proposals.
+    fn merge_album(&self, proposal: &AlbumProposal) -> Result<Checksum> {
+        if self.is_read_only() {
+            return Err(DistStoreError::ReadOnlyNode.into());
+        }
+        proposal.verify()?;
+        self.storage.merge_album(&proposal.album)
     }
 
     fn get_years_checksums(&self) -> Result<Vec<(u32, Vec<u8>)>> {
@@ -274,12 +1656,34 @@ impl RemotePeer for CatalogNode {
         self.storage.get_existing_days_in_range(ymd_from, ymd_to)
     }
 
-    fn get_data(&self, ymd: u32) -> Result<Option<Vec<(Data, Vec<Peer>)>>> {
-        self.storage.get_photos(ymd)
+    fn get_range_checksum(&self, ymd_from: YearMonthDay, ymd_to: YearMonthDay) -> Result<Checksum> {
+        self.storage.get_range_checksum(ymd_from, ymd_to)
+    }
+
+    fn get_data(&self, ymd: u32) -> Result<Option<DayData>> {
+        self.storage.get_day(ymd)
     }
 
-    fn propose(&self, ymd: u32, data: &[(Vec<u8>, Vec<Peer>)]) -> Result<Vec<u8>> {
-        self.storage.add_photos_to_day(ymd, data)
+    fn get_location_claims(&self, ymd: YearMonthDay) -> Result<Vec<LocationClaim>> {
+        self.storage.get_location_claims(ymd)
+    }
+
+    fn propose(&self, proposal: &Proposal) -> Result<Vec<u8>> {
+        // Days pulled by the node itself are proposed by the node
+        if self.is_read_only() && proposal.proposer != self.id() {
+            return Err(DistStoreError::ReadOnlyNode.into());
+        }
+        proposal.verify()?;
+        self.merge_photos(
+            proposal.ymd,
+            &proposal.photos,
+            &proposal.claims,
+            &proposal.metadata,
+        )
+    }
+
+    fn get_blob(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.storage.get_blob(id)
     }
 }
 
@@ -308,4 +1712,29 @@ mod test {
         let res = calc_diff(&loc, &rem);
         assert_eq!(res, (vec![], vec![], vec![1]));
     }
+
+    proptest::proptest! {
+        /// Compares the merge-like implementation with a straightforward one based on maps
+        #[test]
+        fn calc_diff_matches_reference(
+            local in proptest::collection::btree_map(0u32..50, 0u8..3, 0..20),
+            remote in proptest::collection::btree_map(0u32..50, 0u8..3, 0..20),
+        ) {
+            let to_seq = |m: &std::collections::BTreeMap<u32, u8>| {
+                m.iter().map(|(k, v)| (*k, vec![*v])).collect_vec()
+            };
+            let missing_on_local = remote.keys().filter(|k| !local.contains_key(k)).copied().collect_vec();
+            let missing_on_remote = local.keys().filter(|k| !remote.contains_key(k)).copied().collect_vec();
+            let different = local
+                .iter()
+                .filter(|(k, v)| remote.get(k).is_some_and(|r| r != *v))
+                .map(|(k, _)| *k)
+                .collect_vec();
+
+            proptest::prop_assert_eq!(
+                (missing_on_local, missing_on_remote, different),
+                calc_diff(&to_seq(&local), &to_seq(&remote))
+            );
+        }
+    }
 }
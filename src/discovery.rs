@@ -0,0 +1,165 @@
+//! Discovery of peers on the local network.
+//!
+//! [`Discovery`](Discovery) periodically announces the node to a UDP multicast group,
+//! and listens for announcements of other nodes in the same group.
+//! An announcement contains the node ID and the port of its [`PeerServer`](crate::transport::PeerServer),
+//! signed by the node key. Announced peers that are in the allow-list of the catalog
+//! are connected with [`NetworkPeer`](NetworkPeer) and added to the node.
+//! The signature only proves that the announcement was made by the node,
+//! the connection itself is authenticated by the secure channel handshake.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::catalog::{CatalogNode, RemotePeer};
+use crate::identity::verify_signature;
+use crate::local_storage::Peer;
+use crate::transport::NetworkPeer;
+
+const ANNOUNCEMENT_DOMAIN: &[u8] = b"photo-sync/announcement/v1";
+
+/// Announcements are small, anything bigger is not ours
+const MAX_ANNOUNCEMENT_SIZE: usize = 512;
+
+/// How often the stop flag is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Multicast group (address and port) the announcements are sent to
+    pub group: SocketAddrV4,
+    /// Address of the network interface to announce on, `UNSPECIFIED` for the default one
+    pub interface: Ipv4Addr,
+    /// Port of the peer server of the node, that is announced
+    pub server_port: u16,
+    /// How often the node announces itself
+    pub interval: Duration,
+}
+
+impl DiscoveryConfig {
+    /// Default group 239.255.77.77:47777 on the default interface, announcing every 5 seconds.
+    pub fn new(server_port: u16) -> Self {
+        DiscoveryConfig {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 77), 47777),
+            interface: Ipv4Addr::UNSPECIFIED,
+            server_port,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Signed statement of a node, that its peer server listens on given port.
+/// The IP address is taken from the datagram.
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    peer: Peer,
+    server_port: u16,
+    signature: Vec<u8>,
+}
+
+fn announcement_message(peer: &[u8], server_port: u16) -> Vec<u8> {
+    [ANNOUNCEMENT_DOMAIN, peer, &server_port.to_be_bytes()].concat()
+}
+
+/// Handle of the discovery thread.
+/// Dropping the handle stops announcing and listening, discovered peers stay added to the node.
+pub struct Discovery {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Discovery {
+    /// Joins the multicast group and starts a thread that announces the node and listens for other nodes.
+    pub fn start(node: Arc<CatalogNode>, config: DiscoveryConfig) -> Result<Self> {
+        let socket = join_group(&config)?;
+        let announcement = bincode::serialize(&Announcement {
+            peer: node.id(),
+            server_port: config.server_port,
+            signature: node
+                .identity()
+                .sign(&announcement_message(&node.id(), config.server_port)),
+        })?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                let mut last_announced: Option<Instant> = None;
+                let mut buf = [0u8; MAX_ANNOUNCEMENT_SIZE];
+                while !stopped.load(Ordering::SeqCst) {
+                    if last_announced.is_none_or(|t| t.elapsed() >= config.interval) {
+                        if let Err(e) = socket.send_to(&announcement, config.group) {
+                            debug!("Failed to announce {}: {}", node.name(), e);
+                        }
+                        last_announced = Some(Instant::now());
+                    }
+                    // Times out after the poll interval, so that the stop flag is checked
+                    if let Ok((len, from)) = socket.recv_from(&mut buf) {
+                        if let Err(e) = handle_announcement(&node, &buf[..len], from) {
+                            debug!("Failed to handle announcement from {}: {}", from, e);
+                        }
+                    }
+                }
+            })
+        };
+        Ok(Discovery {
+            stopped,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Creates a socket that receives datagrams of the group.
+/// Several sockets (e.g. several nodes on the same host) can join the same group.
+fn join_group(config: &DiscoveryConfig) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.group.port())).into())?;
+    socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(socket.into())
+}
+
+/// Connects to the announced peer, if it is authorised and not connected yet.
+/// The peer is notified that it has been added, so that it can add this node as well.
+fn handle_announcement(node: &Arc<CatalogNode>, datagram: &[u8], from: SocketAddr) -> Result<()> {
+    let announcement: Announcement = bincode::deserialize(datagram)?;
+    if announcement.peer == node.id() {
+        return Ok(());
+    }
+    let message = announcement_message(&announcement.peer, announcement.server_port);
+    if !verify_signature(&announcement.peer, &message, &announcement.signature) {
+        debug!("Announcement from {} has invalid signature", from);
+        return Ok(());
+    }
+    if node.peer_ids().contains(&announcement.peer)
+        || !node.storage().is_peer_authorized(&announcement.peer)?
+    {
+        return Ok(());
+    }
+
+    let addr = SocketAddr::new(from.ip(), announcement.server_port);
+    let peer: Arc<dyn RemotePeer> = Arc::new(NetworkPeer::connect(node, addr, &announcement.peer)?);
+    debug!("Discovered peer {:?} at {}", announcement.peer, addr);
+    node.add_peer_with_address(peer.clone(), &addr.to_string())?;
+    peer.notify_added_by(node.clone());
+    Ok(())
+}
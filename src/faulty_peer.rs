@@ -0,0 +1,265 @@
+//! Fault injection for testing the resilience of the synchronization.
+//!
+//! [`FaultyPeer`](FaultyPeer) wraps another [`RemotePeer`](RemotePeer) and breaks some of its calls.
+//! Faults are either scripted (a fault for each next call of a method),
+//! or happen randomly with given probability, using a seeded generator,
+//! so that failing scenarios are reproducible.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::album::Album;
+use crate::catalog::RemotePeer;
+use crate::hashing::HashAlgorithm;
+use crate::identity::{AlbumProposal, LocationClaim, Proposal};
+use crate::local_storage::{ChangeFeed, Checksum, DayData};
+use crate::membership::MemberUpdate;
+use crate::metrics::PeerMethod;
+use crate::opaque_date::{Year, YearMonth, YearMonthDay};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// The call returns an error without reaching the wrapped peer
+    Fail,
+    /// Only the first half of the result is returned.
+    /// Applies to `get_data` (photos of the day) and `get_blob` (content bytes),
+    /// other methods are not affected.
+    Truncate,
+    /// Object IDs returned by `get_data`, or content returned by `get_blob`, get a flipped bit.
+    /// Other methods are not affected.
+    Corrupt,
+    /// The call sleeps for given time, then fails as if it had timed out
+    Hang(Duration),
+    /// The call panics
+    Panic,
+}
+
+/// Decorator of a peer, that injects faults into its calls.
+pub struct FaultyPeer<P: RemotePeer + ?Sized> {
+    inner: Arc<P>,
+    scripts: Mutex<HashMap<PeerMethod, VecDeque<Option<Fault>>>>,
+    probabilities: HashMap<PeerMethod, Vec<(Fault, f64)>>,
+    rng: Mutex<StdRng>,
+    calls: Mutex<HashMap<PeerMethod, usize>>,
+}
+
+impl<P: RemotePeer + ?Sized> FaultyPeer<P> {
+    /// Wraps the peer. Without any scripts or probabilities all calls go through.
+    pub fn new(inner: Arc<P>) -> Self {
+        FaultyPeer {
+            inner,
+            scripts: Mutex::new(HashMap::new()),
+            probabilities: HashMap::new(),
+            rng: Mutex::new(StdRng::seed_from_u64(0)),
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the seed of the generator used for probabilistic faults.
+    pub fn with_seed(self, seed: u64) -> Self {
+        *self.rng.lock().unwrap() = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Appends faults for the next calls of the method, one entry per call.
+    /// `None` lets the call through. When the script is over, calls go through
+    /// (unless a probabilistic fault happens).
+    /// Args:
+    /// * method - method to break
+    /// * faults - outcomes of the next calls
+    pub fn with_script(self, method: PeerMethod, faults: Vec<Option<Fault>>) -> Self {
+        self.scripts
+            .lock()
+            .unwrap()
+            .entry(method)
+            .or_default()
+            .extend(faults);
+        self
+    }
+
+    /// Makes the method fail with given probability (0.0 - 1.0) on each call.
+    /// Scripted faults take precedence over probabilistic ones.
+    pub fn with_probability(mut self, method: PeerMethod, fault: Fault, probability: f64) -> Self {
+        self.probabilities
+            .entry(method)
+            .or_default()
+            .push((fault, probability));
+        self
+    }
+
+    /// Number of calls of the method made so far, including the broken ones.
+    pub fn calls(&self, method: PeerMethod) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .get(&method)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Wrapped peer
+    pub fn inner(&self) -> &Arc<P> {
+        &self.inner
+    }
+
+    /// Decides what happens with the current call of the method.
+    fn next_fault(&self, method: PeerMethod) -> Option<Fault> {
+        *self.calls.lock().unwrap().entry(method).or_default() += 1;
+        let scripted = self
+            .scripts
+            .lock()
+            .unwrap()
+            .get_mut(&method)
+            .and_then(|script| script.pop_front());
+        if let Some(fault) = scripted {
+            return fault;
+        }
+        let mut rng = self.rng.lock().unwrap();
+        self.probabilities
+            .get(&method)
+            .into_iter()
+            .flatten()
+            .find(|(_, probability)| rng.gen_bool(probability.clamp(0.0, 1.0)))
+            .map(|(fault, _)| fault.clone())
+    }
+
+    /// Applies faults that don't depend on the result of the call.
+    /// Returns the fault to apply to the result, if any.
+    fn before_call(&self, method: PeerMethod) -> Result<Option<Fault>> {
+        match self.next_fault(method) {
+            Some(Fault::Fail) => Err(anyhow!("Injected failure of {:?}", method)),
+            Some(Fault::Hang(duration)) => {
+                thread::sleep(duration);
+                Err(anyhow!("Call of {:?} timed out", method))
+            }
+            Some(Fault::Panic) => panic!("Injected panic in {:?}", method),
+            fault => Ok(fault),
+        }
+    }
+}
+
+fn flip_bit(bytes: &mut [u8]) {
+    if let Some(b) = bytes.first_mut() {
+        *b ^= 1;
+    }
+}
+
+impl<P: RemotePeer + ?Sized> RemotePeer for FaultyPeer<P> {
+    fn id(&self) -> Vec<u8> {
+        self.inner.id()
+    }
+
+    fn notify_added_by(&self, peer: Arc<dyn RemotePeer>) {
+        // There is no way to report an error, so failures just skip the notification
+        if self.before_call(PeerMethod::NotifyAddedBy).is_ok() {
+            self.inner.notify_added_by(peer)
+        }
+    }
+
+    fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        self.before_call(PeerMethod::GetHashAlgorithm)?;
+        self.inner.hash_algorithm()
+    }
+
+    fn get_years_checksums(&self) -> Result<Vec<(Year, Checksum)>> {
+        self.before_call(PeerMethod::GetYearsChecksums)?;
+        self.inner.get_years_checksums()
+    }
+
+    fn get_months_checksum(&self, y: Year) -> Result<Vec<(YearMonth, Checksum)>> {
+        self.before_call(PeerMethod::GetMonthsChecksum)?;
+        self.inner.get_months_checksum(y)
+    }
+
+    fn get_days_checksum(&self, ym: YearMonth) -> Result<Vec<(YearMonthDay, Checksum)>> {
+        self.before_call(PeerMethod::GetDaysChecksum)?;
+        self.inner.get_days_checksum(ym)
+    }
+
+    fn get_existing_days_in_range(
+        &self,
+        ymd_from: YearMonthDay,
+        ymd_to: YearMonthDay,
+    ) -> Result<Vec<YearMonthDay>> {
+        self.before_call(PeerMethod::GetExistingDaysInRange)?;
+        self.inner.get_existing_days_in_range(ymd_from, ymd_to)
+    }
+
+    fn get_range_checksum(&self, ymd_from: YearMonthDay, ymd_to: YearMonthDay) -> Result<Checksum> {
+        self.before_call(PeerMethod::GetRangeChecksum)?;
+        self.inner.get_range_checksum(ymd_from, ymd_to)
+    }
+
+    fn get_data(&self, ymd: u32) -> Result<Option<DayData>> {
+        let fault = self.before_call(PeerMethod::GetData)?;
+        let data = self.inner.get_data(ymd)?;
+        Ok(data.map(|mut day| {
+            let photos = &mut day.photos;
+            match fault {
+                Some(Fault::Truncate) => photos.truncate(photos.len() / 2),
+                Some(Fault::Corrupt) => photos.iter_mut().for_each(|(id, _)| flip_bit(id)),
+                _ => {}
+            }
+            day
+        }))
+    }
+
+    fn get_location_claims(&self, ymd: YearMonthDay) -> Result<Vec<LocationClaim>> {
+        self.before_call(PeerMethod::GetLocationClaims)?;
+        self.inner.get_location_claims(ymd)
+    }
+
+    fn propose(&self, proposal: &Proposal) -> Result<Vec<u8>> {
+        self.before_call(PeerMethod::Propose)?;
+        self.inner.propose(proposal)
+    }
+
+    fn get_blob(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        let fault = self.before_call(PeerMethod::GetBlob)?;
+        let blob = self.inner.get_blob(id)?;
+        Ok(blob.map(|mut blob| {
+            match fault {
+                Some(Fault::Truncate) => blob.truncate(blob.len() / 2),
+                Some(Fault::Corrupt) => flip_bit(&mut blob),
+                _ => {}
+            }
+            blob
+        }))
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<ChangeFeed> {
+        self.before_call(PeerMethod::ChangesSince)?;
+        self.inner.changes_since(seq)
+    }
+
+    fn gossip(&self, members: Vec<MemberUpdate>) -> Result<Vec<MemberUpdate>> {
+        self.before_call(PeerMethod::Gossip)?;
+        self.inner.gossip(members)
+    }
+
+    fn probe(&self, target: &[u8]) -> Result<bool> {
+        self.before_call(PeerMethod::Probe)?;
+        self.inner.probe(target)
+    }
+
+    fn get_albums_checksums(&self) -> Result<Vec<(String, Checksum)>> {
+        self.before_call(PeerMethod::GetAlbumsChecksums)?;
+        self.inner.get_albums_checksums()
+    }
+
+    fn get_album(&self, name: &str) -> Result<Option<Album>> {
+        self.before_call(PeerMethod::GetAlbum)?;
+        self.inner.get_album(name)
+    }
+
+    fn merge_album(&self, proposal: &AlbumProposal) -> Result<Checksum> {
+        self.before_call(PeerMethod::MergeAlbum)?;
+        self.inner.merge_album(proposal)
+    }
+}
@@ -0,0 +1,81 @@
+//! Hash algorithms for the checksum tree.
+//!
+//! Object IDs are always SHA-256 hashes of the content, so that they match the IDs of existing files,
+//! but the checksums of days, months and years can be calculated with a faster algorithm.
+//! Checksums made with different algorithms can't be compared,
+//! so peers check that they use the same algorithm before the synchronization.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::local_storage::Checksum;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    /// Used by all the databases created before the algorithm became configurable
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub(crate) fn hasher(&self) -> ChecksumHasher {
+        match self {
+            HashAlgorithm::Sha256 => ChecksumHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => ChecksumHasher::Blake3(Box::default()),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            other => bail!("Unknown hash algorithm {}", other),
+        }
+    }
+}
+
+/// Incremental hasher of the configured algorithm.
+pub(crate) enum ChecksumHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChecksumHasher {
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            ChecksumHasher::Sha256(hasher) => hasher.update(bytes),
+            ChecksumHasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Checksum {
+        match self {
+            ChecksumHasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            ChecksumHasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Cryptographic identities of the nodes.
+//! Each node owns an Ed25519 keypair, and the ID of the peer is its public key.
+//! This allows to check that:
+//! * a location label (peer X keeps object Y) was claimed by the peer X itself
+//! * a proposal of a day data was made by the node that signed it
+//! * a state of an album was merged by the node that signed it
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use log::debug;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::album::Album;
+use crate::catalog::DistStoreError;
+use crate::local_storage::{Data, Peer, Photo};
+use crate::metadata::ObjectMetadata;
+use crate::opaque_date::YearMonthDay;
+
+const LOCATION_CLAIM_DOMAIN: &[u8] = b"photo-sync/location-claim/v1";
+const PROPOSAL_DOMAIN: &[u8] = b"photo-sync/proposal/v1";
+const ALBUM_PROPOSAL_DOMAIN: &[u8] = b"photo-sync/album-proposal/v1";
+
+/// Keypair of a node. The public key is used as the ID of the node.
+pub struct NodeIdentity {
+    signing_key: SigningKey,
+}
+
+impl NodeIdentity {
+    /// Generates a new random keypair.
+    pub fn generate() -> Self {
+        NodeIdentity {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Restores the keypair from the secret key bytes.
+    pub fn from_secret_bytes(secret: &[u8; 32]) -> Self {
+        NodeIdentity {
+            signing_key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    /// ID of the node, that is its public key.
+    pub fn peer_id(&self) -> Peer {
+        self.signing_key.verifying_key().to_bytes().to_vec()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(message).to_bytes().to_vec()
+    }
+
+    /// Creates a signed statement that this node keeps the object with given ID.
+    pub fn claim_location(&self, data: &[u8]) -> LocationClaim {
+        LocationClaim {
+            data: data.to_vec(),
+            peer: self.peer_id(),
+            signature: self.sign(&location_claim_message(data)),
+        }
+    }
+}
+
+/// Checks that the message was signed by the peer with given ID.
+/// Returns false if the ID is not a valid public key.
+pub fn verify_signature(peer: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(key_bytes) = <&[u8; 32]>::try_from(peer) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(key_bytes) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    key.verify_strict(message, &signature).is_ok()
+}
+
+/// Signed statement of a peer, that it keeps the binary data of the object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocationClaim {
+    pub data: Data,
+    pub peer: Peer,
+    pub signature: Vec<u8>,
+}
+
+impl LocationClaim {
+    pub fn verify(&self) -> bool {
+        verify_signature(
+            &self.peer,
+            &location_claim_message(&self.data),
+            &self.signature,
+        )
+    }
+}
+
+fn location_claim_message(data: &[u8]) -> Vec<u8> {
+    [LOCATION_CLAIM_DOMAIN, data].concat()
+}
+
+/// Object IDs for a day, proposed by one node to another, along with their metadata records.
+/// Each location label must be backed by a claim signed by the labeled peer,
+/// and the whole proposal is signed by the proposing node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proposal {
+    pub ymd: YearMonthDay,
+    pub photos: Vec<Photo>,
+    pub claims: Vec<LocationClaim>,
+    pub metadata: Vec<(Data, ObjectMetadata)>,
+    pub proposer: Peer,
+    pub signature: Vec<u8>,
+}
+
+impl Proposal {
+    /// Creates a proposal signed by given node.
+    /// Location labels that are not backed by a valid claim are dropped (and logged),
+    /// because the receiving node would reject them anyway.
+    /// E.g. labels added before the labels were signed are never proposed, until the labeled peer claims them again.
+    /// Args:
+    /// * proposer - identity of the proposing node
+    /// * ymd - day the object IDs belong to
+    /// * photos - object IDs along with location labels
+    /// * claims - signed location claims for the labels
+    pub fn new(
+        proposer: &NodeIdentity,
+        ymd: YearMonthDay,
+        photos: Vec<Photo>,
+        claims: Vec<LocationClaim>,
+    ) -> Self {
+        Self::new_with_metadata(proposer, ymd, photos, claims, Vec::new())
+    }
+
+    /// Same as [`new`](Self::new), but also carries metadata records of the objects.
+    pub fn new_with_metadata(
+        proposer: &NodeIdentity,
+        ymd: YearMonthDay,
+        photos: Vec<Photo>,
+        claims: Vec<LocationClaim>,
+        metadata: Vec<(Data, ObjectMetadata)>,
+    ) -> Self {
+        let claims: Vec<LocationClaim> = claims.into_iter().filter(|c| c.verify()).collect();
+        let photos = photos
+            .into_iter()
+            .map(|(data, peers)| {
+                let (peers, unclaimed): (Vec<Peer>, Vec<Peer>) = peers
+                    .into_iter()
+                    .partition(|p| find_claim(&claims, &data, p).is_some());
+                for peer in unclaimed {
+                    debug!(
+                        "Label {:?} of object {:?} is not proposed, as there is no claim for it",
+                        peer, data
+                    );
+                }
+                (data, peers)
+            })
+            .collect();
+        let mut proposal = Proposal {
+            ymd,
+            photos,
+            claims,
+            metadata,
+            proposer: proposer.peer_id(),
+            signature: Vec::new(),
+        };
+        proposal.signature = proposer.sign(&proposal.digest());
+        proposal
+    }
+
+    /// Checks the signature of the proposer and that every location label is claimed by the labeled peer.
+    pub fn verify(&self) -> Result<(), DistStoreError> {
+        if !verify_signature(&self.proposer, &self.digest(), &self.signature) {
+            return Err(DistStoreError::InvalidProposalSignature {
+                proposer: self.proposer.clone(),
+            });
+        }
+        for (data, peers) in &self.photos {
+            for peer in peers {
+                match find_claim(&self.claims, data, peer) {
+                    Some(claim) if claim.verify() => {}
+                    _ => {
+                        return Err(DistStoreError::UnsignedLocationClaim {
+                            data: data.clone(),
+                            peer: peer.clone(),
+                        })
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash of the proposal content, that is signed by the proposer.
+    fn digest(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(PROPOSAL_DOMAIN);
+        hasher.update(self.ymd.to_be_bytes());
+        for (data, peers) in &self.photos {
+            update_with_bytes(&mut hasher, data);
+            hasher.update((peers.len() as u32).to_be_bytes());
+            for peer in peers {
+                update_with_bytes(&mut hasher, peer);
+            }
+        }
+        for claim in &self.claims {
+            update_with_bytes(&mut hasher, &claim.data);
+            update_with_bytes(&mut hasher, &claim.peer);
+            update_with_bytes(&mut hasher, &claim.signature);
+        }
+        for (data, record) in &self.metadata {
+            update_with_bytes(&mut hasher, data);
+            update_with_bytes(&mut hasher, &record.to_bytes());
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
+/// State of an album, merged by one node into another, signed by the proposing node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlbumProposal {
+    pub album: Album,
+    pub proposer: Peer,
+    pub signature: Vec<u8>,
+}
+
+impl AlbumProposal {
+    /// Creates a proposal of the album state signed by given node.
+    pub fn new(proposer: &NodeIdentity, album: Album) -> Self {
+        let mut proposal = AlbumProposal {
+            album,
+            proposer: proposer.peer_id(),
+            signature: Vec::new(),
+        };
+        proposal.signature = proposer.sign(&proposal.digest());
+        proposal
+    }
+
+    /// Checks the signature of the proposer.
+    pub fn verify(&self) -> Result<(), DistStoreError> {
+        if !verify_signature(&self.proposer, &self.digest(), &self.signature) {
+            return Err(DistStoreError::InvalidProposalSignature {
+                proposer: self.proposer.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Hash of the album state, that is signed by the proposer.
+    fn digest(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(ALBUM_PROPOSAL_DOMAIN);
+        update_with_bytes(&mut hasher, self.album.name.as_bytes());
+        for (data, tag, removed) in &self.album.entries {
+            update_with_bytes(&mut hasher, data);
+            update_with_bytes(&mut hasher, tag);
+            hasher.update([*removed as u8]);
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
+fn find_claim<'a>(
+    claims: &'a [LocationClaim],
+    data: &[u8],
+    peer: &[u8],
+) -> Option<&'a LocationClaim> {
+    claims.iter().find(|c| c.data == data && c.peer == peer)
+}
+
+/// Length prefix makes the concatenation of variable sized fields unambiguous
+fn update_with_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u32).to_be_bytes());
+    hasher.update(bytes);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_location_claim() {
+        let identity = NodeIdentity::generate();
+        let claim = identity.claim_location(&[1, 2, 3]);
+        assert!(claim.verify());
+
+        let forged = LocationClaim {
+            data: vec![1, 2, 4],
+            ..claim.clone()
+        };
+        assert!(!forged.verify());
+
+        let restored = NodeIdentity::from_secret_bytes(&identity.secret_bytes());
+        assert_eq!(identity.peer_id(), restored.peer_id());
+    }
+
+    #[test]
+    fn test_proposal_drops_unclaimed_labels() {
+        let proposer = NodeIdentity::generate();
+        let keeper = NodeIdentity::generate();
+        let photos = vec![(vec![0], vec![keeper.peer_id(), vec![7]])];
+        let claims = vec![keeper.claim_location(&[0])];
+
+        let proposal = Proposal::new(&proposer, 20200101, photos, claims);
+        assert_eq!(vec![(vec![0], vec![keeper.peer_id()])], proposal.photos);
+        assert!(proposal.verify().is_ok());
+
+        let mut tampered = proposal.clone();
+        tampered.ymd = 20200102;
+        assert!(matches!(
+            tampered.verify(),
+            Err(DistStoreError::InvalidProposalSignature { .. })
+        ));
+
+        // Properly signed by the proposer, but the label is not claimed
+        let mut unclaimed = proposal.clone();
+        unclaimed.photos[0].1.push(vec![7]);
+        unclaimed.signature = proposer.sign(&unclaimed.digest());
+        assert!(matches!(
+            unclaimed.verify(),
+            Err(DistStoreError::UnsignedLocationClaim { .. })
+        ));
+    }
+
+    #[test]
+    fn test_album_proposal() {
+        let proposer = NodeIdentity::generate();
+        let mut album = Album::new("trip");
+        album.add(&[1], vec![2]);
+        let proposal = AlbumProposal::new(&proposer, album);
+        assert!(proposal.verify().is_ok());
+
+        // A removal made by someone else
+        let mut tampered = proposal.clone();
+        tampered.album.entries[0].2 = true;
+        assert!(matches!(
+            tampered.verify(),
+            Err(DistStoreError::InvalidProposalSignature { .. })
+        ));
+    }
+}
@@ -1,3 +1,17 @@
+pub mod album;
 pub mod catalog;
+pub mod discovery;
+#[cfg(any(test, feature = "testing"))]
+pub mod faulty_peer;
+pub mod hashing;
+pub mod identity;
 pub mod local_storage;
+pub mod membership;
+pub mod metadata;
+pub mod metrics;
 pub mod opaque_date;
+pub mod replication;
+pub mod secure_channel;
+pub mod sync_options;
+pub mod trace_context;
+pub mod transport;
@@ -1,15 +1,29 @@
+use crate::album::{Album, AlbumEntry};
+use crate::catalog::DistStoreError;
+use crate::hashing::HashAlgorithm;
+use crate::identity::LocationClaim;
+use crate::metadata::ObjectMetadata;
 use crate::opaque_date::*;
+use crate::sync_options::{PeerOptions, SyncDirection, SyncScope};
 use anyhow::Result;
 use itertools::Itertools;
-use redb::{backends::InMemoryBackend, TableError};
-use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+use redb::{backends::InMemoryBackend, StorageBackend, TableError};
+use redb::{
+    Database, Durability, MultimapTableDefinition, MultimapTableHandle, ReadableMultimapTable,
+    ReadableTable, ReadableTableMetadata, Table, TableDefinition, TableHandle, WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 
 pub type Data = Vec<u8>;
 pub type Checksum = Vec<u8>;
 pub type Peer = Vec<u8>;
 
+/// Object ID along with the list of peers that keep the binary data of the object
+pub type Photo = (Data, Vec<Peer>);
+
 /// Following three tables do store checksums for the partitioned data we store.
 /// The data is partitioned by year, month and day, that's why this tree like storage of checksums
 /// significantly speeds up the search of differences between peers.
@@ -27,34 +41,370 @@ const TBL_CHECKSUM_MONTH: TableDefinition<YearMonth, Checksum> =
 const TBL_CHECKSUM_DAY: TableDefinition<YearMonthDay, Checksum> =
     TableDefinition::new("checksum_day");
 
-const TBL_DATA: TableDefinition<YearMonthDay, Vec<(Data, Vec<Peer>)>> =
-    TableDefinition::new("data_in_day");
+const TBL_DATA: TableDefinition<YearMonthDay, Vec<Photo>> = TableDefinition::new("data_in_day");
+
+/// Reverse index of location labels: peer -> (year/month/day, object ID).
+/// Allows answering "what does peer X hold?" without scanning all the days.
+const TBL_PEER_OBJECTS: MultimapTableDefinition<&[u8], (YearMonthDay, &[u8])> =
+    MultimapTableDefinition::new("peer_objects");
+
+/// Metadata records of the objects: (year/month/day, object ID) -> encoded [`ObjectMetadata`].
+/// Records are included in the checksum of the day.
+const TBL_OBJECT_METADATA: TableDefinition<(YearMonthDay, &[u8]), &[u8]> =
+    TableDefinition::new("object_metadata");
+
+/// Binary content of the objects kept on this host: object ID -> bytes.
+const TBL_BLOBS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("blobs");
+
+/// Signatures of location claims: (object ID, peer) -> signature of the peer.
+/// Kept to pass them along with location labels to other peers.
+const TBL_LOCATION_CLAIMS: TableDefinition<(&[u8], &[u8]), &[u8]> =
+    TableDefinition::new("location_claims");
+
+/// Allow-list of peers that can connect to this node: peer ID -> human readable name.
+const TBL_AUTHORIZED_PEERS: TableDefinition<&[u8], &str> = TableDefinition::new("authorized_peers");
+
+/// Peers that were caught sending wrong data: peer ID -> number of incidents.
+const TBL_MISBEHAVING_PEERS: TableDefinition<&[u8], u64> =
+    TableDefinition::new("misbehaving_peers");
+
+/// Replicated states of the albums: album name -> additions of objects, see [`Album`].
+const TBL_ALBUMS: TableDefinition<&str, Vec<AlbumEntry>> = TableDefinition::new("albums");
+
+/// Checksums of the albums, compared by peers separately from the checksum tree of the days.
+const TBL_CHECKSUM_ALBUM: TableDefinition<&str, Checksum> = TableDefinition::new("checksum_album");
+
+/// Node level settings, e.g. the node key.
+const TBL_META: TableDefinition<&str, &[u8]> = TableDefinition::new("meta");
+
+/// Key of the checksum tree hash algorithm in the settings
+const META_HASH_ALGORITHM: &str = "hash_algorithm";
+
+/// Key of the version of the day checksums format in the settings
+const META_CHECKSUM_FORMAT: &str = "checksum_format";
+/// Databases without the version have hashed only the object IDs of a day,
+/// since this version location labels and metadata records are included, see [`calc_photos_checksum`].
+const CHECKSUM_FORMAT: &[u8] = b"2";
+
+/// Append-only log of day mutations: sequence number -> (day, added object IDs and labels).
+const TBL_CHANGE_LOG: TableDefinition<u64, (YearMonthDay, Vec<Photo>)> =
+    TableDefinition::new("change_log");
+
+/// Sequence number of the last change, kept separately from the log, so that it survives truncation
+const META_LAST_CHANGE_SEQ: &str = "change_log_last_seq";
+/// Changes up to this sequence number (inclusive) are removed from the log
+const META_TRUNCATED_SEQ: &str = "change_log_truncated_seq";
+/// Number of days in the catalog, maintained along with the days, so that it is known without a scan
+const META_DAY_COUNT: &str = "day_count";
+/// Number of objects in all the days of the catalog
+const META_OBJECT_COUNT: &str = "object_count";
+
+/// Maximal number of records in a change feed, so that a peer far behind doesn't read the whole log at once.
+/// A longer feed is cut, and the rest of it is read page by page.
+pub const MAX_CHANGE_FEED_RECORDS: usize = 1000;
+
+/// Progress of the incremental synchronization with a peer:
+/// peer ID -> (last change of the peer pulled, last local change pushed to the peer).
+const TBL_SYNC_CURSORS: TableDefinition<&[u8], (u64, u64)> = TableDefinition::new("sync_cursors");
+
+/// Days transferred during a synchronization with a peer, that hasn't completed yet:
+/// (peer ID, direction, day) -> checksum of the source day at the time it was transferred.
+const TBL_SYNC_CHECKPOINTS: TableDefinition<(&[u8], u8, YearMonthDay), Checksum> =
+    TableDefinition::new("sync_checkpoints");
+
+/// Peers of the node, to restore them after a restart:
+/// peer ID -> (addresses, last successful sync time, year checksums of the peer seen at that sync,
+/// sync direction, sync scope as (kind, bounds or years)).
+const TBL_PEERS: TableDefinition<&[u8], PeerRow> = TableDefinition::new("peers");
+
+type PeerRow = (
+    Vec<String>,
+    Option<u64>,
+    Vec<(Year, Checksum)>,
+    u8,
+    (u8, Vec<u32>),
+);
+
+/// A peer of the node, as it is persisted in the catalog database.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerRecord {
+    /// ID of the peer, that is also its public key
+    pub id: Peer,
+    /// Addresses the peer can be reached at, the most recent first
+    pub addresses: Vec<String>,
+    /// Time of the last successful synchronization with the peer, seconds since the Unix epoch
+    pub last_sync: Option<u64>,
+    /// Year checksums of the peer, as they were at the last synchronization
+    pub years_checksums: Vec<(Year, Checksum)>,
+    pub direction: SyncDirection,
+    pub scope: SyncScope,
+}
+
+impl PeerRecord {
+    pub fn public_key(&self) -> &[u8] {
+        &self.id
+    }
+
+    /// Options the peer is synchronized with
+    pub fn options(&self) -> PeerOptions {
+        PeerOptions {
+            direction: self.direction,
+            scope: self.scope.clone(),
+        }
+    }
+
+    fn from_row(id: &[u8], row: PeerRow) -> Self {
+        let (addresses, last_sync, years_checksums, direction, scope) = row;
+        PeerRecord {
+            id: id.to_vec(),
+            addresses,
+            last_sync,
+            years_checksums,
+            direction: SyncDirection::from_u8(direction),
+            scope: SyncScope::from_row(scope),
+        }
+    }
+
+    fn to_row(&self) -> PeerRow {
+        (
+            self.addresses.clone(),
+            self.last_sync,
+            self.years_checksums.clone(),
+            self.direction.as_u8(),
+            self.scope.to_row(),
+        )
+    }
+}
+
+/// Direction in which days are transferred between the node and a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    /// From the peer to the node
+    Pull,
+    /// From the node to the peer
+    Push,
+}
+
+impl TransferDirection {
+    fn as_u8(self) -> u8 {
+        match self {
+            TransferDirection::Pull => 0,
+            TransferDirection::Push => 1,
+        }
+    }
+}
+
+/// A mutation of a day, recorded in the change log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    pub seq: u64,
+    pub ymd: YearMonthDay,
+    /// Object IDs that were added or received new labels, along with the new labels only
+    pub photos: Vec<Photo>,
+}
+
+/// What a merge of photos has changed in a day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DayChange {
+    pub ymd: YearMonthDay,
+    /// Object IDs that didn't exist in the day before
+    pub added: Vec<Data>,
+    /// Labels that didn't exist before, including the labels of the added objects
+    pub new_labels: Vec<(Data, Peer)>,
+    /// Objects whose metadata records have changed
+    pub metadata: Vec<Data>,
+}
+
+/// Changes made after given sequence number.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeFeed {
+    pub changes: Vec<ChangeRecord>,
+    /// Sequence number the next feed is read from: of the last returned change if there are more,
+    /// otherwise of the last change in the log
+    pub last_seq: u64,
+    /// Some of the requested changes were removed from the log, so the feed carries no changes,
+    /// and the checksum tree should be used instead
+    pub truncated: bool,
+    /// The feed is cut at [`MAX_CHANGE_FEED_RECORDS`], the next changes are read starting from `last_seq`
+    pub has_more: bool,
+}
+
+/// Object IDs of a day, along with the metadata records of the objects, see [`metadata`](crate::metadata).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DayData {
+    pub photos: Vec<Photo>,
+    /// Sorted by object ID, objects without a record are not listed
+    pub metadata: Vec<(Data, ObjectMetadata)>,
+}
 
 /// Represents a local object ids (hash) storage which is a local part of a distributed catalog system.
 /// The catalog is designed in the way that helps to identify disrepancies with other peers:
 /// * object ids are partitioned by year, month and day
-/// * each level of partitioning contains checksum (sha256 hash) of it's direct content
+/// * each level of partitioning contains checksum (hash of the configured algorithm, see [`HashAlgorithm`]) of it's direct content
 /// * for each object id we keep a list of labels - peers that keep the binary data identified by the id
+///
 /// When an id is changed for a day, the upgoing chain of checksums is recalculated
 pub struct LocalStorage {
     db: Database,
+    /// Algorithm of the checksum tree, a copy of the value stored in the DB
+    hash_algorithm: HashAlgorithm,
 }
 
 impl LocalStorage {
     /// Create a new instance of the local storage
+    /// The checksum tree uses the hash algorithm stored in the DB, SHA-256 for a new DB.
     /// Args:
     /// * path - local DB file
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_file(path, None)
+    }
+
+    /// Same as [`new`](Self::new), but the checksum tree uses the given hash algorithm.
+    /// An existing DB that uses another algorithm is switched to it, all the checksums are recalculated.
+    /// Object IDs are not affected.
+    /// Args:
+    /// * path - local DB file
+    /// * algorithm - hash algorithm of the checksums of days, months and years
+    pub fn new_with_hash_algorithm<P: AsRef<Path>>(
+        path: P,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self> {
+        Self::open_file(path, Some(algorithm))
+    }
+
+    fn open_file<P: AsRef<Path>>(path: P, algorithm: Option<HashAlgorithm>) -> Result<Self> {
         let db = Database::create(path)?;
         // redb will automatically detect and recover from crashes,
         // power loss, and other unclean shutdowns.
-        Ok(LocalStorage { db })
+        // A transaction is applied completely or not at all, so the checksum chain stays consistent,
+        // see tests/crash_consistency_test.rs
+        let storage = Self::from_db(db, algorithm)?;
+        storage.ensure_peer_index()?;
+        Ok(storage)
     }
 
     /// In memory version of storage, for testing purposes
     pub fn test_new() -> Result<Self> {
         let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
-        Ok(LocalStorage { db })
+        Self::from_db(db, None)
+    }
+
+    /// In memory version of storage with the given hash algorithm of the checksum tree
+    pub fn test_new_with_hash_algorithm(algorithm: HashAlgorithm) -> Result<Self> {
+        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        Self::from_db(db, Some(algorithm))
+    }
+
+    /// Creates the storage on top of a custom redb backend,
+    /// e.g. a backend that simulates I/O failures in tests.
+    /// Same as for a file, redb recovers the database if it was not closed properly.
+    /// Args:
+    /// * backend - redb storage backend
+    /// * algorithm - hash algorithm of the checksum tree, `None` to keep the stored one
+    pub fn with_backend<B: StorageBackend>(
+        backend: B,
+        algorithm: Option<HashAlgorithm>,
+    ) -> Result<Self> {
+        let db = Database::builder().create_with_backend(backend)?;
+        let storage = Self::from_db(db, algorithm)?;
+        storage.ensure_peer_index()?;
+        Ok(storage)
+    }
+
+    /// Databases without the stored hash algorithm were created with SHA-256.
+    fn from_db(db: Database, algorithm: Option<HashAlgorithm>) -> Result<Self> {
+        let mut storage = LocalStorage {
+            db,
+            hash_algorithm: HashAlgorithm::default(),
+        };
+        if let Some(name) = storage.get_meta(META_HASH_ALGORITHM)? {
+            storage.hash_algorithm = String::from_utf8(name)?.parse()?;
+        }
+        storage.ensure_checksum_format()?;
+        storage.ensure_counters()?;
+        if let Some(algorithm) = algorithm.filter(|a| *a != storage.hash_algorithm) {
+            storage.switch_hash_algorithm(algorithm)?;
+        }
+        Ok(storage)
+    }
+
+    /// Algorithm used for checksums of days, months and years.
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    /// Switches the checksum tree to another hash algorithm.
+    /// All the checksums are recalculated, and the algorithm is stored in the DB.
+    fn switch_hash_algorithm(&mut self, algorithm: HashAlgorithm) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            Self::recalculate_checksums(&write_txn, algorithm)?;
+            let mut table_meta = write_txn.open_table(TBL_META)?;
+            table_meta.insert(META_HASH_ALGORITHM, algorithm.as_str().as_bytes())?;
+        }
+        write_txn.commit()?;
+        self.hash_algorithm = algorithm;
+        Ok(())
+    }
+
+    /// Recalculates the checksum tree once, if the database has been created
+    /// with an older way of calculating checksums of the days.
+    /// Otherwise the checksums of the old days wouldn't match the ones calculated by up to date peers.
+    fn ensure_checksum_format(&self) -> Result<()> {
+        if self
+            .get_meta(META_CHECKSUM_FORMAT)?
+            .is_some_and(|v| v == CHECKSUM_FORMAT)
+        {
+            return Ok(());
+        }
+        let write_txn = self.db.begin_write()?;
+        {
+            Self::recalculate_checksums(&write_txn, self.hash_algorithm)?;
+            let mut table_meta = write_txn.open_table(TBL_META)?;
+            table_meta.insert(META_CHECKSUM_FORMAT, CHECKSUM_FORMAT)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Calculates all the checksums of days, months, years and albums from scratch.
+    fn recalculate_checksums(txn: &WriteTransaction, algorithm: HashAlgorithm) -> Result<()> {
+        let mut days = BTreeMap::new();
+        let table_days = txn.open_table(TBL_DATA)?;
+        let metadata = read_all_metadata(&txn.open_table(TBL_OBJECT_METADATA)?)?;
+        for row_res in table_days.iter()? {
+            let (ymd, photos) = row_res?;
+            let day_metadata = metadata.get(&ymd.value()).cloned().unwrap_or_default();
+            days.insert(
+                ymd.value(),
+                calc_photos_checksum(algorithm, &photos.value(), &day_metadata),
+            );
+        }
+        let months = hash_children(algorithm, &days, ymd_to_ym);
+        let years = hash_children(algorithm, &months, ym_to_y);
+
+        for (table, checksums) in [
+            (TBL_CHECKSUM_DAY, &days),
+            (TBL_CHECKSUM_MONTH, &months),
+            (TBL_CHECKSUM_YEAR, &years),
+        ] {
+            let mut table = txn.open_table(table)?;
+            for (partition, checksum) in checksums {
+                table.insert(partition, checksum)?;
+            }
+        }
+
+        let table_albums = txn.open_table(TBL_ALBUMS)?;
+        let mut table_checksum_album = txn.open_table(TBL_CHECKSUM_ALBUM)?;
+        for row_res in table_albums.iter()? {
+            let (name, entries) = row_res?;
+            let album = Album {
+                name: name.value().to_string(),
+                entries: entries.value(),
+            };
+            table_checksum_album.insert(name.value(), album.checksum(algorithm))?;
+        }
+        Ok(())
     }
 
     /// Returns list of all year (the object ids exist for) along with checksums for these years.
@@ -116,6 +466,18 @@ impl LocalStorage {
         Ok(result)
     }
 
+    /// Returns the checksum of the day, if the day has any object IDs.
+    pub fn get_day_checksum(&self, ymd: YearMonthDay) -> Result<Option<Checksum>> {
+        let read_txn = self.db.begin_read()?;
+        let table_checksum_day = match read_txn.open_table(TBL_CHECKSUM_DAY) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(None),
+            Err(other) => return Err(other.into()),
+        };
+        let result = table_checksum_day.get(ymd)?.map(|v| v.value());
+        Ok(result)
+    }
+
     /// Return a list of all available (object IDs exist for them) days in a given range defined by start day and end day.
     /// Here, a day is a full date (i.e. year-month-day) encoded into a single 32 unsigned int.
     /// E.g. 2015 May 3 is encoded as 20150503.
@@ -141,7 +503,32 @@ impl LocalStorage {
         Ok(result)
     }
 
-    pub fn get_photos(&self, ymd: YearMonthDay) -> Result<Option<Vec<(Data, Vec<Peer>)>>> {
+    /// Calculates a checksum of the days in a given range, from their days and checksums.
+    /// Used to compare the parts of years and months that are in a sync scope.
+    /// Args:
+    /// * ymd_from - start day of the interval
+    /// * ymd_to - end day of the interval, inclusive
+    pub fn get_range_checksum(
+        &self,
+        ymd_from: YearMonthDay,
+        ymd_to: YearMonthDay,
+    ) -> Result<Checksum> {
+        let mut hasher = self.hash_algorithm.hasher();
+        let read_txn = self.db.begin_read()?;
+        let table_checksum_day = match read_txn.open_table(TBL_CHECKSUM_DAY) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(hasher.finalize()),
+            Err(other) => return Err(other.into()),
+        };
+        for e in table_checksum_day.range(ymd_from..=ymd_to)? {
+            let (ymd, checksum) = e?;
+            hasher.update(&ymd.value().to_be_bytes());
+            hasher.update(&checksum.value());
+        }
+        Ok(hasher.finalize())
+    }
+
+    pub fn get_photos(&self, ymd: YearMonthDay) -> Result<Option<Vec<Photo>>> {
         let read_txn = self.db.begin_read()?;
         let table_days = match read_txn.open_table(TBL_DATA) {
             Ok(table) => table,
@@ -152,23 +539,115 @@ impl LocalStorage {
         Ok(result)
     }
 
+    /// Returns object IDs of the day along with their metadata records, read in one transaction.
+    pub fn get_day(&self, ymd: YearMonthDay) -> Result<Option<DayData>> {
+        let read_txn = self.db.begin_read()?;
+        let table_days = match read_txn.open_table(TBL_DATA) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(None),
+            Err(other) => return Err(other.into()),
+        };
+        let Some(photos) = table_days.get(ymd)?.map(|v| v.value()) else {
+            return Ok(None);
+        };
+        let metadata = match read_txn.open_table(TBL_OBJECT_METADATA) {
+            Ok(table) => read_day_metadata(&table, ymd)?.into_iter().collect(),
+            Err(TableError::TableDoesNotExist(..)) => Vec::new(),
+            Err(other) => return Err(other.into()),
+        };
+        Ok(Some(DayData { photos, metadata }))
+    }
+
     /// Add list of object ids for given day.
     /// This function can be called when a local data is added and we need to add object IDs pointing to this data,
     /// or during the synchronization with other peers.
     /// Returns resulting hash of the directory
-    pub fn add_photos_to_day(
+    pub fn add_photos_to_day(&self, ymd: YearMonthDay, new_photos: &[Photo]) -> Result<Vec<u8>> {
+        self.add_photos_to_day_with_claims(ymd, new_photos, &[])
+    }
+
+    /// Same as [`add_photos_to_day`](Self::add_photos_to_day), but also stores signed location claims
+    /// for the labels, so that they can be passed to other peers.
+    /// The claims are expected to be verified by the caller.
+    pub fn add_photos_to_day_with_claims(
         &self,
         ymd: YearMonthDay,
-        new_photos: &[(Data, Vec<Peer>)],
+        new_photos: &[Photo],
+        claims: &[LocationClaim],
     ) -> Result<Vec<u8>> {
+        Ok(self.merge_photos(ymd, new_photos, claims, &[])?.0)
+    }
+
+    /// Merges photos and their metadata records into the day, returning the new checksum of the day
+    /// and what has actually changed, if anything.
+    /// Records of objects that are not in the day are dropped.
+    pub(crate) fn merge_photos(
+        &self,
+        ymd: YearMonthDay,
+        new_photos: &[Photo],
+        claims: &[LocationClaim],
+        metadata: &[(Data, ObjectMetadata)],
+    ) -> Result<(Checksum, Option<DayChange>)> {
         let write_txn = self.db.begin_write()?; // Only one write transaction can be openned at a time
+        let result = Self::merge_photos_in(
+            &write_txn,
+            self.hash_algorithm,
+            ymd,
+            new_photos,
+            claims,
+            metadata,
+        )?;
+        write_txn.commit()?;
+        Ok(result)
+    }
+
+    /// Same as [`merge_photos`](Self::merge_photos), but also stores the content of a photo kept on this host,
+    /// under its object ID, so that the photo is never labeled as kept without its content, or the other way around.
+    pub(crate) fn merge_photos_with_blob(
+        &self,
+        ymd: YearMonthDay,
+        bytes: &[u8],
+        new_photos: &[Photo],
+        claims: &[LocationClaim],
+        metadata: &[(Data, ObjectMetadata)],
+    ) -> Result<(Checksum, Option<DayChange>)> {
+        let id = object_id(bytes);
+        let write_txn = self.db.begin_write()?;
         let result = {
+            let mut table_blobs = write_txn.open_table(TBL_BLOBS)?;
+            table_blobs.insert(id.as_slice(), bytes)?;
+            let algorithm = self.hash_algorithm;
+            Self::merge_photos_in(&write_txn, algorithm, ymd, new_photos, claims, metadata)?
+        };
+        write_txn.commit()?;
+        Ok(result)
+    }
+
+    fn merge_photos_in(
+        write_txn: &WriteTransaction,
+        algorithm: HashAlgorithm,
+        ymd: YearMonthDay,
+        new_photos: &[Photo],
+        claims: &[LocationClaim],
+        metadata: &[(Data, ObjectMetadata)],
+    ) -> Result<(Checksum, Option<DayChange>)> {
+        let result = {
+            let mut table_claims = write_txn.open_table(TBL_LOCATION_CLAIMS)?;
+            for claim in claims {
+                table_claims.insert(
+                    (claim.data.as_slice(), claim.peer.as_slice()),
+                    claim.signature.as_slice(),
+                )?;
+            }
+
             let mut table_days = write_txn.open_table(TBL_DATA)?;
-            let mut photos = table_days
-                .get(ymd)?
-                .map(|v| v.value())
-                .unwrap_or(Vec::new());
+            let existing = table_days.get(ymd)?.map(|v| v.value());
+            let new_day = existing.is_none();
+            let mut photos = existing.unwrap_or(Vec::new());
 
+            // What is actually new, for the change log
+            let mut added: BTreeMap<Data, BTreeSet<Peer>> = BTreeMap::new();
+            let mut added_ids: BTreeSet<Data> = BTreeSet::new();
             for new_photo in new_photos {
                 // In case if there are a lot of photo, we can optimize this check using bloom folter
                 if let Some(element) = photos.iter_mut().find(|(d, _)| *d == new_photo.0) {
@@ -178,32 +657,808 @@ impl LocalStorage {
                         .filter(|&p| !element.1.contains(p))
                         .map(|e| e.to_owned())
                         .collect_vec();
+                    if !peers_to_add.is_empty() {
+                        added
+                            .entry(new_photo.0.clone())
+                            .or_default()
+                            .extend(peers_to_add.iter().cloned());
+                    }
                     element.1.extend(peers_to_add);
                 } else {
+                    added
+                        .entry(new_photo.0.clone())
+                        .or_default()
+                        .extend(new_photo.1.iter().cloned());
+                    added_ids.insert(new_photo.0.clone());
                     photos.push(new_photo.clone());
                 }
             }
+            let mut table_metadata = write_txn.open_table(TBL_OBJECT_METADATA)?;
+            let mut day_metadata = read_day_metadata(&table_metadata, ymd)?;
+            let mut updated_metadata = BTreeSet::new();
+            for (data, record) in metadata {
+                if !photos.iter().any(|(d, _)| d == data) {
+                    continue;
+                }
+                let mut merged = day_metadata.get(data).cloned().unwrap_or_default();
+                if merged.merge(record) {
+                    table_metadata.insert((ymd, data.as_slice()), merged.to_bytes().as_slice())?;
+                    day_metadata.insert(data.clone(), merged);
+                    updated_metadata.insert(data.clone());
+                }
+            }
+
+            let mut table_meta = write_txn.open_table(TBL_META)?;
+            if new_day {
+                add_to_counter(&mut table_meta, META_DAY_COUNT, 1)?;
+            }
+            add_to_counter(&mut table_meta, META_OBJECT_COUNT, added_ids.len() as u64)?;
+            drop(table_meta);
 
+            let change = if added.is_empty() && updated_metadata.is_empty() {
+                None
+            } else {
+                let change = DayChange {
+                    ymd,
+                    added: added_ids.into_iter().collect(),
+                    new_labels: added
+                        .iter()
+                        .flat_map(|(data, peers)| {
+                            peers.iter().map(|peer| (data.clone(), peer.clone()))
+                        })
+                        .collect(),
+                    metadata: updated_metadata.iter().cloned().collect(),
+                };
+                // Objects with only the metadata changed are logged without labels
+                for data in updated_metadata {
+                    added.entry(data).or_default();
+                }
+                let added = added
+                    .into_iter()
+                    .map(|(data, peers)| (data, peers.into_iter().collect_vec()))
+                    .collect_vec();
+                Self::append_change(write_txn, ymd, added)?;
+                Some(change)
+            };
+
+            // Labels are kept sorted, so that the day checksum doesn't depend on the order they came in
+            for (_, peers) in photos.iter_mut() {
+                peers.sort();
+                peers.dedup();
+            }
             photos.sort();
             table_days.insert(ymd, &photos)?;
 
-            let new_checksum = calc_photos_checksum(&photos);
-            Self::update_day_checksum(&write_txn, ymd, new_checksum.clone())?;
-            new_checksum
+            let mut table_peer_objects = write_txn.open_multimap_table(TBL_PEER_OBJECTS)?;
+            for (data, peers) in new_photos {
+                for peer in peers {
+                    table_peer_objects.insert(peer.as_slice(), (ymd, data.as_slice()))?;
+                }
+            }
+
+            let new_checksum = calc_photos_checksum(algorithm, &photos, &day_metadata);
+            Self::update_day_checksum(write_txn, algorithm, ymd, new_checksum.clone())?;
+            (new_checksum, change)
+        };
+        Ok(result)
+    }
+
+    /// Adds a record to the change log, within the transaction that changes the day.
+    fn append_change(txn: &WriteTransaction, ymd: YearMonthDay, photos: Vec<Photo>) -> Result<()> {
+        let mut table_meta = txn.open_table(TBL_META)?;
+        let seq = read_seq(&table_meta, META_LAST_CHANGE_SEQ)? + 1;
+        table_meta.insert(META_LAST_CHANGE_SEQ, seq.to_be_bytes().as_slice())?;
+        let mut table_log = txn.open_table(TBL_CHANGE_LOG)?;
+        table_log.insert(seq, (ymd, photos))?;
+        Ok(())
+    }
+
+    /// Returns changes of the days made after given sequence number, in order.
+    /// At most [`MAX_CHANGE_FEED_RECORDS`] changes are returned, the next ones can be read
+    /// starting from the `last_seq` of the feed.
+    /// Args:
+    /// * seq - sequence number of the last known change, 0 to get the whole log
+    pub fn changes_since(&self, seq: u64) -> Result<ChangeFeed> {
+        let read_txn = self.db.begin_read()?;
+        let (table_meta, table_log) = match (
+            read_txn.open_table(TBL_META),
+            read_txn.open_table(TBL_CHANGE_LOG),
+        ) {
+            (Ok(meta), Ok(log)) => (meta, log),
+            (Err(TableError::TableDoesNotExist(..)), _)
+            | (_, Err(TableError::TableDoesNotExist(..))) => return Ok(ChangeFeed::default()),
+            (Err(other), _) | (_, Err(other)) => return Err(other.into()),
+        };
+        let head = read_seq(&table_meta, META_LAST_CHANGE_SEQ)?;
+        if seq < read_seq(&table_meta, META_TRUNCATED_SEQ)? {
+            return Ok(ChangeFeed {
+                changes: Vec::new(),
+                last_seq: head,
+                truncated: true,
+                has_more: false,
+            });
+        }
+        let mut changes = Vec::new();
+        let mut has_more = false;
+        for record in table_log.range(seq.saturating_add(1)..)? {
+            if changes.len() == MAX_CHANGE_FEED_RECORDS {
+                has_more = true;
+                break;
+            }
+            let (seq, change) = record?;
+            let (ymd, photos) = change.value();
+            changes.push(ChangeRecord {
+                seq: seq.value(),
+                ymd,
+                photos,
+            });
+        }
+        let last_seq = match changes.last() {
+            Some(last) if has_more => last.seq,
+            _ => head,
+        };
+        Ok(ChangeFeed {
+            changes,
+            last_seq,
+            truncated: false,
+            has_more,
+        })
+    }
+
+    /// Removes old records from the change log.
+    /// Peers that haven't received the removed changes fall back to the checksum tree.
+    /// Args:
+    /// * up_to - sequence number of the last record to remove
+    pub fn truncate_change_log(&self, up_to: u64) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_meta = write_txn.open_table(TBL_META)?;
+            let up_to = up_to.min(read_seq(&table_meta, META_LAST_CHANGE_SEQ)?);
+            if up_to > read_seq(&table_meta, META_TRUNCATED_SEQ)? {
+                table_meta.insert(META_TRUNCATED_SEQ, up_to.to_be_bytes().as_slice())?;
+            }
+            let mut table_log = write_txn.open_table(TBL_CHANGE_LOG)?;
+            table_log.retain_in(..=up_to, |_, _| false)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns the progress of the incremental synchronization with the peer:
+    /// (last pulled change of the peer, last local change pushed to the peer).
+    pub fn get_sync_cursor(&self, peer: &[u8]) -> Result<(u64, u64)> {
+        let read_txn = self.db.begin_read()?;
+        let table_cursors = match read_txn.open_table(TBL_SYNC_CURSORS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok((0, 0)),
+            Err(other) => return Err(other.into()),
+        };
+        let result = table_cursors
+            .get(peer)?
+            .map(|v| v.value())
+            .unwrap_or((0, 0));
+        Ok(result)
+    }
+
+    pub fn set_sync_cursor(&self, peer: &[u8], pulled: u64, pushed: u64) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_cursors = write_txn.open_table(TBL_SYNC_CURSORS)?;
+            table_cursors.insert(peer, (pulled, pushed))?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns the checksum the source day had when it was transferred to or from the peer,
+    /// if the day has been transferred since the last completed synchronization with the peer.
+    pub fn get_sync_checkpoint(
+        &self,
+        peer: &[u8],
+        direction: TransferDirection,
+        ymd: YearMonthDay,
+    ) -> Result<Option<Checksum>> {
+        let read_txn = self.db.begin_read()?;
+        let table_checkpoints = match read_txn.open_table(TBL_SYNC_CHECKPOINTS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(None),
+            Err(other) => return Err(other.into()),
         };
+        let result = table_checkpoints
+            .get((peer, direction.as_u8(), ymd))?
+            .map(|v| v.value());
+        Ok(result)
+    }
+
+    /// Records that the day has been transferred to or from the peer.
+    /// Checkpoints are committed without waiting for the disk, as there is one per day of a sync:
+    /// they are persisted along with the next durable commit (e.g. the next merged day),
+    /// and losing the last ones in a crash only makes their days transferred again.
+    /// Args:
+    /// * peer - ID of the peer
+    /// * direction - direction of the transfer
+    /// * ymd - transferred day
+    /// * checksum - checksum of the day on the source side, the checkpoint is valid while it stays the same
+    pub fn set_sync_checkpoint(
+        &self,
+        peer: &[u8],
+        direction: TransferDirection,
+        ymd: YearMonthDay,
+        checksum: &[u8],
+    ) -> Result<()> {
+        let mut write_txn = self.db.begin_write()?;
+        write_txn.set_durability(Durability::None);
+        {
+            let mut table_checkpoints = write_txn.open_table(TBL_SYNC_CHECKPOINTS)?;
+            table_checkpoints.insert((peer, direction.as_u8(), ymd), checksum.to_vec())?;
+        }
         write_txn.commit()?;
+        Ok(())
+    }
 
+    /// Removes all checkpoints of the peer, once a synchronization with it has completed.
+    /// Same as the checkpoints themselves, the removal is not durable until the next durable commit.
+    pub fn clear_sync_checkpoints(&self, peer: &[u8]) -> Result<()> {
+        let mut write_txn = self.db.begin_write()?;
+        write_txn.set_durability(Durability::None);
+        {
+            let mut table_checkpoints = write_txn.open_table(TBL_SYNC_CHECKPOINTS)?;
+            table_checkpoints.retain_in((peer, 0, 0)..=(peer, u8::MAX, u32::MAX), |_, _| false)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns signed location claims for the labels of given day.
+    /// Labels that were added without a claim are omitted.
+    pub fn get_location_claims(&self, ymd: YearMonthDay) -> Result<Vec<LocationClaim>> {
+        let read_txn = self.db.begin_read()?;
+        let (table_days, table_claims) = match (
+            read_txn.open_table(TBL_DATA),
+            read_txn.open_table(TBL_LOCATION_CLAIMS),
+        ) {
+            (Ok(days), Ok(claims)) => (days, claims),
+            (Err(TableError::TableDoesNotExist(..)), _)
+            | (_, Err(TableError::TableDoesNotExist(..))) => return Ok(Vec::new()),
+            (Err(other), _) | (_, Err(other)) => return Err(other.into()),
+        };
+        let photos = table_days.get(ymd)?.map(|v| v.value()).unwrap_or_default();
+        let mut result = Vec::new();
+        for (data, peers) in photos {
+            for peer in peers {
+                if let Some(signature) = table_claims.get((data.as_slice(), peer.as_slice()))? {
+                    result.push(LocationClaim {
+                        data: data.clone(),
+                        peer,
+                        signature: signature.value().to_vec(),
+                    });
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns metadata records of the objects of the day, sorted by object ID.
+    /// Objects without a record are not listed.
+    pub fn get_metadata(&self, ymd: YearMonthDay) -> Result<Vec<(Data, ObjectMetadata)>> {
+        let read_txn = self.db.begin_read()?;
+        let table_metadata = match read_txn.open_table(TBL_OBJECT_METADATA) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(Vec::new()),
+            Err(other) => return Err(other.into()),
+        };
+        Ok(read_day_metadata(&table_metadata, ymd)?
+            .into_iter()
+            .collect())
+    }
+
+    /// Returns names of the albums along with their checksums, sorted by name.
+    pub fn get_albums_checksums(&self) -> Result<Vec<(String, Checksum)>> {
+        let read_txn = self.db.begin_read()?;
+        let table_checksum_album = match read_txn.open_table(TBL_CHECKSUM_ALBUM) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(Vec::new()),
+            Err(other) => return Err(other.into()),
+        };
+        let mut result = Vec::new();
+        for record in table_checksum_album.iter()? {
+            let (name, checksum) = record?;
+            result.push((name.value().to_string(), checksum.value()));
+        }
         Ok(result)
     }
 
+    pub fn get_album(&self, name: &str) -> Result<Option<Album>> {
+        let read_txn = self.db.begin_read()?;
+        let table_albums = match read_txn.open_table(TBL_ALBUMS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(None),
+            Err(other) => return Err(other.into()),
+        };
+        let result = table_albums.get(name)?.map(|v| Album {
+            name: name.to_string(),
+            entries: v.value(),
+        });
+        Ok(result)
+    }
+
+    /// Creates an empty album. Returns the checksum of the album.
+    /// Fails with [`DistStoreError::AlbumAlreadyExists`] if there is an album with this name already.
+    pub fn create_album(&self, name: &str) -> Result<Checksum> {
+        self.update_album(name, |album| match album {
+            Some(_) => Err(DistStoreError::AlbumAlreadyExists {
+                name: name.to_string(),
+            }
+            .into()),
+            None => Ok(Album::new(name)),
+        })
+    }
+
+    /// Adds objects to the album, each addition gets a new unique tag.
+    /// Objects that are already in the album are skipped.
+    /// Fails with [`DistStoreError::UnknownAlbum`] if there is no such album.
+    pub fn add_to_album(&self, name: &str, ids: &[Data]) -> Result<Checksum> {
+        self.update_album(name, |album| {
+            let mut album = album.ok_or_else(|| DistStoreError::UnknownAlbum {
+                name: name.to_string(),
+            })?;
+            for id in ids {
+                album.add(id, rand::random::<[u8; 16]>().to_vec());
+            }
+            Ok(album)
+        })
+    }
+
+    /// Removes objects from the album. Only the additions seen by this node are removed,
+    /// so an object concurrently added by another peer stays in the album.
+    /// Fails with [`DistStoreError::UnknownAlbum`] if there is no such album.
+    pub fn remove_from_album(&self, name: &str, ids: &[Data]) -> Result<Checksum> {
+        self.update_album(name, |album| {
+            let mut album = album.ok_or_else(|| DistStoreError::UnknownAlbum {
+                name: name.to_string(),
+            })?;
+            for id in ids {
+                album.remove(id);
+            }
+            Ok(album)
+        })
+    }
+
+    /// Merges a replica of the album received from a peer, creating the album if needed.
+    /// Returns the checksum of the merged album.
+    pub fn merge_album(&self, album: &Album) -> Result<Checksum> {
+        self.update_album(&album.name, |local| {
+            let mut local = local.unwrap_or_else(|| Album::new(album.name.as_str()));
+            local.merge(&album.entries);
+            Ok(local)
+        })
+    }
+
+    /// Updates the album and its checksum within one transaction.
+    fn update_album(
+        &self,
+        name: &str,
+        update: impl FnOnce(Option<Album>) -> Result<Album>,
+    ) -> Result<Checksum> {
+        let algorithm = self.hash_algorithm;
+        let write_txn = self.db.begin_write()?;
+        let checksum = {
+            let mut table_albums = write_txn.open_table(TBL_ALBUMS)?;
+            let album = table_albums.get(name)?.map(|v| Album {
+                name: name.to_string(),
+                entries: v.value(),
+            });
+            let album = update(album)?;
+            table_albums.insert(name, &album.entries)?;
+
+            let checksum = album.checksum(algorithm);
+            let mut table_checksum_album = write_txn.open_table(TBL_CHECKSUM_ALBUM)?;
+            table_checksum_album.insert(name, &checksum)?;
+            checksum
+        };
+        write_txn.commit()?;
+        Ok(checksum)
+    }
+
+    /// Adds a peer to the allow-list, so that it can connect to this node over the network.
+    /// Args:
+    /// * peer - ID (public key) of the peer
+    /// * name - human readable name of the peer
+    pub fn authorize_peer(&self, peer: &[u8], name: &str) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_authorized = write_txn.open_table(TBL_AUTHORIZED_PEERS)?;
+            table_authorized.insert(peer, name)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Removes a peer from the allow-list. Already established connections are not affected.
+    pub fn revoke_peer(&self, peer: &[u8]) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_authorized = write_txn.open_table(TBL_AUTHORIZED_PEERS)?;
+            table_authorized.remove(peer)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    pub fn is_peer_authorized(&self, peer: &[u8]) -> Result<bool> {
+        let read_txn = self.db.begin_read()?;
+        let table_authorized = match read_txn.open_table(TBL_AUTHORIZED_PEERS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(false),
+            Err(other) => return Err(other.into()),
+        };
+        Ok(table_authorized.get(peer)?.is_some())
+    }
+
+    /// Returns the allow-list as a list of (peer ID, name) pairs.
+    pub fn get_authorized_peers(&self) -> Result<Vec<(Peer, String)>> {
+        let read_txn = self.db.begin_read()?;
+        let table_authorized = match read_txn.open_table(TBL_AUTHORIZED_PEERS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(Vec::new()),
+            Err(other) => return Err(other.into()),
+        };
+        let mut result = Vec::new();
+        for record in table_authorized.iter()? {
+            let (peer, name) = record?;
+            result.push((peer.value().to_vec(), name.value().to_string()));
+        }
+        Ok(result)
+    }
+
+    /// Records a peer of the node, or adds an address to an already known one.
+    /// Args:
+    /// * peer - ID of the peer
+    /// * address - address the peer can be reached at, if known
+    /// * options - options the peer is synchronized with, `None` to keep the recorded ones
+    pub fn put_peer(
+        &self,
+        peer: &[u8],
+        address: Option<&str>,
+        options: Option<&PeerOptions>,
+    ) -> Result<()> {
+        self.update_peer(peer, |record| {
+            if let Some(address) = address {
+                record.addresses.retain(|a| a != address);
+                record.addresses.insert(0, address.to_string());
+            }
+            if let Some(options) = options {
+                record.direction = options.direction;
+                record.scope = options.scope.clone();
+            }
+        })
+    }
+
+    /// Records a successful synchronization with the peer.
+    /// Args:
+    /// * peer - ID of the peer
+    /// * time - time of the synchronization, seconds since the Unix epoch
+    /// * years_checksums - year checksums of the peer seen during the synchronization
+    pub fn record_peer_sync(
+        &self,
+        peer: &[u8],
+        time: u64,
+        years_checksums: Vec<(Year, Checksum)>,
+    ) -> Result<()> {
+        self.update_peer(peer, |record| {
+            record.last_sync = Some(time);
+            record.years_checksums = years_checksums;
+        })
+    }
+
+    /// Changes the record of the peer within one transaction, a missing record is created.
+    fn update_peer(&self, peer: &[u8], update: impl FnOnce(&mut PeerRecord)) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_peers = write_txn.open_table(TBL_PEERS)?;
+            let mut record = match table_peers.get(peer)? {
+                Some(row) => PeerRecord::from_row(peer, row.value()),
+                None => PeerRecord {
+                    id: peer.to_vec(),
+                    ..PeerRecord::default()
+                },
+            };
+            update(&mut record);
+            table_peers.insert(peer, record.to_row())?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Forgets the peer, along with the progress of the synchronization with it.
+    /// Returns `false` if the peer is not recorded.
+    pub fn remove_peer(&self, peer: &[u8]) -> Result<bool> {
+        let write_txn = self.db.begin_write()?;
+        let removed = {
+            let mut table_peers = write_txn.open_table(TBL_PEERS)?;
+            let removed = table_peers.remove(peer)?.is_some();
+            let mut table_cursors = write_txn.open_table(TBL_SYNC_CURSORS)?;
+            table_cursors.remove(peer)?;
+            let mut table_checkpoints = write_txn.open_table(TBL_SYNC_CHECKPOINTS)?;
+            table_checkpoints.retain_in((peer, 0, 0)..=(peer, u8::MAX, u32::MAX), |_, _| false)?;
+            removed
+        };
+        write_txn.commit()?;
+        Ok(removed)
+    }
+
+    pub fn get_peer(&self, peer: &[u8]) -> Result<Option<PeerRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table_peers = match read_txn.open_table(TBL_PEERS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(None),
+            Err(other) => return Err(other.into()),
+        };
+        let result = table_peers
+            .get(peer)?
+            .map(|v| PeerRecord::from_row(peer, v.value()));
+        Ok(result)
+    }
+
+    /// Returns all the recorded peers, ordered by ID.
+    pub fn get_peers(&self) -> Result<Vec<PeerRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table_peers = match read_txn.open_table(TBL_PEERS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(Vec::new()),
+            Err(other) => return Err(other.into()),
+        };
+        let mut result = Vec::new();
+        for record in table_peers.iter()? {
+            let (peer, row) = record?;
+            result.push(PeerRecord::from_row(peer.value(), row.value()));
+        }
+        Ok(result)
+    }
+
+    /// Records an incident of a peer sending wrong data.
+    pub fn record_misbehavior(&self, peer: &[u8]) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_misbehaving = write_txn.open_table(TBL_MISBEHAVING_PEERS)?;
+            let count = table_misbehaving.get(peer)?.map(|v| v.value()).unwrap_or(0);
+            table_misbehaving.insert(peer, count + 1)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns peers caught sending wrong data, along with the number of incidents.
+    pub fn get_misbehaving_peers(&self) -> Result<Vec<(Peer, u64)>> {
+        let read_txn = self.db.begin_read()?;
+        let table_misbehaving = match read_txn.open_table(TBL_MISBEHAVING_PEERS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(Vec::new()),
+            Err(other) => return Err(other.into()),
+        };
+        let mut result = Vec::new();
+        for record in table_misbehaving.iter()? {
+            let (peer, count) = record?;
+            result.push((peer.value().to_vec(), count.value()));
+        }
+        Ok(result)
+    }
+
+    /// Returns a node level setting.
+    pub(crate) fn get_meta(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let read_txn = self.db.begin_read()?;
+        let table_meta = match read_txn.open_table(TBL_META) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(None),
+            Err(other) => return Err(other.into()),
+        };
+        let result = table_meta.get(key)?.map(|v| v.value().to_vec());
+        Ok(result)
+    }
+
+    /// Stores a node level setting.
+    pub(crate) fn put_meta(&self, key: &str, value: &[u8]) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_meta = write_txn.open_table(TBL_META)?;
+            table_meta.insert(key, value)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns all objects that are labeled as kept by given peer, ordered by day.
+    /// Args:
+    /// * peer - ID of the peer
+    pub fn get_peer_objects(&self, peer: &[u8]) -> Result<Vec<(YearMonthDay, Data)>> {
+        let read_txn = self.db.begin_read()?;
+        let table_peer_objects = match read_txn.open_multimap_table(TBL_PEER_OBJECTS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(Vec::new()),
+            Err(other) => return Err(other.into()),
+        };
+        let mut result = Vec::new();
+        for value_res in table_peer_objects.get(peer)? {
+            let value = value_res?;
+            let (ymd, data) = value.value();
+            result.push((ymd, data.to_vec()));
+        }
+        Ok(result)
+    }
+
+    /// Returns number of objects kept by given peer, grouped by year.
+    /// Years the peer has no objects for are not included.
+    /// Args:
+    /// * peer - ID of the peer
+    pub fn count_peer_objects_per_year(&self, peer: &[u8]) -> Result<Vec<(Year, u64)>> {
+        let read_txn = self.db.begin_read()?;
+        let table_peer_objects = match read_txn.open_multimap_table(TBL_PEER_OBJECTS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(Vec::new()),
+            Err(other) => return Err(other.into()),
+        };
+        let mut result: Vec<(Year, u64)> = Vec::new();
+        for value_res in table_peer_objects.get(peer)? {
+            let y = ym_to_y(ymd_to_ym(value_res?.value().0));
+            // Values are sorted by day, so same years always go one after another
+            match result.last_mut() {
+                Some((last_y, count)) if *last_y == y => *count += 1,
+                _ => result.push((y, 1)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns number of days that have objects, and the total number of objects in them.
+    /// The numbers are maintained along with the days, so the catalog is not scanned.
+    pub fn count_days_and_objects(&self) -> Result<(u64, u64)> {
+        let read_txn = self.db.begin_read()?;
+        let table_meta = match read_txn.open_table(TBL_META) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok((0, 0)),
+            Err(other) => return Err(other.into()),
+        };
+        Ok((
+            read_seq(&table_meta, META_DAY_COUNT)?,
+            read_seq(&table_meta, META_OBJECT_COUNT)?,
+        ))
+    }
+
+    /// Counts the days and objects once, if the database has been created before the counters were maintained.
+    fn ensure_counters(&self) -> Result<()> {
+        if self.get_meta(META_OBJECT_COUNT)?.is_some() {
+            return Ok(());
+        }
+        let write_txn = self.db.begin_write()?;
+        {
+            let table_days = write_txn.open_table(TBL_DATA)?;
+            let mut objects = 0;
+            for entry in table_days.iter()? {
+                objects += entry?.1.value().len() as u64;
+            }
+            let mut table_meta = write_txn.open_table(TBL_META)?;
+            add_to_counter(&mut table_meta, META_DAY_COUNT, table_days.len()?)?;
+            add_to_counter(&mut table_meta, META_OBJECT_COUNT, objects)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns objects for which given peer is the only known keeper,
+    /// i.e. the list of peers for the object consists of a single entry.
+    /// These objects are lost if the peer is decommissioned without copying them elsewhere.
+    /// Args:
+    /// * peer - ID of the peer
+    pub fn get_objects_only_on_peer(&self, peer: &[u8]) -> Result<Vec<(YearMonthDay, Data)>> {
+        let read_txn = self.db.begin_read()?;
+        let (table_peer_objects, table_days) = match (
+            read_txn.open_multimap_table(TBL_PEER_OBJECTS),
+            read_txn.open_table(TBL_DATA),
+        ) {
+            (Ok(index), Ok(days)) => (index, days),
+            (Err(TableError::TableDoesNotExist(..)), _)
+            | (_, Err(TableError::TableDoesNotExist(..))) => return Ok(Vec::new()),
+            (Err(other), _) | (_, Err(other)) => return Err(other.into()),
+        };
+
+        let mut result = Vec::new();
+        let mut day_photos: Option<(YearMonthDay, Vec<Photo>)> = None;
+        for value_res in table_peer_objects.get(peer)? {
+            let value = value_res?;
+            let (ymd, data) = value.value();
+            // Objects of the same day go one after another, so we read each day only once
+            if day_photos.as_ref().map(|(d, _)| *d) != Some(ymd) {
+                let photos = table_days.get(ymd)?.map(|v| v.value()).unwrap_or_default();
+                day_photos = Some((ymd, photos));
+            }
+            let is_single_copy = day_photos.as_ref().is_some_and(|(_, photos)| {
+                photos
+                    .iter()
+                    .any(|(d, peers)| d.as_slice() == data && peers.len() == 1)
+            });
+            if is_single_copy {
+                result.push((ymd, data.to_vec()));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Stores binary content of an object kept on this host.
+    /// The content is rejected if it doesn't hash to the object ID.
+    /// Args:
+    /// * id - object ID
+    /// * bytes - content of the object
+    pub fn put_blob(&self, id: &[u8], bytes: &[u8]) -> Result<()> {
+        let actual = object_id(bytes);
+        if actual != id {
+            return Err(DistStoreError::ObjectIdMismatch {
+                claimed: id.to_vec(),
+                actual,
+            }
+            .into());
+        }
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table_blobs = write_txn.open_table(TBL_BLOBS)?;
+            table_blobs.insert(id, bytes)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Returns binary content of an object, if it is kept on this host.
+    pub fn get_blob(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        let read_txn = self.db.begin_read()?;
+        let table_blobs = match read_txn.open_table(TBL_BLOBS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(..)) => return Ok(None),
+            Err(other) => return Err(other.into()),
+        };
+        let result = table_blobs.get(id)?.map(|v| v.value().to_vec());
+        Ok(result)
+    }
+
+    /// Builds the peer -> objects index from the data table,
+    /// if the database has been created before the index was introduced.
+    fn ensure_peer_index(&self) -> Result<()> {
+        let read_txn = self.db.begin_read()?;
+        let has_index = read_txn
+            .list_multimap_tables()?
+            .any(|t| t.name() == TBL_PEER_OBJECTS.name());
+        let has_data = read_txn.list_tables()?.any(|t| t.name() == TBL_DATA.name());
+        drop(read_txn);
+        if has_index || !has_data {
+            return Ok(());
+        }
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let table_days = write_txn.open_table(TBL_DATA)?;
+            let mut table_peer_objects = write_txn.open_multimap_table(TBL_PEER_OBJECTS)?;
+            for row_res in table_days.iter()? {
+                let (ymd, photos) = row_res?;
+                let ymd = ymd.value();
+                for (data, peers) in photos.value() {
+                    for peer in peers {
+                        table_peer_objects.insert(peer.as_slice(), (ymd, data.as_slice()))?;
+                    }
+                }
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
     /// Updates the while upgoing chain of checksums: year/month/day -> year/month -> year
     /// Should be called after the list of object IDs has been chenged for a day.
     /// Args:
     /// * txn - redb transaction
+    /// * algorithm - hash algorithm of the checksum tree
     /// * day - that received an update of object IDs list
     /// * day_checksum - new checksum of the given day
     fn update_day_checksum(
         txn: &WriteTransaction,
+        algorithm: HashAlgorithm,
         ymd: YearMonthDay,
         day_checksum: Vec<u8>,
     ) -> Result<()> {
@@ -213,24 +1468,120 @@ impl LocalStorage {
 
         // Updating YearMonth checksum table
         let ym = ymd_to_ym(ymd);
-        let mut days_checksum_hasher = Sha256::new();
+        let mut days_checksum_hasher = algorithm.hasher();
         for day_checksum_res in table_checksum_day.range(ymd_range_for_ym(ym))? {
             // They are allways sorted
-            days_checksum_hasher.update(day_checksum_res?.1.value());
+            days_checksum_hasher.update(&day_checksum_res?.1.value());
         }
 
         let mut table_checksum_month = txn.open_table(TBL_CHECKSUM_MONTH)?;
-        table_checksum_month.insert(ym, days_checksum_hasher.finalize().to_vec())?;
+        table_checksum_month.insert(ym, days_checksum_hasher.finalize())?;
 
         // Updating Year checksum table
         let y = ym_to_y(ym);
-        let mut months_checksum_hasher = Sha256::new();
+        let mut months_checksum_hasher = algorithm.hasher();
         for month_checksum_res in table_checksum_month.range(ym_range_for_y(y))? {
-            months_checksum_hasher.update(month_checksum_res?.1.value());
+            months_checksum_hasher.update(&month_checksum_res?.1.value());
         }
         let mut table_checksum_year = txn.open_table(TBL_CHECKSUM_YEAR)?;
-        table_checksum_year.insert(y, months_checksum_hasher.finalize().to_vec())?;
+        table_checksum_year.insert(y, months_checksum_hasher.finalize())?;
+
+        Ok(())
+    }
+
+    /// Recalculates the checksum chain from the day data and compares it with the stored checksums.
+    /// Also checks that the peer index matches the location labels.
+    /// Returns [`DistStoreError::InconsistentStorage`] for the first discrepancy found.
+    pub fn verify_consistency(&self) -> Result<()> {
+        let read_txn = self.db.begin_read()?;
+        let inconsistent = |reason: String| DistStoreError::InconsistentStorage { reason };
+
+        // The checksums are verified with the algorithm stored along with them
+        let algorithm = match read_txn.open_table(TBL_META) {
+            Ok(table) => match table.get(META_HASH_ALGORITHM)? {
+                Some(name) => std::str::from_utf8(name.value())?.parse()?,
+                None => HashAlgorithm::default(),
+            },
+            Err(TableError::TableDoesNotExist(..)) => HashAlgorithm::default(),
+            Err(other) => return Err(other.into()),
+        };
+        if algorithm != self.hash_algorithm {
+            return Err(inconsistent(format!(
+                "hash algorithm {} is stored, {} is in use",
+                algorithm, self.hash_algorithm
+            ))
+            .into());
+        }
+
+        let metadata = match read_txn.open_table(TBL_OBJECT_METADATA) {
+            Ok(table) => read_all_metadata(&table)?,
+            Err(TableError::TableDoesNotExist(..)) => BTreeMap::new(),
+            Err(other) => return Err(other.into()),
+        };
+        let mut days = BTreeMap::new();
+        let mut labels = BTreeSet::new();
+        match read_txn.open_table(TBL_DATA) {
+            Ok(table_days) => {
+                for row_res in table_days.iter()? {
+                    let (ymd, photos) = row_res?;
+                    let (ymd, photos) = (ymd.value(), photos.value());
+                    for (data, peers) in &photos {
+                        for peer in peers {
+                            labels.insert((peer.clone(), ymd, data.clone()));
+                        }
+                    }
+                    let day_metadata = metadata.get(&ymd).cloned().unwrap_or_default();
+                    days.insert(ymd, calc_photos_checksum(algorithm, &photos, &day_metadata));
+                }
+            }
+            Err(TableError::TableDoesNotExist(..)) => {}
+            Err(other) => return Err(other.into()),
+        }
+        let months = hash_children(algorithm, &days, ymd_to_ym);
+        let years = hash_children(algorithm, &months, ym_to_y);
 
+        for (name, table, expected) in [
+            ("day", TBL_CHECKSUM_DAY, &days),
+            ("month", TBL_CHECKSUM_MONTH, &months),
+            ("year", TBL_CHECKSUM_YEAR, &years),
+        ] {
+            let stored = match read_txn.open_table(table) {
+                Ok(table) => table
+                    .iter()?
+                    .map_ok(|(k, v)| (k.value(), v.value()))
+                    .collect::<Result<BTreeMap<_, _>, _>>()?,
+                Err(TableError::TableDoesNotExist(..)) => BTreeMap::new(),
+                Err(other) => return Err(other.into()),
+            };
+            if let Some(partition) = stored
+                .keys()
+                .chain(expected.keys())
+                .find(|k| stored.get(k) != expected.get(k))
+            {
+                return Err(
+                    inconsistent(format!("wrong {} checksum for {}", name, partition)).into(),
+                );
+            }
+        }
+
+        let mut index = BTreeSet::new();
+        match read_txn.open_multimap_table(TBL_PEER_OBJECTS) {
+            Ok(table_peer_objects) => {
+                for row_res in table_peer_objects.iter()? {
+                    let (peer, values) = row_res?;
+                    for value_res in values {
+                        let value = value_res?;
+                        let (ymd, data) = value.value();
+                        index.insert((peer.value().to_vec(), ymd, data.to_vec()));
+                    }
+                }
+            }
+            Err(TableError::TableDoesNotExist(..)) => {}
+            Err(other) => return Err(other.into()),
+        }
+        if index != labels {
+            return Err(inconsistent("peer index doesn't match location labels".into()).into());
+        }
         Ok(())
     }
 
@@ -246,12 +1597,181 @@ impl LocalStorage {
     }
 }
 
+/// Metadata records of the day, by object ID.
+fn read_day_metadata<T: ReadableTable<(YearMonthDay, &'static [u8]), &'static [u8]>>(
+    table_metadata: &T,
+    ymd: YearMonthDay,
+) -> Result<BTreeMap<Data, ObjectMetadata>> {
+    let mut result = BTreeMap::new();
+    for record in table_metadata.range((ymd, [].as_slice())..(ymd + 1, [].as_slice()))? {
+        let (key, value) = record?;
+        result.insert(
+            key.value().1.to_vec(),
+            ObjectMetadata::from_bytes(value.value())?,
+        );
+    }
+    Ok(result)
+}
+
+/// Metadata records of all the days: year/month/day -> object ID -> record.
+fn read_all_metadata<T: ReadableTable<(YearMonthDay, &'static [u8]), &'static [u8]>>(
+    table_metadata: &T,
+) -> Result<BTreeMap<YearMonthDay, BTreeMap<Data, ObjectMetadata>>> {
+    let mut result: BTreeMap<YearMonthDay, BTreeMap<Data, ObjectMetadata>> = BTreeMap::new();
+    for record in table_metadata.iter()? {
+        let (key, value) = record?;
+        let (ymd, data) = key.value();
+        result
+            .entry(ymd)
+            .or_default()
+            .insert(data.to_vec(), ObjectMetadata::from_bytes(value.value())?);
+    }
+    Ok(result)
+}
+
+/// Reads a sequence number stored in the settings, 0 if absent.
+fn read_seq<T: ReadableTable<&'static str, &'static [u8]>>(
+    table_meta: &T,
+    key: &str,
+) -> Result<u64> {
+    match table_meta.get(key)? {
+        Some(value) => Ok(u64::from_be_bytes(value.value().try_into()?)),
+        None => Ok(0),
+    }
+}
+
+/// Adds to a number stored in the settings, a missing number counts as 0.
+fn add_to_counter(table_meta: &mut Table<&str, &[u8]>, key: &str, delta: u64) -> Result<()> {
+    let value = read_seq(table_meta, key)? + delta;
+    table_meta.insert(key, value.to_be_bytes().as_slice())?;
+    Ok(())
+}
+
+/// Object ID of a photo is the SHA256 hash of its content.
+pub fn object_id(bytes: &[u8]) -> Data {
+    Sha256::digest(bytes).to_vec()
+}
+
+/// Calculates checksums of the upper level of partitioning, same way as `update_day_checksum` does.
+/// Args:
+/// * algorithm - hash algorithm of the checksum tree
+/// * checksums - sorted checksums of the lower level
+/// * to_parent - maps a partition to its parent, e.g. a day to its month
+fn hash_children(
+    algorithm: HashAlgorithm,
+    checksums: &BTreeMap<u32, Checksum>,
+    to_parent: fn(u32) -> u32,
+) -> BTreeMap<u32, Checksum> {
+    let mut result = BTreeMap::new();
+    for (parent, children) in &checksums.iter().group_by(|(k, _)| to_parent(**k)) {
+        let mut hasher = algorithm.hasher();
+        for (_, checksum) in children {
+            hasher.update(checksum);
+        }
+        result.insert(parent, hasher.finalize());
+    }
+    result
+}
+
 /// Calculates checksum for given list of object IDs
 /// that suppose to be taken from a day.
-fn calc_photos_checksum(photos: &[(Data, Vec<Peer>)]) -> Checksum {
-    let mut hasher = Sha256::new();
-    for photo in photos {
-        hasher.update(&photo.0);
+/// Location labels are included, so that peers also exchange information about who keeps which object.
+/// Metadata records are included only for the objects that have them,
+/// so checksums of days without metadata are the same as before the records were introduced.
+/// Each ID is prefixed with its length to avoid ambiguity between IDs and labels.
+fn calc_photos_checksum(
+    algorithm: HashAlgorithm,
+    photos: &[Photo],
+    metadata: &BTreeMap<Data, ObjectMetadata>,
+) -> Checksum {
+    let mut hasher = algorithm.hasher();
+    for (data, peers) in photos {
+        hasher.update(&(data.len() as u32).to_be_bytes());
+        hasher.update(data);
+        hasher.update(&(peers.len() as u32).to_be_bytes());
+        for peer in peers {
+            hasher.update(&(peer.len() as u32).to_be_bytes());
+            hasher.update(peer);
+        }
+        if let Some(record) = metadata.get(data) {
+            let bytes = record.to_bytes();
+            hasher.update(&(bytes.len() as u32).to_be_bytes());
+            hasher.update(&bytes);
+        }
+    }
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify_consistency_detects_wrong_checksum() -> Result<()> {
+        let storage = LocalStorage::test_new()?;
+        storage.add_photos_to_day(20200101, &[(vec![1], vec![vec![2]])])?;
+        storage.add_photos_to_day(20200202, &[(vec![3], vec![vec![2]])])?;
+        storage.verify_consistency()?;
+
+        let write_txn = storage.db.begin_write()?;
+        write_txn
+            .open_table(TBL_CHECKSUM_MONTH)?
+            .insert(202002, vec![0u8; 32])?;
+        write_txn.commit()?;
+
+        let res = storage.verify_consistency();
+        assert!(matches!(
+            res.unwrap_err().downcast::<DistStoreError>(),
+            Ok(DistStoreError::InconsistentStorage { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_old_checksum_format_is_recalculated() -> Result<()> {
+        let storage = LocalStorage::test_new()?;
+        storage.add_photos_to_day(20200101, &[(vec![1], vec![vec![2]])])?;
+        let checksums = storage.get_years_checksums()?;
+
+        // Checksums of a database created before the labels were hashed
+        let write_txn = storage.db.begin_write()?;
+        {
+            write_txn
+                .open_table(TBL_CHECKSUM_DAY)?
+                .insert(20200101, Sha256::digest([1]).to_vec())?;
+            write_txn
+                .open_table(TBL_META)?
+                .remove(META_CHECKSUM_FORMAT)?;
+        }
+        write_txn.commit()?;
+        assert!(storage.verify_consistency().is_err());
+
+        storage.ensure_checksum_format()?;
+        storage.verify_consistency()?;
+        assert_eq!(checksums, storage.get_years_checksums()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_counters_of_old_database_are_initialized() -> Result<()> {
+        let storage = LocalStorage::test_new()?;
+        storage.add_photos_to_day(20200101, &[(vec![1], vec![]), (vec![2], vec![])])?;
+        storage.add_photos_to_day(20200101, &[(vec![1], vec![vec![3]])])?;
+        storage.add_photos_to_day(20200202, &[(vec![1], vec![])])?;
+        assert_eq!((2, 3), storage.count_days_and_objects()?);
+
+        // A database created before the counters were maintained
+        let write_txn = storage.db.begin_write()?;
+        {
+            let mut table_meta = write_txn.open_table(TBL_META)?;
+            table_meta.remove(META_DAY_COUNT)?;
+            table_meta.remove(META_OBJECT_COUNT)?;
+        }
+        write_txn.commit()?;
+        assert_eq!((0, 0), storage.count_days_and_objects()?);
+
+        storage.ensure_counters()?;
+        assert_eq!((2, 3), storage.count_days_and_objects()?);
+        Ok(())
     }
-    hasher.finalize().to_vec()
 }
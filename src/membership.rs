@@ -0,0 +1,262 @@
+//! Gossip based membership of the catalog nodes, inspired by SWIM.
+//!
+//! Each node keeps a view of all the members it knows about: their state, incarnation and address.
+//! Periodically (see [`CatalogNode::gossip_round`](crate::catalog::CatalogNode::gossip_round))
+//! a node exchanges its view with a random live peer, so that members spread over the cluster
+//! without adding every node to every other node manually.
+//! New members are connected with a [`PeerConnector`](PeerConnector).
+//!
+//! Failures are detected by the gossip itself: if a peer doesn't answer,
+//! a few other peers are asked to probe it. If nobody can reach it, the peer becomes suspected,
+//! and if the suspicion is not refuted within a timeout, the peer is considered dead
+//! and is not synchronized with anymore.
+//! A suspected member refutes the suspicion by gossiping itself as alive with a bigger incarnation.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::RemotePeer;
+use crate::local_storage::Peer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    /// Didn't answer recently, but can still refute it
+    Suspect,
+    Dead,
+}
+
+/// What a node knows about a member, as it is gossiped to other nodes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberUpdate {
+    pub peer: Peer,
+    /// Address of the member, in the form the connector understands (e.g. "host:port")
+    pub address: Option<String>,
+    /// Increased only by the member itself, to refute suspicions
+    pub incarnation: u64,
+    pub state: MemberState,
+}
+
+impl MemberUpdate {
+    /// Whether this update is newer than what is known.
+    /// Bigger incarnation wins, on the same incarnation the worse state wins.
+    fn overrides(&self, known: &MemberUpdate) -> bool {
+        (self.incarnation, self.state) > (known.incarnation, known.state)
+    }
+}
+
+/// Creates connections to peers: members learned from the gossip,
+/// or peers restored from the catalog database after a restart.
+pub trait PeerConnector: Send + Sync {
+    /// Args:
+    /// * peer - ID of the peer
+    /// * addresses - known addresses of the peer, the most recent first (may be empty)
+    fn connect(&self, peer: &[u8], addresses: &[String]) -> Result<Arc<dyn RemotePeer>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct MembershipConfig {
+    /// Number of random live peers synchronized with on each round, `None` for all of them
+    pub sync_fanout: Option<usize>,
+    /// Number of peers asked to probe a peer that doesn't answer
+    pub indirect_probes: usize,
+    /// Time after which a suspected member is considered dead
+    pub suspect_timeout: Duration,
+}
+
+impl Default for MembershipConfig {
+    fn default() -> Self {
+        MembershipConfig {
+            sync_fanout: None,
+            indirect_probes: 2,
+            suspect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Member {
+    update: MemberUpdate,
+    /// When the member has got its current state, for the suspicion timeout
+    since: Instant,
+}
+
+/// Membership view of a node.
+pub struct Membership {
+    self_id: Peer,
+    members: Mutex<BTreeMap<Peer, Member>>,
+    config: RwLock<MembershipConfig>,
+}
+
+impl Membership {
+    pub(crate) fn new(self_id: Peer) -> Self {
+        let mut members = BTreeMap::new();
+        members.insert(
+            self_id.clone(),
+            Member {
+                update: MemberUpdate {
+                    peer: self_id.clone(),
+                    address: None,
+                    incarnation: 0,
+                    state: MemberState::Alive,
+                },
+                since: Instant::now(),
+            },
+        );
+        Membership {
+            self_id,
+            members: Mutex::new(members),
+            config: RwLock::new(MembershipConfig::default()),
+        }
+    }
+
+    pub fn config(&self) -> MembershipConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: MembershipConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Sets the address other members should use to connect to this node.
+    /// The incarnation is increased, so that the new address overrides the old one.
+    pub fn set_address(&self, address: String) {
+        let mut members = self.members.lock().unwrap();
+        let own = &mut members.get_mut(&self.self_id).unwrap().update;
+        own.address = Some(address);
+        own.incarnation += 1;
+    }
+
+    /// Address of this node, set with [`set_address`](Self::set_address).
+    pub fn address(&self) -> Option<String> {
+        let members = self.members.lock().unwrap();
+        members[&self.self_id].update.address.clone()
+    }
+
+    /// All known members including this node, as they are gossiped.
+    pub fn members(&self) -> Vec<MemberUpdate> {
+        let members = self.members.lock().unwrap();
+        members.values().map(|m| m.update.clone()).collect()
+    }
+
+    pub fn state_of(&self, peer: &[u8]) -> Option<MemberState> {
+        let members = self.members.lock().unwrap();
+        members.get(peer).map(|m| m.update.state)
+    }
+
+    /// Peers that are not known to be dead are worth talking to.
+    pub fn is_live(&self, peer: &[u8]) -> bool {
+        self.state_of(peer) != Some(MemberState::Dead)
+    }
+
+    /// Registers a peer added directly to the node, unless it is already known.
+    pub(crate) fn add_known(&self, peer: Peer) {
+        let mut members = self.members.lock().unwrap();
+        members.entry(peer.clone()).or_insert_with(|| Member {
+            update: MemberUpdate {
+                peer,
+                address: None,
+                incarnation: 0,
+                state: MemberState::Alive,
+            },
+            since: Instant::now(),
+        });
+    }
+
+    /// Merges the view of another member into this one.
+    pub(crate) fn merge(&self, updates: Vec<MemberUpdate>) {
+        let mut members = self.members.lock().unwrap();
+        for update in updates {
+            if update.peer == self.self_id {
+                // Others think we are not alive, refuting it
+                let own = &mut members.get_mut(&self.self_id).unwrap().update;
+                if update.state != MemberState::Alive && update.incarnation >= own.incarnation {
+                    own.incarnation = update.incarnation + 1;
+                }
+                continue;
+            }
+            match members.get_mut(&update.peer) {
+                Some(known) if !update.overrides(&known.update) => {}
+                Some(known) => {
+                    let address = update.address.clone().or(known.update.address.take());
+                    known.update = MemberUpdate { address, ..update };
+                    known.since = Instant::now();
+                }
+                None => {
+                    members.insert(
+                        update.peer.clone(),
+                        Member {
+                            update,
+                            since: Instant::now(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Marks the member as suspected, if it is considered alive.
+    pub(crate) fn suspect(&self, peer: &[u8]) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.get_mut(peer) {
+            if member.update.state == MemberState::Alive {
+                member.update.state = MemberState::Suspect;
+                member.since = Instant::now();
+            }
+        }
+    }
+
+    /// Declares dead the members that have been suspected for too long.
+    pub(crate) fn expire_suspects(&self) {
+        let timeout = self.config().suspect_timeout;
+        let mut members = self.members.lock().unwrap();
+        for member in members.values_mut() {
+            if member.update.state == MemberState::Suspect && member.since.elapsed() >= timeout {
+                member.update.state = MemberState::Dead;
+                member.since = Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn update(peer: u8, incarnation: u64, state: MemberState) -> MemberUpdate {
+        MemberUpdate {
+            peer: vec![peer],
+            address: None,
+            incarnation,
+            state,
+        }
+    }
+
+    #[test]
+    fn test_merge_precedence() {
+        let membership = Membership::new(vec![0]);
+        membership.merge(vec![update(1, 0, MemberState::Alive)]);
+        assert_eq!(Some(MemberState::Alive), membership.state_of(&[1]));
+
+        // Same incarnation: the worse state wins
+        membership.merge(vec![update(1, 0, MemberState::Suspect)]);
+        membership.merge(vec![update(1, 0, MemberState::Alive)]);
+        assert_eq!(Some(MemberState::Suspect), membership.state_of(&[1]));
+
+        // Bigger incarnation wins
+        membership.merge(vec![update(1, 1, MemberState::Alive)]);
+        assert_eq!(Some(MemberState::Alive), membership.state_of(&[1]));
+    }
+
+    #[test]
+    fn test_suspicion_is_refuted() {
+        let membership = Membership::new(vec![0]);
+        membership.merge(vec![update(0, 0, MemberState::Suspect)]);
+
+        let own = membership.members().remove(0);
+        assert_eq!(update(0, 1, MemberState::Alive), own);
+    }
+}
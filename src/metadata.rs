@@ -0,0 +1,96 @@
+//! Metadata records attached to objects: size, MIME type, original file name, dimensions, capture time,
+//! and any extra fields. Allows to list the photos of a day without fetching their content.
+//!
+//! Records are kept per day, next to the object IDs, and are exchanged along with them
+//! (see [`RemotePeer::get_data`](crate::catalog::RemotePeer::get_data) and [`Proposal`](crate::identity::Proposal)).
+//! Records of the same object coming from different peers are merged field by field:
+//! a known value wins over an unknown one, and of two conflicting values the greater one is kept.
+//! So merges don't depend on the order the records come in, and all peers end up with the same record.
+//!
+//! New kinds of metadata are added as extra fields, so that records stay readable by older nodes.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectMetadata {
+    /// Size of the content in bytes
+    pub size: Option<u64>,
+    pub mime_type: Option<String>,
+    pub file_name: Option<String>,
+    /// Width and height in pixels
+    pub dimensions: Option<(u32, u32)>,
+    /// Capture time, seconds since the Unix epoch
+    pub taken_at: Option<u64>,
+    pub extra: BTreeMap<String, String>,
+}
+
+impl ObjectMetadata {
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn with_mime_type<S: Into<String>>(mut self, mime_type: S) -> Self {
+        self.mime_type = Some(mime_type.into());
+        self
+    }
+
+    pub fn with_file_name<S: Into<String>>(mut self, file_name: S) -> Self {
+        self.file_name = Some(file_name.into());
+        self
+    }
+
+    pub fn with_dimensions(mut self, width: u32, height: u32) -> Self {
+        self.dimensions = Some((width, height));
+        self
+    }
+
+    pub fn with_taken_at(mut self, taken_at: u64) -> Self {
+        self.taken_at = Some(taken_at);
+        self
+    }
+
+    pub fn with_extra<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// Merges another record of the same object. Returns whether the record has changed.
+    pub fn merge(&mut self, other: &ObjectMetadata) -> bool {
+        let before = self.clone();
+        merge_field(&mut self.size, &other.size);
+        merge_field(&mut self.mime_type, &other.mime_type);
+        merge_field(&mut self.file_name, &other.file_name);
+        merge_field(&mut self.dimensions, &other.dimensions);
+        merge_field(&mut self.taken_at, &other.taken_at);
+        for (key, value) in &other.extra {
+            let known = self
+                .extra
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+            if value > known {
+                *known = value.clone();
+            }
+        }
+        *self != before
+    }
+
+    /// Encoding of the record, that is used to store it, and to calculate checksums and signatures.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        // Serialization of plain fields and maps never fails
+        bincode::serialize(self).expect("Object metadata is serializable")
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// `None` < `Some`, and the greater of two values wins
+fn merge_field<T: Ord + Clone>(field: &mut Option<T>, other: &Option<T>) {
+    if other > field {
+        *field = other.clone();
+    }
+}
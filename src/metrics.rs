@@ -0,0 +1,552 @@
+//! Metrics of the catalog node in the Prometheus text exposition format.
+//!
+//! [`Metrics`](Metrics) keeps counters of the synchronization and of the calls made to remote peers.
+//! Calls are counted by [`InstrumentedPeer`](InstrumentedPeer), that wraps peers during the sync and repair,
+//! and runs each call within a tracing span.
+//! Gauges (number of objects, days and peers) are read from the node when the metrics are rendered,
+//! see [`CatalogNode::render_metrics`](CatalogNode::render_metrics).
+//! [`MetricsServer`](MetricsServer) serves the rendered metrics over HTTP, to be scraped by Prometheus.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::debug;
+use serde::Serialize;
+use tracing::field;
+
+use crate::album::Album;
+use crate::catalog::{CatalogNode, RemotePeer};
+use crate::hashing::HashAlgorithm;
+use crate::identity::{AlbumProposal, LocationClaim, Proposal};
+use crate::local_storage::{ChangeFeed, Checksum, DayData};
+use crate::membership::MemberUpdate;
+use crate::opaque_date::{Year, YearMonth, YearMonthDay};
+use crate::trace_context::{format_peer, Partition};
+
+/// Upper bounds (in seconds) of the sync duration histogram buckets
+const SYNC_DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+/// Methods of the `RemotePeer` trait, calls are counted per method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PeerMethod {
+    NotifyAddedBy,
+    GetHashAlgorithm,
+    GetYearsChecksums,
+    GetMonthsChecksum,
+    GetDaysChecksum,
+    GetExistingDaysInRange,
+    GetRangeChecksum,
+    GetData,
+    GetLocationClaims,
+    Propose,
+    GetBlob,
+    ChangesSince,
+    Gossip,
+    Probe,
+    GetAlbumsChecksums,
+    GetAlbum,
+    MergeAlbum,
+}
+
+impl PeerMethod {
+    /// Name of the method, as in the `RemotePeer` trait
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PeerMethod::NotifyAddedBy => "notify_added_by",
+            PeerMethod::GetHashAlgorithm => "hash_algorithm",
+            PeerMethod::GetYearsChecksums => "get_years_checksums",
+            PeerMethod::GetMonthsChecksum => "get_months_checksum",
+            PeerMethod::GetDaysChecksum => "get_days_checksum",
+            PeerMethod::GetExistingDaysInRange => "get_existing_days_in_range",
+            PeerMethod::GetRangeChecksum => "get_range_checksum",
+            PeerMethod::GetData => "get_data",
+            PeerMethod::GetLocationClaims => "get_location_claims",
+            PeerMethod::Propose => "propose",
+            PeerMethod::GetBlob => "get_blob",
+            PeerMethod::ChangesSince => "changes_since",
+            PeerMethod::Gossip => "gossip",
+            PeerMethod::Probe => "probe",
+            PeerMethod::GetAlbumsChecksums => "get_albums_checksums",
+            PeerMethod::GetAlbum => "get_album",
+            PeerMethod::MergeAlbum => "merge_album",
+        }
+    }
+}
+
+/// Counters of a catalog node. All of them start from zero when the node is created.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sync_rounds: AtomicU64,
+    failed_peer_syncs: AtomicU64,
+    sync_duration: Histogram,
+    /// Number of calls and number of failed calls per method
+    peer_calls: Mutex<BTreeMap<PeerMethod, (u64, u64)>>,
+    /// Whether the sizes of the transferred values are counted, see [`enable_byte_counters`](Self::enable_byte_counters)
+    byte_counters: AtomicBool,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+/// Distribution of observed values
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Upper bound of each bucket, along with the number of values that fall into it or any lower bucket
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Not cumulative counts, the last one is for values above all bounds
+    buckets: [AtomicU64; SYNC_DURATION_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = SYNC_DURATION_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(SYNC_DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = SYNC_DURATION_BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+impl Metrics {
+    /// Number of synchronization rounds, i.e. calls of `sync_with_peers` that weren't rejected
+    pub fn sync_rounds(&self) -> u64 {
+        self.sync_rounds.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the synchronization with a single peer has failed
+    pub fn failed_peer_syncs(&self) -> u64 {
+        self.failed_peer_syncs.load(Ordering::Relaxed)
+    }
+
+    /// Durations of the synchronization rounds, in seconds
+    pub fn sync_duration(&self) -> HistogramSnapshot {
+        self.sync_duration.snapshot()
+    }
+
+    /// Returns number of calls of the method made to remote peers, and how many of them have failed.
+    pub fn peer_calls(&self, method: PeerMethod) -> (u64, u64) {
+        self.peer_calls
+            .lock()
+            .unwrap()
+            .get(&method)
+            .copied()
+            .unwrap_or((0, 0))
+    }
+
+    /// Starts counting the bytes sent to and received from remote peers.
+    /// The counters are off by default, as the size of each value is calculated by serializing it.
+    /// [`MetricsServer`] turns them on when started.
+    pub fn enable_byte_counters(&self) {
+        self.byte_counters.store(true, Ordering::Relaxed);
+    }
+
+    /// Size of the arguments sent to remote peers, in the wire encoding
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Size of the results received from remote peers, in the wire encoding
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_sync(&self, duration: Duration, failed_peers: usize) {
+        self.sync_rounds.fetch_add(1, Ordering::Relaxed);
+        self.failed_peer_syncs
+            .fetch_add(failed_peers as u64, Ordering::Relaxed);
+        self.sync_duration.observe(duration);
+    }
+
+    fn record_call(&self, method: PeerMethod, failed: bool) {
+        let mut calls = self.peer_calls.lock().unwrap();
+        let (count, errors) = calls.entry(method).or_default();
+        *count += 1;
+        if failed {
+            *errors += 1;
+        }
+    }
+
+    /// Appends the counters to the text exposition.
+    pub(crate) fn render(&self, out: &mut String) {
+        write_metric(
+            out,
+            "photo_sync_sync_rounds_total",
+            "counter",
+            "Number of synchronization rounds",
+            self.sync_rounds(),
+        );
+        write_metric(
+            out,
+            "photo_sync_failed_peer_syncs_total",
+            "counter",
+            "Number of failed synchronizations with a single peer",
+            self.failed_peer_syncs(),
+        );
+
+        let name = "photo_sync_sync_duration_seconds";
+        let duration = self.sync_duration();
+        let _ = writeln!(out, "# HELP {} Duration of synchronization rounds", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in &duration.buckets {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, duration.count);
+        let _ = writeln!(out, "{}_sum {}", name, duration.sum);
+        let _ = writeln!(out, "{}_count {}", name, duration.count);
+
+        let calls = self.peer_calls.lock().unwrap().clone();
+        for (name, help, errors) in [
+            (
+                "photo_sync_peer_calls_total",
+                "Number of calls made to remote peers",
+                false,
+            ),
+            (
+                "photo_sync_peer_call_errors_total",
+                "Number of failed calls made to remote peers",
+                true,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (method, (count, failed)) in &calls {
+                let value = if errors { failed } else { count };
+                let _ = writeln!(out, "{}{{method=\"{}\"}} {}", name, method.as_str(), value);
+            }
+        }
+
+        write_metric(
+            out,
+            "photo_sync_bytes_sent_total",
+            "counter",
+            "Bytes sent to remote peers",
+            self.bytes_sent(),
+        );
+        write_metric(
+            out,
+            "photo_sync_bytes_received_total",
+            "counter",
+            "Bytes received from remote peers",
+            self.bytes_received(),
+        );
+    }
+}
+
+/// Appends a metric without labels to the text exposition.
+pub(crate) fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Decorator of a remote peer, that counts calls, errors and transferred bytes,
+/// and wraps each call into a `remote_call` span.
+pub(crate) struct InstrumentedPeer<'a> {
+    inner: &'a dyn RemotePeer,
+    metrics: &'a Metrics,
+}
+
+impl<'a> InstrumentedPeer<'a> {
+    pub(crate) fn new(inner: &'a dyn RemotePeer, metrics: &'a Metrics) -> Self {
+        InstrumentedPeer { inner, metrics }
+    }
+
+    /// Performs the call and records it.
+    /// Args:
+    /// * method - called method
+    /// * partition - catalog partition the call is about, if any
+    /// * sent - arguments of the call, to count their size
+    /// * call - the call itself
+    fn call<T: Serialize, S: Serialize + ?Sized>(
+        &self,
+        method: PeerMethod,
+        partition: Option<Partition>,
+        sent: &S,
+        call: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let span = tracing::debug_span!(
+            "remote_call",
+            method = method.as_str(),
+            peer = %format_peer(&self.inner.id()),
+            year = field::Empty,
+            month = field::Empty,
+            day = field::Empty,
+            error = field::Empty,
+        );
+        if let Some(partition) = partition {
+            partition.record(&span);
+        }
+        let _enter = span.enter();
+
+        // Sizes are calculated by serializing the values, so only if someone reads them
+        let count_bytes = self.metrics.byte_counters.load(Ordering::Relaxed);
+        if count_bytes {
+            self.metrics
+                .bytes_sent
+                .fetch_add(wire_size(sent), Ordering::Relaxed);
+        }
+        let result = call();
+        self.metrics.record_call(method, result.is_err());
+        if let Err(e) = &result {
+            span.record("error", field::display(e));
+        }
+        match &result {
+            Ok(value) if count_bytes => {
+                self.metrics
+                    .bytes_received
+                    .fetch_add(wire_size(value), Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        result
+    }
+}
+
+fn wire_size<T: Serialize + ?Sized>(value: &T) -> u64 {
+    bincode::serialized_size(value).unwrap_or(0)
+}
+
+impl RemotePeer for InstrumentedPeer<'_> {
+    fn id(&self) -> Vec<u8> {
+        self.inner.id()
+    }
+
+    fn notify_added_by(&self, peer: Arc<dyn RemotePeer>) {
+        // Doesn't return anything, so it is never counted as failed
+        let _ = self.call(PeerMethod::NotifyAddedBy, None, &(), || {
+            self.inner.notify_added_by(peer);
+            Ok(())
+        });
+    }
+
+    fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        self.call(PeerMethod::GetHashAlgorithm, None, &(), || {
+            self.inner.hash_algorithm()
+        })
+    }
+
+    fn get_years_checksums(&self) -> Result<Vec<(Year, Checksum)>> {
+        self.call(PeerMethod::GetYearsChecksums, None, &(), || {
+            self.inner.get_years_checksums()
+        })
+    }
+
+    fn get_months_checksum(&self, y: Year) -> Result<Vec<(YearMonth, Checksum)>> {
+        self.call(
+            PeerMethod::GetMonthsChecksum,
+            Some(Partition::Year(y)),
+            &y,
+            || self.inner.get_months_checksum(y),
+        )
+    }
+
+    fn get_days_checksum(&self, ym: YearMonth) -> Result<Vec<(YearMonthDay, Checksum)>> {
+        self.call(
+            PeerMethod::GetDaysChecksum,
+            Some(Partition::Month(ym)),
+            &ym,
+            || self.inner.get_days_checksum(ym),
+        )
+    }
+
+    fn get_existing_days_in_range(
+        &self,
+        ymd_from: YearMonthDay,
+        ymd_to: YearMonthDay,
+    ) -> Result<Vec<YearMonthDay>> {
+        let sent = (ymd_from, ymd_to);
+        self.call(PeerMethod::GetExistingDaysInRange, None, &sent, || {
+            self.inner.get_existing_days_in_range(ymd_from, ymd_to)
+        })
+    }
+
+    fn get_range_checksum(&self, ymd_from: YearMonthDay, ymd_to: YearMonthDay) -> Result<Checksum> {
+        let sent = (ymd_from, ymd_to);
+        self.call(PeerMethod::GetRangeChecksum, None, &sent, || {
+            self.inner.get_range_checksum(ymd_from, ymd_to)
+        })
+    }
+
+    fn get_data(&self, ymd: u32) -> Result<Option<DayData>> {
+        self.call(PeerMethod::GetData, Some(Partition::Day(ymd)), &ymd, || {
+            self.inner.get_data(ymd)
+        })
+    }
+
+    fn get_location_claims(&self, ymd: YearMonthDay) -> Result<Vec<LocationClaim>> {
+        self.call(
+            PeerMethod::GetLocationClaims,
+            Some(Partition::Day(ymd)),
+            &ymd,
+            || self.inner.get_location_claims(ymd),
+        )
+    }
+
+    fn propose(&self, proposal: &Proposal) -> Result<Vec<u8>> {
+        self.call(
+            PeerMethod::Propose,
+            Some(Partition::Day(proposal.ymd)),
+            proposal,
+            || self.inner.propose(proposal),
+        )
+    }
+
+    fn get_blob(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.call(PeerMethod::GetBlob, None, id, || self.inner.get_blob(id))
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<ChangeFeed> {
+        self.call(PeerMethod::ChangesSince, None, &seq, || {
+            self.inner.changes_since(seq)
+        })
+    }
+
+    fn gossip(&self, members: Vec<MemberUpdate>) -> Result<Vec<MemberUpdate>> {
+        self.call(PeerMethod::Gossip, None, &members, || {
+            self.inner.gossip(members.clone())
+        })
+    }
+
+    fn probe(&self, target: &[u8]) -> Result<bool> {
+        self.call(PeerMethod::Probe, None, target, || self.inner.probe(target))
+    }
+
+    fn get_albums_checksums(&self) -> Result<Vec<(String, Checksum)>> {
+        self.call(PeerMethod::GetAlbumsChecksums, None, &(), || {
+            self.inner.get_albums_checksums()
+        })
+    }
+
+    fn get_album(&self, name: &str) -> Result<Option<Album>> {
+        self.call(PeerMethod::GetAlbum, None, name, || {
+            self.inner.get_album(name)
+        })
+    }
+
+    fn merge_album(&self, proposal: &AlbumProposal) -> Result<Checksum> {
+        self.call(PeerMethod::MergeAlbum, None, proposal, || {
+            self.inner.merge_album(proposal)
+        })
+    }
+}
+
+/// Serves metrics of the node over HTTP, at `GET /metrics`.
+/// Each connection is served in the accepting thread, as scrapes are rare and cheap.
+/// The server stops when dropped.
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    accept_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Starts listening on given address, e.g. "127.0.0.1:9090".
+    pub fn start<A: ToSocketAddrs>(node: Arc<CatalogNode>, addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        node.metrics().enable_byte_counters();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let accept_thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = serve_scrape(&node, stream) {
+                                debug!("Failed to serve metrics of {}: {}", node.name(), e);
+                            }
+                        }
+                        Err(e) => debug!("Failed to accept connection: {}", e),
+                    }
+                }
+            })
+        };
+        Ok(MetricsServer {
+            local_addr,
+            stopped,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accept loop, so that it can notice the stop flag
+        let _ = TcpStream::connect(self.local_addr);
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve_scrape(node: &CatalogNode, mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are not needed, but have to be read before responding
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => match node.render_metrics() {
+            Ok(body) => ("200 OK", body),
+            Err(e) => ("500 Internal Server Error", e.to_string()),
+        },
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
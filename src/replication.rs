@@ -0,0 +1,136 @@
+//! Replication policy says how many peers should keep a copy of each object.
+//! The catalog already knows which peers keep which objects (location labels),
+//! so comparing the number of labels with the target factor gives a list
+//! of under-replicated objects, and the list of peers that should fetch them.
+
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+use crate::local_storage::{Data, Peer};
+use crate::opaque_date::{Year, YearMonthDay};
+
+/// Target replication factor: the number of peers that should keep each object.
+/// The factor can be overridden for specific years,
+/// e.g. to keep more copies of old photos that are hard to recover.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationPolicy {
+    default_factor: usize,
+    per_year: BTreeMap<Year, usize>,
+}
+
+impl ReplicationPolicy {
+    /// Creates a policy with the same target factor for all years.
+    pub fn new(default_factor: usize) -> Self {
+        ReplicationPolicy {
+            default_factor,
+            per_year: BTreeMap::new(),
+        }
+    }
+
+    /// Overrides the target factor for given year.
+    pub fn with_year(mut self, year: Year, factor: usize) -> Self {
+        self.per_year.insert(year, factor);
+        self
+    }
+
+    /// Returns the target replication factor for objects of given year.
+    pub fn target_for(&self, year: Year) -> usize {
+        self.per_year
+            .get(&year)
+            .copied()
+            .unwrap_or(self.default_factor)
+    }
+}
+
+/// A single copy is what we have without any replication,
+/// so by default nothing is reported as under-replicated.
+impl Default for ReplicationPolicy {
+    fn default() -> Self {
+        ReplicationPolicy::new(1)
+    }
+}
+
+/// An object that is kept by less peers than the policy requires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnderReplicated {
+    pub ymd: YearMonthDay,
+    pub data: Data,
+    /// Peers that currently keep the object
+    pub holders: Vec<Peer>,
+    /// Desired number of holders
+    pub target: usize,
+    /// Reachable peers that don't keep the object yet and should fetch it.
+    /// May contain less peers than needed, if there are not enough reachable peers.
+    pub suggested: Vec<Peer>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicationReport {
+    pub under_replicated: Vec<UnderReplicated>,
+}
+
+impl ReplicationReport {
+    /// Returns under-replicated objects that given peer is suggested to fetch.
+    pub fn suggested_for<'a>(
+        &'a self,
+        peer: &'a [u8],
+    ) -> impl Iterator<Item = &'a UnderReplicated> {
+        self.under_replicated
+            .iter()
+            .filter(move |e| e.suggested.iter().any(|p| p == peer))
+    }
+}
+
+/// Picks peers that should fetch the object to reach the target factor.
+/// Candidates are ordered by rendezvous hash of (object ID, peer ID),
+/// so all nodes that see the same set of peers come to the same suggestion,
+/// and the load is spread evenly among peers.
+/// Args:
+/// * data - object ID
+/// * holders - peers that already keep the object
+/// * candidates - reachable peers
+/// * target - desired number of holders
+pub(crate) fn suggest_fetchers(
+    data: &[u8],
+    holders: &[Peer],
+    candidates: &[Peer],
+    target: usize,
+) -> Vec<Peer> {
+    let needed = target.saturating_sub(holders.len());
+    candidates
+        .iter()
+        .filter(|c| !holders.contains(c))
+        .unique()
+        .sorted_by_cached_key(|c| {
+            let mut hasher = Sha256::new();
+            hasher.update(data);
+            hasher.update(c);
+            hasher.finalize().to_vec()
+        })
+        .take(needed)
+        .cloned()
+        .collect_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_suggest_fetchers() {
+        let candidates = vec![vec![1], vec![2], vec![3]];
+
+        let res = suggest_fetchers(&[0], &[vec![1]], &candidates, 3);
+        assert_eq!(2, res.len());
+        assert!(!res.contains(&vec![1]));
+
+        let res = suggest_fetchers(&[0], &[vec![1], vec![2]], &candidates, 2);
+        assert!(res.is_empty());
+
+        // Not enough candidates to reach the target
+        let res = suggest_fetchers(&[0], &[], &candidates, 5);
+        assert_eq!(3, res.len());
+    }
+}
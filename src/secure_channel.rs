@@ -0,0 +1,241 @@
+//! Mutually authenticated and encrypted channel between two nodes.
+//!
+//! The channel is established with the Noise XX handshake.
+//! Noise static keys are X25519 keys, that are generated for each connection
+//! (we don't reuse the Ed25519 node key for key exchange),
+//! so each side binds its Noise static key to its node identity
+//! by signing the static key with the node key and passing the signature in the handshake payload.
+//! Only after both sides have verified the identity of each other, and the server
+//! has checked the client against its allow-list, the channel can be used for the requests.
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, TransportState};
+
+use crate::catalog::DistStoreError;
+use crate::identity::{verify_signature, NodeIdentity};
+use crate::local_storage::Peer;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"photo-sync/secure-channel/v1";
+const STATIC_KEY_DOMAIN: &[u8] = b"photo-sync/noise-static-key/v1";
+
+/// Max size of a single Noise message
+const MAX_NOISE_MESSAGE: usize = 65535;
+/// Noise messages carry 16 bytes of authentication tag
+const MAX_PLAINTEXT_CHUNK: usize = MAX_NOISE_MESSAGE - 16;
+/// Protects from allocating memory for a huge message announced by a broken peer
+const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Handshake payload, that proves the sender owns the node key.
+#[derive(Serialize, Deserialize)]
+struct IdentityProof {
+    peer_id: Peer,
+    /// Signature of the Noise static key of the sender, made with the node key
+    signature: Vec<u8>,
+}
+
+/// Result of the client authorization, sent by the server as the first encrypted message.
+#[derive(Serialize, Deserialize)]
+enum Admission {
+    Accepted,
+    Rejected(String),
+}
+
+/// Encrypted channel over a byte stream (e.g. TCP stream).
+/// Messages of any size (up to a limit) are split into Noise messages,
+/// each of them is prefixed with 2 bytes of length.
+pub struct SecureChannel<S: Read + Write> {
+    stream: S,
+    transport: TransportState,
+    remote_id: Peer,
+}
+
+impl<S: Read + Write> SecureChannel<S> {
+    /// Performs the client side of the handshake.
+    /// Args:
+    /// * stream - connection to the server
+    /// * identity - node key of the client
+    /// * expected_peer - ID of the server we are connecting to, the connection fails if the server has another ID
+    pub fn connect(mut stream: S, identity: &NodeIdentity, expected_peer: &[u8]) -> Result<Self> {
+        let builder = Builder::new(NOISE_PARAMS.parse()?);
+        let static_key = builder.generate_keypair()?;
+        let mut handshake = builder
+            .local_private_key(&static_key.private)
+            .prologue(PROLOGUE)
+            .build_initiator()?;
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+
+        // -> e
+        let len = handshake.write_message(&[], &mut buf)?;
+        write_frame(&mut stream, &buf[..len])?;
+
+        // <- e, ee, s, es
+        let len = handshake.read_message(&read_frame(&mut stream)?, &mut payload)?;
+        let remote_id = verify_proof(&handshake, &payload[..len])?;
+        if remote_id != expected_peer {
+            return Err(DistStoreError::UnexpectedPeerIdentity {
+                expected: expected_peer.to_vec(),
+                actual: remote_id,
+            }
+            .into());
+        }
+
+        // -> s, se
+        let proof = make_proof(identity, &static_key.public)?;
+        let len = handshake.write_message(&proof, &mut buf)?;
+        write_frame(&mut stream, &buf[..len])?;
+
+        let mut channel = SecureChannel {
+            stream,
+            transport: handshake.into_transport_mode()?,
+            remote_id,
+        };
+        match bincode::deserialize(&channel.recv()?)? {
+            Admission::Accepted => Ok(channel),
+            Admission::Rejected(reason) => bail!("Connection rejected by peer: {}", reason),
+        }
+    }
+
+    /// Performs the server side of the handshake.
+    /// Args:
+    /// * stream - connection from the client
+    /// * identity - node key of the server
+    /// * is_authorized - checks if the client with given ID is allowed to connect
+    pub fn accept<F: Fn(&[u8]) -> bool>(
+        mut stream: S,
+        identity: &NodeIdentity,
+        is_authorized: F,
+    ) -> Result<Self> {
+        let builder = Builder::new(NOISE_PARAMS.parse()?);
+        let static_key = builder.generate_keypair()?;
+        let mut handshake = builder
+            .local_private_key(&static_key.private)
+            .prologue(PROLOGUE)
+            .build_responder()?;
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+
+        // -> e
+        handshake.read_message(&read_frame(&mut stream)?, &mut payload)?;
+
+        // <- e, ee, s, es
+        let proof = make_proof(identity, &static_key.public)?;
+        let len = handshake.write_message(&proof, &mut buf)?;
+        write_frame(&mut stream, &buf[..len])?;
+
+        // -> s, se
+        let len = handshake.read_message(&read_frame(&mut stream)?, &mut payload)?;
+        let remote_id = verify_proof(&handshake, &payload[..len])?;
+
+        let mut channel = SecureChannel {
+            stream,
+            transport: handshake.into_transport_mode()?,
+            remote_id,
+        };
+        if !is_authorized(&channel.remote_id) {
+            let rejection = Admission::Rejected("peer is not authorized".into());
+            channel.send(&bincode::serialize(&rejection)?)?;
+            return Err(DistStoreError::UnauthorizedPeer {
+                peer: channel.remote_id,
+            }
+            .into());
+        }
+        channel.send(&bincode::serialize(&Admission::Accepted)?)?;
+        Ok(channel)
+    }
+
+    /// ID of the node on the other side, verified during the handshake.
+    pub fn remote_id(&self) -> &[u8] {
+        &self.remote_id
+    }
+
+    /// Underlying stream, e.g. to change its timeouts.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Encrypts and sends a message.
+    pub fn send(&mut self, message: &[u8]) -> Result<()> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            bail!("Message of {} bytes is too large", message.len());
+        }
+        let plaintext = [&(message.len() as u32).to_be_bytes()[..], message].concat();
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        for chunk in plaintext.chunks(MAX_PLAINTEXT_CHUNK) {
+            let len = self.transport.write_message(chunk, &mut buf)?;
+            write_frame(&mut self.stream, &buf[..len])?;
+        }
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Receives and decrypts a message.
+    pub fn recv(&mut self) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = self
+            .transport
+            .read_message(&read_frame(&mut self.stream)?, &mut buf)?;
+        if len < 4 {
+            bail!("Malformed message header");
+        }
+        let total = u32::from_be_bytes(buf[..4].try_into()?) as usize;
+        if total > MAX_MESSAGE_SIZE {
+            bail!("Message of {} bytes is too large", total);
+        }
+        let mut message = Vec::with_capacity(total);
+        message.extend_from_slice(&buf[4..len]);
+        while message.len() < total {
+            let len = self
+                .transport
+                .read_message(&read_frame(&mut self.stream)?, &mut buf)?;
+            message.extend_from_slice(&buf[..len]);
+        }
+        if message.len() != total {
+            bail!("Message is longer than announced");
+        }
+        Ok(message)
+    }
+}
+
+fn make_proof(identity: &NodeIdentity, static_public: &[u8]) -> Result<Vec<u8>> {
+    let proof = IdentityProof {
+        peer_id: identity.peer_id(),
+        signature: identity.sign(&[STATIC_KEY_DOMAIN, static_public].concat()),
+    };
+    Ok(bincode::serialize(&proof)?)
+}
+
+/// Checks that the Noise static key of the remote side is signed by the node key of the remote side.
+/// Returns the ID of the remote node.
+fn verify_proof(handshake: &HandshakeState, payload: &[u8]) -> Result<Peer> {
+    let proof: IdentityProof = bincode::deserialize(payload)?;
+    let static_public = handshake
+        .get_remote_static()
+        .ok_or_else(|| anyhow!("Remote static key is not known"))?;
+    let message = [STATIC_KEY_DOMAIN, static_public].concat();
+    if !verify_signature(&proof.peer_id, &message, &proof.signature) {
+        return Err(DistStoreError::InvalidIdentityProof {
+            peer: proof.peer_id,
+        }
+        .into());
+    }
+    Ok(proof.peer_id)
+}
+
+fn write_frame<W: Write>(stream: &mut W, frame: &[u8]) -> Result<()> {
+    stream.write_all(&(frame.len() as u16).to_be_bytes())?;
+    stream.write_all(frame)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(stream: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
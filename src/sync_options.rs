@@ -0,0 +1,305 @@
+//! Options of a synchronization run: cancellation and progress reporting,
+//! see [`CatalogNode::sync_with_peers_with`](crate::catalog::CatalogNode::sync_with_peers_with).
+//!
+//! A long sync can be cancelled from another thread with a [`CancellationToken`](CancellationToken).
+//! The sync stops before the next day is transferred. Each day is proposed atomically,
+//! so the catalog stays consistent, and the next sync resumes from where the cancelled one has stopped.
+//!
+//! Progress is reported to a [`ProgressSink`](ProgressSink) per peer: the partition being compared
+//! or transferred, and the number of days to transfer along with the number of days done.
+//!
+//! Options of a peer, e.g. the direction it is synchronized in, are given when the peer is added,
+//! see [`CatalogNode::add_peer_with_options`](crate::catalog::CatalogNode::add_peer_with_options).
+//!
+//! A sync can be limited to a part of the catalog with a [`SyncScope`](SyncScope): a date range or a set of years.
+//! The scope can be given per peer, and per sync; when both are given, only the days in both of them are synchronized.
+
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+
+use anyhow::Result;
+
+use crate::catalog::DistStoreError;
+use crate::local_storage::{Peer, TransferDirection};
+use crate::opaque_date::{ym_to_y, ymd_to_ym, Year, YearMonthDay};
+use crate::trace_context::Partition;
+
+/// Direction the node synchronizes with a peer in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncDirection {
+    #[default]
+    Bidirectional,
+    /// Days are only received from the peer, e.g. by a read-only archive mirror
+    PullOnly,
+    /// Days are only sent to the peer, e.g. by a phone that uploads photos
+    PushOnly,
+}
+
+impl SyncDirection {
+    pub fn allows(self, transfer: TransferDirection) -> bool {
+        match self {
+            SyncDirection::Bidirectional => true,
+            SyncDirection::PullOnly => transfer == TransferDirection::Pull,
+            SyncDirection::PushOnly => transfer == TransferDirection::Push,
+        }
+    }
+
+    pub(crate) fn as_u8(self) -> u8 {
+        match self {
+            SyncDirection::Bidirectional => 0,
+            SyncDirection::PullOnly => 1,
+            SyncDirection::PushOnly => 2,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            1 => SyncDirection::PullOnly,
+            2 => SyncDirection::PushOnly,
+            _ => SyncDirection::Bidirectional,
+        }
+    }
+}
+
+/// Part of the catalog that is synchronized.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SyncScope {
+    #[default]
+    All,
+    /// Days from the first one to the last one, both inclusive
+    DateRange {
+        from: YearMonthDay,
+        to: YearMonthDay,
+    },
+    Years(BTreeSet<Year>),
+}
+
+impl SyncScope {
+    pub fn date_range(from: YearMonthDay, to: YearMonthDay) -> Self {
+        SyncScope::DateRange { from, to }
+    }
+
+    pub fn years(years: impl IntoIterator<Item = Year>) -> Self {
+        SyncScope::Years(years.into_iter().collect())
+    }
+
+    pub fn contains_day(&self, ymd: YearMonthDay) -> bool {
+        match self {
+            SyncScope::All => true,
+            SyncScope::DateRange { from, to } => (*from..=*to).contains(&ymd),
+            SyncScope::Years(years) => years.contains(&ym_to_y(ymd_to_ym(ymd))),
+        }
+    }
+
+    /// Narrows the interval of days to the ones in the scope, `None` if there are no such days.
+    /// The interval is expected to be within one year, e.g. a year or a month.
+    fn clip(&self, (start, end): (u32, u32)) -> Option<(u32, u32)> {
+        match self {
+            SyncScope::All => Some((start, end)),
+            SyncScope::DateRange { from, to } => {
+                let (start, end) = (start.max(*from), end.min(*to));
+                (start <= end).then_some((start, end))
+            }
+            SyncScope::Years(years) => years
+                .contains(&ym_to_y(ymd_to_ym(start)))
+                .then_some((start, end)),
+        }
+    }
+
+    /// Encodes the scope as a kind and the bounds or years, to be stored in the catalog database.
+    pub(crate) fn to_row(&self) -> (u8, Vec<u32>) {
+        match self {
+            SyncScope::All => (0, Vec::new()),
+            SyncScope::DateRange { from, to } => (1, vec![*from, *to]),
+            SyncScope::Years(years) => (2, years.iter().copied().collect()),
+        }
+    }
+
+    pub(crate) fn from_row((kind, values): (u8, Vec<u32>)) -> Self {
+        match (kind, values.as_slice()) {
+            (1, [from, to]) => SyncScope::date_range(*from, *to),
+            (2, _) => SyncScope::years(values),
+            _ => SyncScope::All,
+        }
+    }
+}
+
+/// Scope of a peer combined with the scope of a sync.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScopeFilter<'a> {
+    pub(crate) peer: &'a SyncScope,
+    pub(crate) sync: &'a SyncScope,
+}
+
+impl ScopeFilter<'_> {
+    pub(crate) fn contains_day(&self, ymd: YearMonthDay) -> bool {
+        self.peer.contains_day(ymd) && self.sync.contains_day(ymd)
+    }
+
+    /// Days of the year or month interval that are in both scopes, `None` if there are no such days.
+    pub(crate) fn clip(&self, interval: (u32, u32)) -> Option<(u32, u32)> {
+        self.peer
+            .clip(interval)
+            .and_then(|interval| self.sync.clip(interval))
+    }
+
+    /// Whether the whole year or month interval is in both scopes.
+    /// Checksums of partially covered years and months include days out of the scope,
+    /// so such partitions are compared by the checksums of their days in the scope.
+    pub(crate) fn covers(&self, interval: (u32, u32)) -> bool {
+        self.clip(interval) == Some(interval)
+    }
+}
+
+/// Options of a peer, given when the peer is added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerOptions {
+    pub direction: SyncDirection,
+    pub scope: SyncScope,
+}
+
+/// Cancels the synchronizations it has been given to. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Progress of the synchronization with a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncProgress {
+    pub peer: Peer,
+    /// Partition being compared, or the day being transferred. `None` when the sync with the peer starts
+    pub partition: Option<Partition>,
+    /// Days to transfer found so far. Days changed according to the change logs are known first,
+    /// the rest is added once the checksum tree has been compared
+    pub total_days: usize,
+    /// Days transferred, or skipped as transferred by an interrupted sync
+    pub completed_days: usize,
+}
+
+/// Receives progress reports of a synchronization.
+pub trait ProgressSink: Send + Sync {
+    fn report(&self, progress: &SyncProgress);
+}
+
+impl<F: Fn(&SyncProgress) + Send + Sync> ProgressSink for F {
+    fn report(&self, progress: &SyncProgress) {
+        self(progress)
+    }
+}
+
+/// Sends the reports over a channel, e.g. to a UI thread. Reports are dropped if the receiver is gone.
+impl ProgressSink for mpsc::Sender<SyncProgress> {
+    fn report(&self, progress: &SyncProgress) {
+        let _ = self.send(progress.clone());
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SyncOptions {
+    cancellation: Option<CancellationToken>,
+    progress: Option<Arc<dyn ProgressSink>>,
+    scope: SyncScope,
+}
+
+impl SyncOptions {
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    pub fn with_progress(mut self, sink: impl ProgressSink + 'static) -> Self {
+        self.progress = Some(Arc::new(sink));
+        self
+    }
+
+    /// Limits the sync with every peer to the scope, in addition to the scopes of the peers.
+    pub fn with_scope(mut self, scope: SyncScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+    }
+
+    pub fn scope(&self) -> &SyncScope {
+        &self.scope
+    }
+}
+
+impl fmt::Debug for SyncOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncOptions")
+            .field("cancellation", &self.cancellation)
+            .field("progress", &self.progress.is_some())
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+/// Tracks the progress of the synchronization with one peer, and checks for the cancellation.
+pub(crate) struct PeerProgress<'a> {
+    options: &'a SyncOptions,
+    peer: Peer,
+    total_days: Cell<usize>,
+    completed_days: Cell<usize>,
+}
+
+impl<'a> PeerProgress<'a> {
+    pub(crate) fn new(options: &'a SyncOptions, peer: Peer) -> Self {
+        PeerProgress {
+            options,
+            peer,
+            total_days: Cell::new(0),
+            completed_days: Cell::new(0),
+        }
+    }
+
+    /// Reports that the sync has moved to the partition.
+    /// Fails with [`DistStoreError::SyncCancelled`] if the sync has been cancelled.
+    pub(crate) fn enter(&self, partition: Option<Partition>) -> Result<()> {
+        if self.options.is_cancelled() {
+            return Err(DistStoreError::SyncCancelled.into());
+        }
+        self.report(partition);
+        Ok(())
+    }
+
+    pub(crate) fn add_days(&self, days: usize) {
+        self.total_days.set(self.total_days.get() + days);
+    }
+
+    pub(crate) fn complete_day(&self, ymd: u32) {
+        self.completed_days.set(self.completed_days.get() + 1);
+        self.report(Some(Partition::Day(ymd)));
+    }
+
+    fn report(&self, partition: Option<Partition>) {
+        if let Some(sink) = &self.options.progress {
+            sink.report(&SyncProgress {
+                peer: self.peer.clone(),
+                partition,
+                total_days: self.total_days.get(),
+                completed_days: self.completed_days.get(),
+            });
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Trace context, that links the work done for one synchronization on all the nodes it involves.
+//!
+//! The synchronization is instrumented with [`tracing`](tracing) spans.
+//! The root span of a sync round gets a random trace ID, that is kept in a thread local
+//! while the round is running. The network transport sends the trace ID along with each request,
+//! and the server runs the request within the same trace ID,
+//! so that spans of both nodes can be matched by the `trace_id` field.
+
+use std::cell::Cell;
+
+use tracing::Span;
+
+use crate::opaque_date::{ym_to_y, ymd_to_ym, Year, YearMonth, YearMonthDay};
+
+pub type TraceId = u64;
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceId>> = const { Cell::new(None) };
+}
+
+/// Trace ID of the work the current thread is doing, if any.
+pub fn current_trace_id() -> Option<TraceId> {
+    CURRENT.with(|current| current.get())
+}
+
+pub fn new_trace_id() -> TraceId {
+    rand::random()
+}
+
+/// Runs the function within given trace ID, restoring the previous one afterwards (even on panic).
+pub fn in_trace<T>(trace_id: Option<TraceId>, f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<TraceId>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.set(self.0));
+        }
+    }
+    let _restore = Restore(CURRENT.with(|current| current.replace(trace_id)));
+    f()
+}
+
+/// Representation of a trace ID in span fields
+pub fn format_trace_id(trace_id: Option<TraceId>) -> String {
+    trace_id
+        .map(|id| format!("{:016x}", id))
+        .unwrap_or_default()
+}
+
+/// Representation of a peer ID in span fields
+pub(crate) fn format_peer(id: &[u8]) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Catalog partition a span (or a progress report of a sync) is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partition {
+    Year(Year),
+    Month(YearMonth),
+    Day(YearMonthDay),
+}
+
+impl Partition {
+    /// Records `year`, `month` and `day` fields of the span, as far as the partition defines them.
+    /// The fields must be declared (e.g. as `field::Empty`) when the span is created.
+    pub(crate) fn record(&self, span: &Span) {
+        let (y, ym, ymd) = match *self {
+            Partition::Year(y) => (y, None, None),
+            Partition::Month(ym) => (ym_to_y(ym), Some(ym), None),
+            Partition::Day(ymd) => (ym_to_y(ymd_to_ym(ymd)), Some(ymd_to_ym(ymd)), Some(ymd)),
+        };
+        span.record("year", y);
+        if let Some(ym) = ym {
+            span.record("month", ym);
+        }
+        if let Some(ymd) = ymd {
+            span.record("day", ymd);
+        }
+    }
+}
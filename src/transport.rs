@@ -0,0 +1,572 @@
+//! Network transport for the [`RemotePeer`](RemotePeer) trait.
+//!
+//! [`PeerServer`](PeerServer) exposes a local catalog node over TCP,
+//! and [`NetworkPeer`](NetworkPeer) is a client that implements `RemotePeer` by sending requests to the server.
+//! All the communication goes through the [`SecureChannel`](SecureChannel),
+//! so both sides are authenticated before any checksum or data is exchanged.
+//! Peers are authorised by the allow-list kept in the catalog database.
+//! Connections that stall during the handshake, or in the middle of a request, are closed after an I/O timeout.
+//! A client waits for a response up to a request timeout, and then closes the connection.
+//! Errors of the catalog ([`DistStoreError`]) reach the client as they are.
+//! Requests carry the trace ID of the caller (see [`trace_context`](crate::trace_context)),
+//! so that the work done by the server can be matched with the sync that caused it.
+
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::album::Album;
+use crate::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use crate::hashing::HashAlgorithm;
+use crate::identity::{AlbumProposal, LocationClaim, Proposal};
+use crate::local_storage::{ChangeFeed, Checksum, Data, DayData, Peer};
+use crate::membership::{MemberUpdate, PeerConnector};
+use crate::metrics::PeerMethod;
+use crate::opaque_date::{Year, YearMonth, YearMonthDay};
+use crate::secure_channel::SecureChannel;
+use crate::trace_context::{current_trace_id, format_peer, format_trace_id, in_trace, TraceId};
+
+/// Time the other side is given to complete the handshake, or to send the rest of a started message.
+/// Doesn't limit the time an established connection can stay idle between requests.
+pub const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time the client waits for the response to a request.
+/// Longer than the I/O timeout, as serving a request (e.g. a proposal of a big day) takes longer than a message.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Requests mirror the methods of the `RemotePeer` trait
+#[derive(Serialize, Deserialize)]
+enum Request {
+    /// Carries the address of the caller server, so that the notified node can connect back
+    NotifyAddedBy(Option<String>),
+    GetHashAlgorithm,
+    GetYearsChecksums,
+    GetMonthsChecksum(Year),
+    GetDaysChecksum(YearMonth),
+    GetExistingDaysInRange(YearMonthDay, YearMonthDay),
+    GetRangeChecksum(YearMonthDay, YearMonthDay),
+    GetData(YearMonthDay),
+    GetLocationClaims(YearMonthDay),
+    Propose(Proposal),
+    GetBlob(Data),
+    ChangesSince(u64),
+    Gossip(Vec<MemberUpdate>),
+    Probe(Peer),
+    GetAlbumsChecksums,
+    GetAlbum(String),
+    MergeAlbum(AlbumProposal),
+}
+
+impl Request {
+    fn method(&self) -> PeerMethod {
+        match self {
+            Request::NotifyAddedBy(_) => PeerMethod::NotifyAddedBy,
+            Request::GetHashAlgorithm => PeerMethod::GetHashAlgorithm,
+            Request::GetYearsChecksums => PeerMethod::GetYearsChecksums,
+            Request::GetMonthsChecksum(_) => PeerMethod::GetMonthsChecksum,
+            Request::GetDaysChecksum(_) => PeerMethod::GetDaysChecksum,
+            Request::GetExistingDaysInRange(_, _) => PeerMethod::GetExistingDaysInRange,
+            Request::GetRangeChecksum(_, _) => PeerMethod::GetRangeChecksum,
+            Request::GetData(_) => PeerMethod::GetData,
+            Request::GetLocationClaims(_) => PeerMethod::GetLocationClaims,
+            Request::Propose(_) => PeerMethod::Propose,
+            Request::GetBlob(_) => PeerMethod::GetBlob,
+            Request::ChangesSince(_) => PeerMethod::ChangesSince,
+            Request::Gossip(_) => PeerMethod::Gossip,
+            Request::Probe(_) => PeerMethod::Probe,
+            Request::GetAlbumsChecksums => PeerMethod::GetAlbumsChecksums,
+            Request::GetAlbum(_) => PeerMethod::GetAlbum,
+            Request::MergeAlbum(_) => PeerMethod::MergeAlbum,
+        }
+    }
+}
+
+/// Request along with the context of the caller
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    trace_id: Option<TraceId>,
+    request: Request,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Response {
+    Done,
+    HashAlgorithm(HashAlgorithm),
+    Checksums(Vec<(u32, Checksum)>),
+    Days(Vec<YearMonthDay>),
+    Data(Option<DayData>),
+    Claims(Vec<LocationClaim>),
+    Checksum(Checksum),
+    Blob(Option<Vec<u8>>),
+    Changes(ChangeFeed),
+    Members(Vec<MemberUpdate>),
+    Probed(bool),
+    AlbumsChecksums(Vec<(String, Checksum)>),
+    Album(Option<Album>),
+    /// Typed error, so that the caller can tell e.g. a refused peer from a broken connection
+    Failed(DistStoreError),
+    Error(String),
+}
+
+impl Response {
+    /// Errors of the catalog are sent as they are, others (e.g. of the storage) only as a description.
+    fn from_error(e: anyhow::Error) -> Self {
+        match e.downcast::<DistStoreError>() {
+            Ok(e) => Response::Failed(e),
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+}
+
+/// Client side of the transport: a remote catalog node reachable over the network.
+pub struct NetworkPeer {
+    peer_id: Peer,
+    channel: Mutex<SecureChannel<TcpStream>>,
+    /// Address of the local node server, see [`Membership::set_address`](crate::membership::Membership::set_address)
+    local_address: Option<String>,
+}
+
+impl NetworkPeer {
+    /// Connects to a remote node and performs the handshake.
+    /// The remote node must be in the allow-list of the local node,
+    /// and must prove it owns the key of the expected peer ID.
+    /// Args:
+    /// * node - local node, that owns the key we authenticate with
+    /// * addr - address of the remote node server
+    /// * peer_id - expected ID of the remote node
+    pub fn connect<A: ToSocketAddrs>(node: &CatalogNode, addr: A, peer_id: &[u8]) -> Result<Self> {
+        Self::connect_with_request_timeout(node, addr, peer_id, DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    /// Same as [`connect`](Self::connect), but with a custom [request timeout](DEFAULT_REQUEST_TIMEOUT).
+    pub fn connect_with_request_timeout<A: ToSocketAddrs>(
+        node: &CatalogNode,
+        addr: A,
+        peer_id: &[u8],
+        request_timeout: Duration,
+    ) -> Result<Self> {
+        if !node.storage().is_peer_authorized(peer_id)? {
+            return Err(DistStoreError::UnauthorizedPeer {
+                peer: peer_id.to_vec(),
+            }
+            .into());
+        }
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(DEFAULT_IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(DEFAULT_IO_TIMEOUT))?;
+        let channel = SecureChannel::connect(stream, node.identity(), peer_id)?;
+        channel.get_ref().set_read_timeout(Some(request_timeout))?;
+        Ok(NetworkPeer {
+            peer_id: peer_id.to_vec(),
+            channel: Mutex::new(channel),
+            local_address: node.membership().address(),
+        })
+    }
+
+    fn call(&self, request: Request) -> Result<Response> {
+        let envelope = Envelope {
+            trace_id: current_trace_id(),
+            request,
+        };
+        let mut channel = self.channel.lock().unwrap();
+        let received = channel
+            .send(&bincode::serialize(&envelope)?)
+            .and_then(|_| channel.recv());
+        let message = match received {
+            Ok(message) => message,
+            Err(e) => {
+                // A late response would be taken for the response to the next request
+                let _ = channel.get_ref().shutdown(Shutdown::Both);
+                return Err(e);
+            }
+        };
+        match bincode::deserialize(&message)? {
+            Response::Failed(e) => Err(e.into()),
+            Response::Error(e) => Err(anyhow!("Peer {:?} failed: {}", self.peer_id, e)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected_response() -> anyhow::Error {
+    anyhow!("Unexpected response type")
+}
+
+impl RemotePeer for NetworkPeer {
+    fn id(&self) -> Vec<u8> {
+        self.peer_id.clone()
+    }
+
+    fn notify_added_by(&self, _peer: Arc<dyn RemotePeer>) {
+        // The server knows who we are from the handshake, and can reach us only through our own server
+        if let Err(e) = self.call(Request::NotifyAddedBy(self.local_address.clone())) {
+            debug!("Failed to notify peer {:?}: {}", self.peer_id, e);
+        }
+    }
+
+    fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        match self.call(Request::GetHashAlgorithm)? {
+            Response::HashAlgorithm(algorithm) => Ok(algorithm),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_years_checksums(&self) -> Result<Vec<(Year, Checksum)>> {
+        match self.call(Request::GetYearsChecksums)? {
+            Response::Checksums(checksums) => Ok(checksums),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_months_checksum(&self, y: Year) -> Result<Vec<(YearMonth, Checksum)>> {
+        match self.call(Request::GetMonthsChecksum(y))? {
+            Response::Checksums(checksums) => Ok(checksums),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_days_checksum(&self, ym: YearMonth) -> Result<Vec<(YearMonthDay, Checksum)>> {
+        match self.call(Request::GetDaysChecksum(ym))? {
+            Response::Checksums(checksums) => Ok(checksums),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_existing_days_in_range(
+        &self,
+        ymd_from: YearMonthDay,
+        ymd_to: YearMonthDay,
+    ) -> Result<Vec<YearMonthDay>> {
+        match self.call(Request::GetExistingDaysInRange(ymd_from, ymd_to))? {
+            Response::Days(days) => Ok(days),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_range_checksum(&self, ymd_from: YearMonthDay, ymd_to: YearMonthDay) -> Result<Checksum> {
+        match self.call(Request::GetRangeChecksum(ymd_from, ymd_to))? {
+            Response::Checksum(checksum) => Ok(checksum),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_data(&self, ymd: u32) -> Result<Option<DayData>> {
+        match self.call(Request::GetData(ymd))? {
+            Response::Data(data) => Ok(data),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_location_claims(&self, ymd: YearMonthDay) -> Result<Vec<LocationClaim>> {
+        match self.call(Request::GetLocationClaims(ymd))? {
+            Response::Claims(claims) => Ok(claims),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn propose(&self, proposal: &Proposal) -> Result<Vec<u8>> {
+        match self.call(Request::Propose(proposal.clone()))? {
+            Response::Checksum(checksum) => Ok(checksum),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_blob(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.call(Request::GetBlob(id.to_vec()))? {
+            Response::Blob(blob) => Ok(blob),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<ChangeFeed> {
+        match self.call(Request::ChangesSince(seq))? {
+            Response::Changes(feed) => Ok(feed),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn gossip(&self, members: Vec<MemberUpdate>) -> Result<Vec<MemberUpdate>> {
+        match self.call(Request::Gossip(members))? {
+            Response::Members(members) => Ok(members),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn probe(&self, target: &[u8]) -> Result<bool> {
+        match self.call(Request::Probe(target.to_vec()))? {
+            Response::Probed(reachable) => Ok(reachable),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_albums_checksums(&self) -> Result<Vec<(String, Checksum)>> {
+        match self.call(Request::GetAlbumsChecksums)? {
+            Response::AlbumsChecksums(checksums) => Ok(checksums),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn get_album(&self, name: &str) -> Result<Option<Album>> {
+        match self.call(Request::GetAlbum(name.to_string()))? {
+            Response::Album(album) => Ok(album),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    fn merge_album(&self, proposal: &AlbumProposal) -> Result<Checksum> {
+        match self.call(Request::MergeAlbum(proposal.clone()))? {
+            Response::Checksum(checksum) => Ok(checksum),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+/// Connects peers over the network, trying their addresses one by one.
+/// Only peers from the allow-list are connected.
+pub struct NetworkConnector {
+    /// Weak, as the node itself keeps the connector
+    node: Weak<CatalogNode>,
+}
+
+impl NetworkConnector {
+    pub fn new(node: &Arc<CatalogNode>) -> Self {
+        NetworkConnector {
+            node: Arc::downgrade(node),
+        }
+    }
+}
+
+impl PeerConnector for NetworkConnector {
+    fn connect(&self, peer: &[u8], addresses: &[String]) -> Result<Arc<dyn RemotePeer>> {
+        let node = self
+            .node
+            .upgrade()
+            .ok_or_else(|| anyhow!("Node has been dropped"))?;
+        let mut last_error = anyhow!("Peer {:?} has no known address", peer);
+        for address in addresses {
+            match NetworkPeer::connect(&node, address.as_str(), peer) {
+                Ok(connected) => return Ok(Arc::new(connected)),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Server side of the transport: serves requests of authorised peers to the local node.
+/// Each connection is served in a separate thread.
+/// When dropped, the server stops accepting connections, closes the open ones and waits for their threads.
+pub struct PeerServer {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    accept_thread: Option<thread::JoinHandle<()>>,
+    connections: Arc<Mutex<Vec<Connection>>>,
+}
+
+/// Accepted connection, along with the thread serving it
+struct Connection {
+    stream: TcpStream,
+    thread: thread::JoinHandle<()>,
+}
+
+impl PeerServer {
+    /// Starts listening on given address, e.g. "127.0.0.1:0" for a random port on loopback.
+    pub fn start<A: ToSocketAddrs>(node: Arc<CatalogNode>, addr: A) -> Result<Self> {
+        Self::start_with_io_timeout(node, addr, DEFAULT_IO_TIMEOUT)
+    }
+
+    /// Same as [`start`](Self::start), but with a custom [I/O timeout](DEFAULT_IO_TIMEOUT).
+    pub fn start_with_io_timeout<A: ToSocketAddrs>(
+        node: Arc<CatalogNode>,
+        addr: A,
+        io_timeout: Duration,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(Mutex::new(Vec::<Connection>::new()));
+        let accept_thread = {
+            let stopped = stopped.clone();
+            let connections = connections.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let accepted = stream.and_then(|stream| {
+                        let handle = stream.try_clone()?;
+                        let node = node.clone();
+                        let thread = thread::spawn(move || {
+                            if let Err(e) = serve_connection(&node, &stream, io_timeout) {
+                                debug!("Connection to {} closed: {}", node.name(), e);
+                            }
+                            // The server keeps a handle of the stream, so dropping this one doesn't close it
+                            let _ = stream.shutdown(Shutdown::Both);
+                        });
+                        Ok(Connection {
+                            stream: handle,
+                            thread,
+                        })
+                    });
+                    match accepted {
+                        Ok(connection) => {
+                            let mut connections = connections.lock().unwrap();
+                            connections.retain(|c| !c.thread.is_finished());
+                            connections.push(connection);
+                        }
+                        Err(e) => debug!("Failed to accept connection: {}", e),
+                    }
+                }
+            })
+        };
+        Ok(PeerServer {
+            local_addr,
+            stopped,
+            accept_thread: Some(accept_thread),
+            connections,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for PeerServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accept loop, so that it can notice the stop flag
+        let _ = TcpStream::connect(self.local_addr);
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+        // Closing the streams wakes up the threads waiting for requests
+        let connections = std::mem::take(&mut *self.connections.lock().unwrap());
+        for connection in connections {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+            let _ = connection.thread.join();
+        }
+    }
+}
+
+/// Serves requests of one client until the connection is closed.
+/// A client that doesn't complete the handshake, or a started request, within the I/O timeout is disconnected.
+fn serve_connection(
+    node: &Arc<CatalogNode>,
+    stream: &TcpStream,
+    io_timeout: Duration,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(io_timeout))?;
+    stream.set_write_timeout(Some(io_timeout))?;
+    let storage = node.storage();
+    let mut channel = SecureChannel::accept(stream, node.identity(), |peer| {
+        storage.is_peer_authorized(peer).unwrap_or(false)
+    })?;
+    let remote_id = channel.remote_id().to_vec();
+    loop {
+        // The client can stay idle between requests as long as it wants
+        channel.get_ref().set_read_timeout(None)?;
+        if channel.get_ref().peek(&mut [0u8; 1])? == 0 {
+            return Ok(());
+        }
+        channel.get_ref().set_read_timeout(Some(io_timeout))?;
+        let envelope: Envelope = bincode::deserialize(&channel.recv()?)?;
+        let _enter = tracing::debug_span!(
+            "serve_request",
+            node = %node.name(),
+            peer = %format_peer(&remote_id),
+            method = envelope.request.method().as_str(),
+            trace_id = %format_trace_id(envelope.trace_id),
+        )
+        .entered();
+        let response = in_trace(envelope.trace_id, || {
+            handle_request(node, &remote_id, envelope.request)
+        })
+        .unwrap_or_else(Response::from_error);
+        channel.send(&bincode::serialize(&response)?)?;
+    }
+}
+
+/// Connects to the peer that has added the node and adds it as a peer, in a separate thread,
+/// so that the request of the peer doesn't wait for the connection, that goes to the peer itself.
+/// A peer that has been added in the meantime (e.g. by a concurrent notification) is left as it is.
+fn connect_back(node: &Arc<CatalogNode>, peer: Peer, address: String) {
+    let node = Arc::downgrade(node);
+    thread::spawn(move || {
+        let Some(node) = node.upgrade() else {
+            return;
+        };
+        let added = NetworkPeer::connect(&node, address.as_str(), &peer)
+            .and_then(|connected| node.add_peer_with_address(Arc::new(connected), &address));
+        if let Err(e) = added {
+            let duplicate = matches!(
+                e.downcast_ref::<DistStoreError>(),
+                Some(DistStoreError::DuplicatePeer { .. })
+            );
+            if !duplicate {
+                debug!("Failed to connect back to peer {:?}: {}", peer, e);
+            }
+        }
+    });
+}
+
+fn handle_request(node: &Arc<CatalogNode>, remote_id: &[u8], request: Request) -> Result<Response> {
+    let response = match request {
+        Request::NotifyAddedBy(address) => {
+            debug!("Peer {:?}, has been added by {:?}", node.id(), remote_id);
+            if let Some(address) = address {
+                if !node.peer_ids().iter().any(|p| p == remote_id) {
+                    connect_back(node, remote_id.to_vec(), address);
+                }
+            }
+            Response::Done
+        }
+        Request::GetHashAlgorithm => Response::HashAlgorithm(node.hash_algorithm()?),
+        Request::GetYearsChecksums => Response::Checksums(node.get_years_checksums()?),
+        Request::GetMonthsChecksum(y) => Response::Checksums(node.get_months_checksum(y)?),
+        Request::GetDaysChecksum(ym) => Response::Checksums(node.get_days_checksum(ym)?),
+        Request::GetExistingDaysInRange(from, to) => {
+            Response::Days(node.get_existing_days_in_range(from, to)?)
+        }
+        Request::GetRangeChecksum(from, to) => {
+            Response::Checksum(node.get_range_checksum(from, to)?)
+        }
+        Request::GetData(ymd) => Response::Data(node.get_data(ymd)?),
+        Request::GetLocationClaims(ymd) => Response::Claims(node.get_location_claims(ymd)?),
+        Request::Propose(proposal) => {
+            // Only the authenticated peer itself can make proposals over its connection
+            if proposal.proposer != remote_id {
+                return Err(DistStoreError::InvalidProposalSignature {
+                    proposer: proposal.proposer,
+                }
+                .into());
+            }
+            Response::Checksum(node.propose(&proposal)?)
+        }
+        Request::GetBlob(id) => Response::Blob(node.get_blob(&id)?),
+        Request::ChangesSince(seq) => Response::Changes(node.changes_since(seq)?),
+        Request::Gossip(members) => Response::Members(node.gossip(members)?),
+        Request::Probe(target) => Response::Probed(node.probe(&target)?),
+        Request::GetAlbumsChecksums => Response::AlbumsChecksums(node.get_albums_checksums()?),
+        Request::GetAlbum(name) => Response::Album(node.get_album(&name)?),
+        Request::MergeAlbum(proposal) => {
+            // Same as proposals of days, albums are merged only as the authenticated peer
+            if proposal.proposer != remote_id {
+                return Err(DistStoreError::InvalidProposalSignature {
+                    proposer: proposal.proposer,
+                }
+                .into());
+            }
+            Response::Checksum(node.merge_album(&proposal)?)
+        }
+    };
+    Ok(response)
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::faulty_peer::FaultyPeer;
+use photo_sync_tst::hashing::HashAlgorithm;
+use photo_sync_tst::identity::{AlbumProposal, NodeIdentity};
+use photo_sync_tst::local_storage::LocalStorage;
+use photo_sync_tst::metrics::PeerMethod;
+
+fn objects(node: &CatalogNode, album: &str) -> Result<Vec<Vec<u8>>> {
+    Ok(node.get_album(album)?.unwrap().objects())
+}
+
+#[test]
+fn test_album_operations() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    node.create_album("trip")?;
+    node.add_to_album("trip", &[img!(2), img!(1), img!(2)])?;
+    node.remove_from_album("trip", &[img!(2)])?;
+    assert_eq!(vec![img!(1)], objects(&node, "trip")?);
+
+    let err = node.create_album("trip").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::AlbumAlreadyExists { .. })
+    ));
+    let err = node.add_to_album("unknown", &[img!(1)]).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::UnknownAlbum { .. })
+    ));
+    assert!(node.get_album("unknown")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_albums_are_synced() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    let faulty = Arc::new(FaultyPeer::new(peer.clone()));
+    node.add_peer(faulty.clone())?;
+    node.create_album("trip")?;
+    node.add_to_album("trip", &[img!(1)])?;
+    peer.create_album("family")?;
+    peer.add_to_album("family", &[img!(2)])?;
+
+    node.sync_with_peers()?;
+    assert_eq!(node.get_albums_checksums()?, peer.get_albums_checksums()?);
+    assert_eq!(vec![img!(1)], objects(&peer, "trip")?);
+    assert_eq!(vec![img!(2)], objects(&node, "family")?);
+
+    // Removal made on the peer reaches the node
+    peer.remove_from_album("trip", &[img!(1)])?;
+    node.sync_with_peers()?;
+    assert!(objects(&node, "trip")?.is_empty());
+
+    // Albums with equal checksums are not exchanged
+    let exchanged = [PeerMethod::GetAlbum, PeerMethod::MergeAlbum].map(|m| faulty.calls(m));
+    node.sync_with_peers()?;
+    assert_eq!(
+        exchanged,
+        [PeerMethod::GetAlbum, PeerMethod::MergeAlbum].map(|m| faulty.calls(m))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_add_wins_over_remove() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    node.add_peer(peer.clone())?;
+    node.create_album("trip")?;
+    node.add_to_album("trip", &[img!(1), img!(2)])?;
+    node.sync_with_peers()?;
+
+    // The node removes the objects, while the peer adds one of them again without seeing the removal
+    node.remove_from_album("trip", &[img!(1), img!(2)])?;
+    peer.remove_from_album("trip", &[img!(1)])?;
+    peer.add_to_album("trip", &[img!(1)])?;
+    node.sync_with_peers()?;
+
+    assert_eq!(vec![img!(1)], objects(&node, "trip")?);
+    assert_eq!(node.get_album("trip")?, peer.get_album("trip")?);
+    Ok(())
+}
+
+#[test]
+fn test_read_only_node_refuses_album_merges() -> Result<()> {
+    let node = Arc::new(CatalogNode::test_new("node")?);
+    let peer = CatalogNode::test_new("peer")?;
+    node.set_read_only(true);
+    peer.add_peer(node.clone())?;
+    peer.create_album("trip")?;
+
+    assert!(peer.sync_with_peers().is_err());
+    assert!(node.get_album("trip")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_album_merge_must_be_signed_by_proposer() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = CatalogNode::test_new("peer")?;
+    peer.create_album("trip")?;
+    peer.add_to_album("trip", &[img!(1)])?;
+    let proposal = peer.propose_album("trip")?.unwrap();
+
+    let mut forged = AlbumProposal::new(&NodeIdentity::generate(), proposal.album.clone());
+    forged.proposer = peer.id();
+    let err = node.merge_album(&forged).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::InvalidProposalSignature { .. })
+    ));
+    assert!(node.get_album("trip")?.is_none());
+
+    node.merge_album(&proposal)?;
+    assert_eq!(vec![img!(1)], objects(&node, "trip")?);
+    Ok(())
+}
+
+#[test]
+fn test_album_checksums_follow_hash_algorithm() -> Result<()> {
+    let (s1, s2) = (LocalStorage::test_new()?, LocalStorage::test_new()?);
+    let s3 = LocalStorage::test_new_with_hash_algorithm(HashAlgorithm::Blake3)?;
+    s1.create_album("trip")?;
+    s1.add_to_album("trip", &[img!(1)])?;
+    let album = s1.get_album("trip")?.unwrap();
+    s2.merge_album(&album)?;
+    assert_eq!(s1.get_albums_checksums()?, s2.get_albums_checksums()?);
+
+    s3.merge_album(&album)?;
+    assert_ne!(s1.get_albums_checksums()?, s3.get_albums_checksums()?);
+    Ok(())
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::faulty_peer::{Fault, FaultyPeer};
+use photo_sync_tst::metrics::PeerMethod;
+use photo_sync_tst::replication::ReplicationPolicy;
+
+/// Peer that flips a bit in every blob it sends
+fn corrupting(node: &Arc<CatalogNode>) -> Arc<FaultyPeer<CatalogNode>> {
+    Arc::new(FaultyPeer::new(node.clone()).with_probability(
+        PeerMethod::GetBlob,
+        Fault::Corrupt,
+        1.0,
+    ))
+}
+
+#[test]
+fn test_corrupted_blob_is_rejected() -> Result<()> {
+    let honest = Arc::new(CatalogNode::test_new("honest")?);
+    let liar = Arc::new(CatalogNode::test_new("liar")?);
+    let node = Arc::new(CatalogNode::test_new("node")?);
+    node.add_peer(corrupting(&liar))?;
+
+    let photo = liar.ingest_photo(20200505, b"photo")?;
+    honest.ingest_photo(20200505, b"photo")?;
+    node.sync_with_peers()?;
+
+    // The only reachable holder sends a wrong content, so the photo can't be repaired
+    node.set_replication_policy(ReplicationPolicy::new(2));
+    assert_eq!(0, node.repair_replication()?);
+    assert_eq!(None, node.get_blob(&photo)?);
+    let err = node.retrive_photo(20200505, &photo).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::CorruptedBlobFromPeer { peer, id }) if *peer == liar.id() && *id == photo
+    ));
+    assert_eq!(
+        vec![(liar.id(), 2)],
+        node.storage().get_misbehaving_peers()?
+    );
+
+    // The content is taken from the next holder
+    node.add_peer(honest.clone())?;
+    node.sync_with_peers()?;
+    node.set_replication_policy(ReplicationPolicy::new(3));
+    assert_eq!(
+        Some(b"photo".to_vec()),
+        node.retrive_photo(20200505, &photo)?
+    );
+    assert_eq!(1, node.repair_replication()?);
+    assert_eq!(Some(b"photo".to_vec()), node.get_blob(&photo)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_missing_blob_is_not_an_error() -> Result<()> {
+    let node = Arc::new(CatalogNode::test_new("node")?);
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    node.add_peer(peer.clone())?;
+
+    // The peer knows the object, but doesn't keep its content
+    let ids = vec![img!(1)];
+    peer.add_photos(20200505, &ids)?;
+    node.sync_with_peers()?;
+    assert_eq!(None, node.retrive_photo(20200505, &ids[0])?);
+    Ok(())
+}
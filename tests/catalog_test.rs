@@ -3,7 +3,8 @@ mod common;
 use std::sync::Arc;
 
 use anyhow::Result;
-use photo_sync_tst::catalog::{CatalogNode, RemotePeer};
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::identity::{NodeIdentity, Proposal};
 
 #[test]
 fn test_synchronization() -> Result<()> {
@@ -11,11 +12,11 @@ fn test_synchronization() -> Result<()> {
     let peer1: Arc<CatalogNode> = Arc::new(CatalogNode::test_new("s1")?);
     let peer2 = Arc::new(CatalogNode::test_new("s2")?);
 
-    peer1.add_peer(peer2.clone());
-    peer2.add_peer(peer1.clone());
+    peer1.add_peer(peer2.clone())?;
+    peer2.add_peer(peer1.clone())?;
 
     // Adding photo object IDs to firsts
-    peer1.propose(20210711, &vec![(img!(0), peers!(0))])?;
+    peer1.add_photos(20210711, &[img!(0)])?;
 
     // Verify second peer doesn't know about newly added photos yet
     assert_eq!(0, peer2.get_years_checksums()?.len());
@@ -25,19 +26,97 @@ fn test_synchronization() -> Result<()> {
 
     // The second peer is aware of photos from first peer
     assert_eq!(1, peer2.get_years_checksums()?.len());
-    assert_eq!(Some(vec![(img!(0), peers!(0))]), peer2.get_data(20210711)?);
+    assert_eq!(
+        Some(vec![(img!(0), vec![peer1.id()])]),
+        peer2.get_data(20210711)?.map(|day| day.photos)
+    );
 
     // Now adding a photo to the second peer
-    peer2.propose(20210711, &vec![(img!(1), peers!(0))])?;
+    peer2.add_photos(20210711, &[img!(1)])?;
 
     // Launching sync on first peer
     peer1.sync_with_peers()?;
 
     // Verify updates have been fetched from peer 2
     assert_eq!(
-        Some(vec![(img!(0), peers!(0)), (img!(1), peers!(0))]),
-        peer1.get_data(20210711)?
+        Some(vec![
+            (img!(0), vec![peer1.id()]),
+            (img!(1), vec![peer2.id()])
+        ]),
+        peer1.get_data(20210711)?.map(|day| day.photos)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_propose_rejects_unsigned_labels() -> Result<()> {
+    let node = CatalogNode::test_new("s1")?;
+    let proposer = NodeIdentity::generate();
+    let keeper = NodeIdentity::generate();
+    let stranger = NodeIdentity::generate();
+
+    // Label of the stranger is not backed by a claim, so it is not proposed
+    let proposal = Proposal::new(
+        &proposer,
+        20210711,
+        vec![(img!(0), vec![keeper.peer_id(), stranger.peer_id()])],
+        vec![keeper.claim_location(&[0])],
+    );
+    node.propose(&proposal)?;
+    assert_eq!(
+        Some(vec![(img!(0), vec![keeper.peer_id()])]),
+        node.get_data(20210711)?.map(|day| day.photos)
+    );
+
+    // Label added after the proposal has been signed
+    let mut tampered = proposal.clone();
+    tampered.photos[0].1.push(stranger.peer_id());
+    let err = node.propose(&tampered).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::InvalidProposalSignature { .. })
+    ));
+    assert_eq!(
+        Some(vec![(img!(0), vec![keeper.peer_id()])]),
+        node.get_data(20210711)?.map(|day| day.photos)
     );
 
     Ok(())
 }
+
+#[test]
+fn test_add_peer_rejects_duplicates_and_self() -> Result<()> {
+    let node = Arc::new(CatalogNode::test_new("n1")?);
+    let peer = Arc::new(CatalogNode::test_new("n2")?);
+    node.add_peer(peer.clone())?;
+
+    let err = node.add_peer(peer.clone()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::DuplicatePeer { peer: p }) if *p == peer.id()
+    ));
+    let err = node.add_peer(node.clone()).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::SelfPeering)
+    ));
+    assert_eq!(vec![peer.id()], node.peer_ids());
+    Ok(())
+}
+
+#[test]
+fn test_notified_peer_adds_caller_back() -> Result<()> {
+    let node = Arc::new(CatalogNode::test_new("n1")?);
+    let peer = Arc::new(CatalogNode::test_new("n2")?);
+    node.add_peer(peer.clone())?;
+    peer.notify_added_by(node.clone());
+    // Repeated notifications don't add the caller twice
+    peer.notify_added_by(node.clone());
+
+    assert_eq!(vec![node.id()], peer.peer_ids());
+    node.add_photos(20210711, &[img!(0)])?;
+    peer.sync_with_peers()?;
+    assert_eq!(node.get_years_checksums()?, peer.get_years_checksums()?);
+    Ok(())
+}
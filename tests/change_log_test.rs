@@ -0,0 +1,170 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, RemotePeer};
+use photo_sync_tst::faulty_peer::FaultyPeer;
+use photo_sync_tst::local_storage::{ChangeRecord, LocalStorage, MAX_CHANGE_FEED_RECORDS};
+use photo_sync_tst::metrics::PeerMethod;
+
+#[test]
+fn test_change_log_records_day_mutations() -> Result<()> {
+    let storage = LocalStorage::test_new()?;
+    storage.add_photos_to_day(20200101, &[(img!(1), peers!(1))])?;
+    // Nothing new, so nothing is recorded
+    storage.add_photos_to_day(20200101, &[(img!(1), peers!(1))])?;
+    storage.add_photos_to_day(20200101, &[(img!(1), peers!(1, 2)), (img!(2), vec![])])?;
+    storage.add_photos_to_day(20200505, &[(img!(3), peers!(3))])?;
+
+    let feed = storage.changes_since(0)?;
+    assert!(!feed.truncated);
+    assert_eq!(3, feed.last_seq);
+    assert_eq!(
+        vec![
+            ChangeRecord {
+                seq: 1,
+                ymd: 20200101,
+                photos: vec![(img!(1), peers!(1))]
+            },
+            ChangeRecord {
+                seq: 2,
+                ymd: 20200101,
+                photos: vec![(img!(1), peers!(2)), (img!(2), vec![])]
+            },
+            ChangeRecord {
+                seq: 3,
+                ymd: 20200505,
+                photos: vec![(img!(3), peers!(3))]
+            },
+        ],
+        feed.changes
+    );
+    assert_eq!(
+        vec![3],
+        storage
+            .changes_since(2)?
+            .changes
+            .iter()
+            .map(|c| c.seq)
+            .collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_truncated_change_log() -> Result<()> {
+    let storage = LocalStorage::test_new()?;
+    for i in 0..5 {
+        storage.add_photos_to_day(20200101, &[(img!(i), peers!(1))])?;
+    }
+    storage.truncate_change_log(3)?;
+
+    // Changes 2 and 3 are lost, so none are returned
+    let feed = storage.changes_since(1)?;
+    assert!(feed.truncated && feed.changes.is_empty());
+    assert_eq!(5, feed.last_seq);
+    let feed = storage.changes_since(3)?;
+    assert!(!feed.truncated);
+    assert_eq!(
+        vec![4, 5],
+        feed.changes.iter().map(|c| c.seq).collect::<Vec<_>>()
+    );
+
+    // Sequence numbers are not reused after truncation of the whole log
+    storage.truncate_change_log(100)?;
+    storage.add_photos_to_day(20200101, &[(img!(9), peers!(1))])?;
+    assert_eq!(6, storage.changes_since(5)?.last_seq);
+    Ok(())
+}
+
+#[test]
+fn test_long_change_feed_is_paged() -> Result<()> {
+    let storage = LocalStorage::test_new()?;
+    for i in 0..MAX_CHANGE_FEED_RECORDS as u32 + 5 {
+        storage.add_photos_to_day(20200101, &[(i.to_be_bytes().to_vec(), peers!(1))])?;
+    }
+
+    let feed = storage.changes_since(0)?;
+    assert!(feed.has_more && !feed.truncated);
+    assert_eq!(MAX_CHANGE_FEED_RECORDS, feed.changes.len());
+    assert_eq!(MAX_CHANGE_FEED_RECORDS as u64, feed.last_seq);
+
+    // The rest of the log is read starting from the last received change
+    let feed = storage.changes_since(feed.last_seq)?;
+    assert!(!feed.has_more && !feed.truncated);
+    assert_eq!(5, feed.changes.len());
+    assert_eq!(MAX_CHANGE_FEED_RECORDS as u64 + 5, feed.last_seq);
+    Ok(())
+}
+
+#[test]
+fn test_incremental_sync_uses_change_feed() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    let counted = Arc::new(FaultyPeer::new(peer.clone()));
+    node.add_peer(counted.clone())?;
+
+    peer.add_photos(20200101, &[img!(1)])?;
+    node.add_photos(20210101, &[img!(2)])?;
+    node.sync_with_peers()?;
+    assert_eq!(peer.get_years_checksums()?, node.get_years_checksums()?);
+
+    // Changes from both sides are exchanged through the logs, the checksum tree is not walked
+    peer.add_photos(20200101, &[img!(3)])?;
+    node.add_photos(20210101, &[img!(4)])?;
+    let months_calls = counted.calls(PeerMethod::GetMonthsChecksum);
+    node.sync_with_peers()?;
+    assert_eq!(months_calls, counted.calls(PeerMethod::GetMonthsChecksum));
+    assert_eq!(peer.get_years_checksums()?, node.get_years_checksums()?);
+    assert_eq!(2, node.get_data(20200101)?.unwrap().photos.len());
+    assert_eq!(2, peer.get_data(20210101)?.unwrap().photos.len());
+    Ok(())
+}
+
+#[test]
+fn test_long_change_feed_is_synced_incrementally() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    let counted = Arc::new(FaultyPeer::new(peer.clone()));
+    node.add_peer(counted.clone())?;
+    peer.add_photos(20200101, &[img!(0)])?;
+    node.sync_with_peers()?;
+
+    // More changes than fit into one feed, all in a month the node already has
+    for i in 0..MAX_CHANGE_FEED_RECORDS as u32 + 5 {
+        peer.add_photos(20200101 + i % 28, &[i.to_be_bytes().to_vec()])?;
+    }
+    let (feeds, months) = (
+        counted.calls(PeerMethod::ChangesSince),
+        counted.calls(PeerMethod::GetMonthsChecksum),
+    );
+    node.sync_with_peers()?;
+
+    assert_eq!(peer.get_years_checksums()?, node.get_years_checksums()?);
+    assert_eq!(feeds + 2, counted.calls(PeerMethod::ChangesSince));
+    // The tree is equal after the feed is transferred, so it is not walked
+    assert_eq!(months, counted.calls(PeerMethod::GetMonthsChecksum));
+    Ok(())
+}
+
+#[test]
+fn test_sync_falls_back_to_checksum_tree() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    let counted = Arc::new(FaultyPeer::new(peer.clone()));
+    node.add_peer(counted.clone())?;
+    node.sync_with_peers()?;
+
+    // The peer doesn't keep the changes the node hasn't seen yet
+    peer.add_photos(20200101, &[img!(1)])?;
+    peer.add_photos(20200202, &[img!(2)])?;
+    peer.storage().truncate_change_log(1)?;
+
+    // Year is missing on the node, so the tree walk looks for existing days of the year
+    let ranges_calls = counted.calls(PeerMethod::GetExistingDaysInRange);
+    node.sync_with_peers()?;
+    assert!(counted.calls(PeerMethod::GetExistingDaysInRange) > ranges_calls);
+    assert_eq!(peer.get_years_checksums()?, node.get_years_checksums()?);
+    Ok(())
+}
@@ -0,0 +1,240 @@
+//! Simulation of process crashes and power loss for the redb storage.
+//!
+//! [`SimulatedDisk`](SimulatedDisk) keeps the content of a database "file".
+//! Writes that are not followed by `sync_data` are not durable yet:
+//! after a crash the disk may contain any subset of them, and some of them may be torn.
+//! [`CrashingBackend`](CrashingBackend) gives redb access to the disk,
+//! and stops working (as a dead process would) after given number of write operations.
+
+#![allow(dead_code)]
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use redb::StorageBackend;
+
+#[derive(Debug, Clone)]
+enum PendingOp {
+    Write(u64, Vec<u8>),
+    SetLen(u64),
+}
+
+#[derive(Debug, Default)]
+struct DiskState {
+    /// Content that survives a crash
+    durable: Vec<u8>,
+    /// Content as seen by the running process
+    current: Vec<u8>,
+    /// Operations after the last sync, in order
+    pending: Vec<PendingOp>,
+}
+
+/// What is left on the disk after a crash.
+#[derive(Debug, Clone, Copy)]
+pub enum CrashImage {
+    /// None of the not synced writes reached the disk
+    LoseUnsynced,
+    /// All the not synced writes reached the disk
+    KeepUnsynced,
+    /// Random subset of the not synced writes reached the disk, some of them partially
+    Torn(u64),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedDisk {
+    state: Arc<Mutex<DiskState>>,
+}
+
+impl SimulatedDisk {
+    pub fn new() -> Self {
+        SimulatedDisk::default()
+    }
+
+    /// Independent copy of the disk, e.g. to run several scenarios from the same initial state.
+    pub fn copy(&self) -> Self {
+        let state = self.state.lock().unwrap();
+        SimulatedDisk {
+            state: Arc::new(Mutex::new(DiskState {
+                durable: state.durable.clone(),
+                current: state.current.clone(),
+                pending: state.pending.clone(),
+            })),
+        }
+    }
+
+    /// Backend that works until the process "crashes".
+    /// Args:
+    /// * write_budget - number of write operations (write, set_len, sync) that succeed,
+    ///   the next one is torn and fails, as well as all the operations after it. `None` for no crash.
+    pub fn backend(&self, write_budget: Option<usize>) -> CrashingBackend {
+        CrashingBackend {
+            state: self.state.clone(),
+            budget: Mutex::new(Budget {
+                left: write_budget,
+                crashed: false,
+            }),
+        }
+    }
+
+    /// Simulates a restart of the machine: the not durable content is replaced with what the disk has kept.
+    pub fn restart(&self, image: CrashImage) {
+        let mut state = self.state.lock().unwrap();
+        let mut content = state.durable.clone();
+        let pending = std::mem::take(&mut state.pending);
+        let mut rng = match image {
+            CrashImage::Torn(seed) => Some(ChaCha8Rng::seed_from_u64(seed)),
+            _ => None,
+        };
+        for op in pending {
+            let op = match (image, rng.as_mut()) {
+                (CrashImage::LoseUnsynced, _) => continue,
+                (CrashImage::Torn(_), Some(rng)) => {
+                    if rng.gen_bool(0.5) {
+                        continue;
+                    }
+                    match op {
+                        PendingOp::Write(offset, data) if rng.gen_bool(0.2) => {
+                            let len = rng.gen_range(0..=data.len());
+                            PendingOp::Write(offset, data[..len].to_vec())
+                        }
+                        op => op,
+                    }
+                }
+                _ => op,
+            };
+            apply(&mut content, &op);
+        }
+        state.durable = content.clone();
+        state.current = content;
+    }
+}
+
+fn apply(content: &mut Vec<u8>, op: &PendingOp) {
+    match op {
+        PendingOp::Write(offset, data) => {
+            let end = *offset as usize + data.len();
+            if content.len() < end {
+                content.resize(end, 0);
+            }
+            content[*offset as usize..end].copy_from_slice(data);
+        }
+        PendingOp::SetLen(len) => content.resize(*len as usize, 0),
+    }
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("simulated crash")
+}
+
+#[derive(Debug)]
+struct Budget {
+    left: Option<usize>,
+    crashed: bool,
+}
+
+/// Outcome of a write operation
+#[derive(PartialEq)]
+enum Spend {
+    Done,
+    /// The process dies during this operation
+    Crash,
+    /// The process is already dead
+    Dead,
+}
+
+#[derive(Debug)]
+pub struct CrashingBackend {
+    state: Arc<Mutex<DiskState>>,
+    budget: Mutex<Budget>,
+}
+
+impl CrashingBackend {
+    /// Spends one write operation from the budget.
+    fn spend(&self) -> Spend {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.crashed {
+            return Spend::Dead;
+        }
+        match budget.left.as_mut() {
+            None => Spend::Done,
+            Some(0) => {
+                budget.crashed = true;
+                Spend::Crash
+            }
+            Some(left) => {
+                *left -= 1;
+                Spend::Done
+            }
+        }
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.budget.lock().unwrap().crashed
+    }
+}
+
+impl StorageBackend for CrashingBackend {
+    fn len(&self) -> Result<u64, io::Error> {
+        if self.is_crashed() {
+            return Err(crashed());
+        }
+        Ok(self.state.lock().unwrap().current.len() as u64)
+    }
+
+    fn read(&self, offset: u64, len: usize) -> Result<Vec<u8>, io::Error> {
+        if self.is_crashed() {
+            return Err(crashed());
+        }
+        let state = self.state.lock().unwrap();
+        let end = offset as usize + len;
+        if end > state.current.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end",
+            ));
+        }
+        Ok(state.current[offset as usize..end].to_vec())
+    }
+
+    fn set_len(&self, len: u64) -> Result<(), io::Error> {
+        if self.spend() != Spend::Done {
+            return Err(crashed());
+        }
+        let mut state = self.state.lock().unwrap();
+        let op = PendingOp::SetLen(len);
+        apply(&mut state.current, &op);
+        state.pending.push(op);
+        Ok(())
+    }
+
+    fn sync_data(&self, _eventual: bool) -> Result<(), io::Error> {
+        if self.spend() != Spend::Done {
+            return Err(crashed());
+        }
+        let mut state = self.state.lock().unwrap();
+        state.durable = state.current.clone();
+        state.pending.clear();
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        match self.spend() {
+            Spend::Done => {}
+            Spend::Crash => {
+                // The process dies in the middle of the write
+                let op = PendingOp::Write(offset, data[..data.len() / 2].to_vec());
+                apply(&mut state.current, &op);
+                state.pending.push(op);
+                return Err(crashed());
+            }
+            Spend::Dead => return Err(crashed()),
+        }
+        let op = PendingOp::Write(offset, data.to_vec());
+        apply(&mut state.current, &op);
+        state.pending.push(op);
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+mod common;
+mod crash;
+
+use anyhow::Result;
+use crash::{CrashImage, SimulatedDisk};
+use photo_sync_tst::local_storage::{LocalStorage, Photo};
+use photo_sync_tst::opaque_date::YearMonthDay;
+
+/// Days the batches are added to. Two of them share a month, so that month checksums are recalculated.
+const DAYS: [YearMonthDay; 3] = [20200101, 20200115, 20210301];
+
+fn batch(i: u8) -> Vec<Photo> {
+    (0..5).map(|j| (img!(i, j), peers!(i % 3, 9))).collect()
+}
+
+/// Adds the batches until the first error, returns the number of committed batches.
+fn add_batches(storage: &LocalStorage, batches: &[(YearMonthDay, Vec<Photo>)]) -> usize {
+    batches
+        .iter()
+        .take_while(|(ymd, photos)| storage.add_photos_to_day(*ymd, photos).is_ok())
+        .count()
+}
+
+/// Content of all the test days
+type DaysContent = [Option<Vec<Photo>>; DAYS.len()];
+
+/// Crashes the process at every write point of a series of `add_photos_to_day` calls,
+/// and checks that after the restart the storage is consistent,
+/// and contains a prefix of the batches with at least all the committed ones.
+#[test]
+fn test_crash_at_every_write_point() -> Result<()> {
+    let batches = (0..6u8)
+        .map(|i| (DAYS[i as usize % DAYS.len()], batch(i)))
+        .collect::<Vec<_>>();
+
+    // Expected content of the days after the first N batches
+    let mut expected = Vec::new();
+    {
+        let reference = LocalStorage::test_new()?;
+        expected.push(DAYS.map(|_| None));
+        for (ymd, photos) in &batches {
+            reference.add_photos_to_day(*ymd, photos)?;
+            expected.push(DAYS.map(|d| reference.get_photos(d).unwrap()));
+        }
+    }
+
+    let images = [
+        CrashImage::LoseUnsynced,
+        CrashImage::KeepUnsynced,
+        CrashImage::Torn(1),
+        CrashImage::Torn(2),
+    ];
+    // Crash during the creation of a new database file is out of scope
+    let empty = SimulatedDisk::new();
+    drop(LocalStorage::with_backend(empty.backend(None), None)?);
+
+    for image in images {
+        crash_at_every_write_point(image, &empty, &batches, &expected)?;
+    }
+    Ok(())
+}
+
+fn crash_at_every_write_point(
+    image: CrashImage,
+    empty: &SimulatedDisk,
+    batches: &[(YearMonthDay, Vec<Photo>)],
+    expected: &[DaysContent],
+) -> Result<()> {
+    let mut budget = 0;
+    loop {
+        let disk = empty.copy();
+
+        let committed = match LocalStorage::with_backend(disk.backend(Some(budget)), None) {
+            Ok(storage) => add_batches(&storage, batches),
+            // Crashed while opening the database
+            Err(_) => 0,
+        };
+        disk.restart(image);
+
+        let storage = LocalStorage::with_backend(disk.backend(None), None)
+            .unwrap_or_else(|e| panic!("{:?} at {}: reopen failed: {}", image, budget, e));
+        storage
+            .verify_consistency()
+            .unwrap_or_else(|e| panic!("{:?} at {}: {}", image, budget, e));
+
+        // Committed batches survive, and a batch is either applied completely or not at all
+        let content = DAYS.map(|d| storage.get_photos(d).unwrap());
+        let recovered = expected.iter().position(|e| *e == content);
+        assert!(
+            matches!(recovered, Some(n) if n >= committed),
+            "{:?} at {}: {} batches committed, recovered state {:?}",
+            image,
+            budget,
+            committed,
+            recovered
+        );
+
+        // The storage is usable after the recovery
+        storage.add_photos_to_day(20220202, &[(img!(42), peers!(1))])?;
+        storage.verify_consistency()?;
+
+        if committed == batches.len() {
+            return Ok(());
+        }
+        budget += 1;
+    }
+}
+
+#[test]
+fn test_verify_consistency_of_clean_storage() -> Result<()> {
+    let storage = LocalStorage::test_new()?;
+    storage.verify_consistency()?;
+    for (i, ymd) in DAYS.iter().enumerate() {
+        storage.add_photos_to_day(*ymd, &batch(i as u8))?;
+    }
+    storage.verify_consistency()?;
+    Ok(())
+}
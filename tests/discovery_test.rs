@@ -0,0 +1,81 @@
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use photo_sync_tst::catalog::CatalogNode;
+use photo_sync_tst::discovery::{Discovery, DiscoveryConfig};
+use photo_sync_tst::transport::PeerServer;
+
+/// Node along with everything it needs to be discovered
+struct LanNode {
+    node: Arc<CatalogNode>,
+    _server: PeerServer,
+    _discovery: Discovery,
+}
+
+fn start_on_loopback(name: &str) -> Result<(Arc<CatalogNode>, PeerServer)> {
+    let node = Arc::new(CatalogNode::test_new(name)?);
+    let server = PeerServer::start(node.clone(), "127.0.0.1:0")?;
+    Ok((node, server))
+}
+
+fn discover(node: Arc<CatalogNode>, server: PeerServer, group_port: u16) -> Result<LanNode> {
+    let mut config = DiscoveryConfig::new(server.local_addr().port());
+    config.group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 77), group_port);
+    config.interface = Ipv4Addr::LOCALHOST;
+    config.interval = Duration::from_millis(100);
+    Ok(LanNode {
+        _discovery: Discovery::start(node.clone(), config)?,
+        node,
+        _server: server,
+    })
+}
+
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn test_authorised_peers_are_discovered() -> Result<()> {
+    let group_port = 47901;
+    let (n1, s1) = start_on_loopback("lan1")?;
+    let (n2, s2) = start_on_loopback("lan2")?;
+    let (n3, s3) = start_on_loopback("stranger")?;
+    n1.storage().authorize_peer(&n2.id(), n2.name())?;
+    n2.storage().authorize_peer(&n1.id(), n1.name())?;
+    // The stranger trusts n1, but n1 doesn't trust the stranger
+    n3.storage().authorize_peer(&n1.id(), n1.name())?;
+
+    let lan1 = discover(n1, s1, group_port)?;
+    let lan2 = discover(n2, s2, group_port)?;
+    let lan3 = discover(n3, s3, group_port)?;
+
+    assert!(wait_for(|| lan1.node.peer_ids() == vec![lan2.node.id()]
+        && lan2.node.peer_ids() == vec![lan1.node.id()]));
+
+    // Discovered peers are synchronized as the manually added ones
+    lan2.node.add_photos(20200101, &[img!(1)])?;
+    lan1.node.sync_with_peers()?;
+    assert_eq!(
+        lan2.node.storage().get_photos(20200101)?,
+        lan1.node.storage().get_photos(20200101)?
+    );
+
+    // Announcements keep going, but known peers are not added twice
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(vec![lan2.node.id()], lan1.node.peer_ids());
+    // The stranger can't connect to n1, as n1 rejects the handshake
+    assert!(lan3.node.peer_ids().is_empty());
+    Ok(())
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogEvent, CatalogNode};
+use photo_sync_tst::local_storage::DayChange;
+
+#[test]
+fn test_local_changes_are_reported() -> Result<()> {
+    let node = CatalogNode::test_new("n1")?;
+    let events = node.subscribe();
+
+    node.add_photos(20200101, &[img!(1), img!(2)])?;
+    // Nothing new, so no event
+    node.add_photos(20200101, &[img!(1)])?;
+
+    assert_eq!(
+        vec![CatalogEvent::DayChanged(DayChange {
+            ymd: 20200101,
+            added: vec![img!(1), img!(2)],
+            new_labels: vec![(img!(1), node.id()), (img!(2), node.id())],
+            metadata: vec![],
+        })],
+        events.try_iter().collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_sync_changes_are_reported() -> Result<()> {
+    let n1 = Arc::new(CatalogNode::test_new("n1")?);
+    let n2 = Arc::new(CatalogNode::test_new("n2")?);
+    n1.add_peer(n2.clone())?;
+
+    n1.add_photos(20200101, &[img!(1)])?;
+    n2.add_photos(20200101, &[img!(1)])?;
+    n2.add_photos(20200202, &[img!(2)])?;
+
+    let events = n1.subscribe();
+    n1.sync_with_peers()?;
+
+    assert_eq!(
+        vec![
+            CatalogEvent::SyncStarted,
+            // Existing object got a new label
+            CatalogEvent::DayChanged(DayChange {
+                ymd: 20200101,
+                added: vec![],
+                new_labels: vec![(img!(1), n2.id())],
+                metadata: vec![],
+            }),
+            CatalogEvent::DayChanged(DayChange {
+                ymd: 20200202,
+                added: vec![img!(2)],
+                new_labels: vec![(img!(2), n2.id())],
+                metadata: vec![],
+            }),
+            CatalogEvent::SyncFinished { failed: vec![] },
+        ],
+        events.try_iter().collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_dropped_subscriber_is_forgotten() -> Result<()> {
+    let node = CatalogNode::test_new("n1")?;
+    drop(node.subscribe());
+    let events = node.subscribe();
+
+    node.add_photos(20200101, &[img!(1)])?;
+
+    assert_eq!(1, events.try_iter().count());
+    Ok(())
+}
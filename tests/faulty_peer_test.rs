@@ -0,0 +1,149 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::faulty_peer::{Fault, FaultyPeer};
+use photo_sync_tst::metrics::PeerMethod;
+
+fn failed_peers(res: Result<()>) -> Vec<Vec<u8>> {
+    match res.unwrap_err().downcast::<DistStoreError>() {
+        Ok(DistStoreError::PartialSync { failed }) => failed,
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_failed_peer_does_not_stop_sync_with_others() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let broken = Arc::new(CatalogNode::test_new("broken")?);
+    let healthy = Arc::new(CatalogNode::test_new("healthy")?);
+    broken.add_photos(20200101, &[img!(1)])?;
+    healthy.add_photos(20200101, &[img!(2)])?;
+
+    let faulty = FaultyPeer::new(broken.clone())
+        .with_script(PeerMethod::GetHashAlgorithm, vec![Some(Fault::Fail)]);
+    node.add_peer(Arc::new(faulty))?;
+    node.add_peer(healthy.clone())?;
+
+    assert_eq!(vec![broken.id()], failed_peers(node.sync_with_peers()));
+    assert_eq!(
+        Some(vec![(img!(2), vec![healthy.id()])]),
+        node.get_data(20200101)?.map(|day| day.photos)
+    );
+
+    // The fault is gone, so the next sync completes
+    node.sync_with_peers()?;
+    assert_eq!(2, node.get_data(20200101)?.unwrap().photos.len());
+    Ok(())
+}
+
+#[test]
+fn test_panicking_peer_does_not_block_next_sync() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    peer.add_photos(20200101, &[img!(1)])?;
+    node.add_peer(Arc::new(
+        FaultyPeer::new(peer.clone()).with_script(PeerMethod::GetData, vec![Some(Fault::Panic)]),
+    ))?;
+
+    assert_eq!(vec![peer.id()], failed_peers(node.sync_with_peers()));
+    node.sync_with_peers()?;
+    assert_eq!(peer.get_years_checksums()?, node.get_years_checksums()?);
+    Ok(())
+}
+
+#[test]
+fn test_truncated_data_is_completed_by_next_sync() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    peer.add_photos(20200101, &[img!(1), img!(2), img!(3), img!(4)])?;
+    // The day is requested twice: for the change feed, and for the checksum tree
+    let truncations = vec![Some(Fault::Truncate), Some(Fault::Truncate)];
+    node.add_peer(Arc::new(
+        FaultyPeer::new(peer.clone()).with_script(PeerMethod::GetData, truncations),
+    ))?;
+
+    // A truncated response can't be detected, the node gets a part of the day
+    node.sync_with_peers()?;
+    assert_eq!(2, node.get_data(20200101)?.unwrap().photos.len());
+    assert_ne!(peer.get_years_checksums()?, node.get_years_checksums()?);
+
+    // The change feed of the peer is already consumed,
+    // but the checksums still differ, so the next sync transfers the day again
+    node.sync_with_peers()?;
+    assert_eq!(peer.get_data(20200101)?, node.get_data(20200101)?);
+    Ok(())
+}
+
+#[test]
+fn test_corrupted_ids_are_not_labeled() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    peer.add_photos(20200101, &[img!(2), img!(4)])?;
+    let corruptions = vec![Some(Fault::Corrupt), Some(Fault::Corrupt)];
+    node.add_peer(Arc::new(
+        FaultyPeer::new(peer.clone()).with_script(PeerMethod::GetData, corruptions),
+    ))?;
+
+    node.sync_with_peers()?;
+
+    // Claims of the peer don't match corrupted IDs, so nobody is recorded as keeping them
+    assert_eq!(
+        Some(vec![(img!(3), vec![]), (img!(5), vec![])]),
+        node.get_data(20200101)?.map(|day| day.photos)
+    );
+    assert!(node.storage().get_peer_objects(&peer.id())?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_hanging_peer_times_out() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    let hang = Duration::from_millis(20);
+    node.add_peer(Arc::new(FaultyPeer::new(peer.clone()).with_script(
+        PeerMethod::GetYearsChecksums,
+        vec![Some(Fault::Hang(hang))],
+    )))?;
+
+    let start = Instant::now();
+    assert_eq!(vec![peer.id()], failed_peers(node.sync_with_peers()));
+    assert!(start.elapsed() >= hang);
+    Ok(())
+}
+
+#[test]
+fn test_random_faults_are_reproducible() -> Result<()> {
+    let run = |seed: u64| -> Result<Vec<bool>> {
+        let node = CatalogNode::test_new("node")?;
+        let peer = Arc::new(CatalogNode::test_new("peer")?);
+        for d in 1..=5 {
+            peer.add_photos(20200100 + d, &[img!(d as u8)])?;
+        }
+        let faulty = Arc::new(
+            FaultyPeer::new(peer.clone())
+                .with_seed(seed)
+                .with_probability(PeerMethod::GetData, Fault::Fail, 0.5),
+        );
+        node.add_peer(faulty.clone())?;
+
+        // Random failures slow down, but don't prevent the convergence
+        let mut outcomes = Vec::new();
+        while outcomes.len() < 50 {
+            let ok = node.sync_with_peers().is_ok();
+            outcomes.push(ok);
+            if ok {
+                break;
+            }
+        }
+        assert_eq!(Some(&true), outcomes.last());
+        assert_eq!(peer.get_years_checksums()?, node.get_years_checksums()?);
+        assert!(faulty.calls(PeerMethod::GetData) >= 5);
+        Ok(outcomes)
+    };
+    assert_eq!(run(7)?, run(7)?);
+    Ok(())
+}
@@ -0,0 +1,67 @@
+mod common;
+mod crash;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use crash::SimulatedDisk;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::hashing::HashAlgorithm;
+use photo_sync_tst::local_storage::LocalStorage;
+
+#[test]
+fn test_hash_algorithm_is_stored_in_database() -> Result<()> {
+    let disk = SimulatedDisk::new();
+    let storage = LocalStorage::with_backend(disk.backend(None), None)?;
+    assert_eq!(HashAlgorithm::Sha256, storage.hash_algorithm());
+    storage.add_photos_to_day(20200101, &[(img!(1), peers!(1))])?;
+    let sha256_checksums = storage.get_years_checksums()?;
+
+    // Opening with another algorithm recalculates the whole checksum tree
+    drop(storage);
+    let storage = LocalStorage::with_backend(disk.backend(None), Some(HashAlgorithm::Blake3))?;
+    let blake3_checksums = storage.get_years_checksums()?;
+    assert_ne!(sha256_checksums, blake3_checksums);
+    storage.verify_consistency()?;
+
+    // The algorithm survives a restart, and is used for new photos
+    drop(storage);
+    let storage = LocalStorage::with_backend(disk.backend(None), None)?;
+    assert_eq!(HashAlgorithm::Blake3, storage.hash_algorithm());
+    assert_eq!(blake3_checksums, storage.get_years_checksums()?);
+    storage.add_photos_to_day(20200102, &[(img!(2), peers!(1))])?;
+    storage.verify_consistency()?;
+
+    // Object IDs and labels are not affected
+    assert_eq!(
+        Some(vec![(img!(1), peers!(1))]),
+        storage.get_photos(20200101)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_peers_with_different_algorithms_do_not_sync() -> Result<()> {
+    let peer1 = Arc::new(CatalogNode::test_new("s1")?);
+    let peer2 = Arc::new(CatalogNode::test_new_with_hash_algorithm(
+        "s2",
+        HashAlgorithm::Blake3,
+    )?);
+    peer1.add_peer(peer2.clone())?;
+    peer1.add_photos(20200101, &[img!(1)])?;
+
+    let err = peer1.sync_with_peers().unwrap_err();
+    assert!(matches!(
+        err.downcast::<DistStoreError>()?,
+        DistStoreError::PartialSync { failed } if failed == vec![peer2.id()]
+    ));
+    // Nothing is transferred because of checksums that don't match
+    assert_eq!(None, peer2.get_data(20200101)?);
+
+    let peer3 = CatalogNode::test_new_with_hash_algorithm("s3", HashAlgorithm::Blake3)?;
+    peer3.add_peer(peer2.clone())?;
+    peer3.add_photos(20200101, &[img!(1)])?;
+    peer3.sync_with_peers()?;
+    assert_eq!(peer3.get_years_checksums()?, peer2.get_years_checksums()?);
+    Ok(())
+}
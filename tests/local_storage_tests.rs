@@ -1,5 +1,9 @@
+// Tests pass vectors to slice arguments of the storage
+#![allow(clippy::useless_vec)]
+
 mod common;
-use photo_sync_tst::local_storage::LocalStorage;
+use photo_sync_tst::catalog::DistStoreError;
+use photo_sync_tst::local_storage::{object_id, LocalStorage};
 
 #[test]
 fn test_add_photo_idempotency() -> anyhow::Result<()> {
@@ -134,3 +138,92 @@ fn test_checksums_do_not_depend_on_order() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_peer_inventory() -> anyhow::Result<()> {
+    let sut: LocalStorage = LocalStorage::test_new()?;
+
+    sut.add_photos_to_day(20210101, &[(img!(0), peers!(0))])?;
+    sut.add_photos_to_day(20220101, &[(img!(1), peers!(0, 1))])?;
+    sut.add_photos_to_day(20220305, &[(img!(2), peers!(1))])?;
+    // Peer 0 obtains a copy of the photo that used to be kept only by peer 1
+    sut.add_photos_to_day(20220305, &[(img!(2), peers!(0))])?;
+
+    assert_eq!(
+        vec![
+            (20210101, img!(0)),
+            (20220101, img!(1)),
+            (20220305, img!(2))
+        ],
+        sut.get_peer_objects(&[0])?
+    );
+    assert_eq!(
+        vec![(20220101, img!(1)), (20220305, img!(2))],
+        sut.get_peer_objects(&[1])?
+    );
+    assert_eq!(Vec::<(u32, Vec<u8>)>::new(), sut.get_peer_objects(&[2])?);
+
+    assert_eq!(
+        vec![(2021, 1), (2022, 2)],
+        sut.count_peer_objects_per_year(&[0])?
+    );
+    assert_eq!(vec![(2022, 2)], sut.count_peer_objects_per_year(&[1])?);
+
+    assert_eq!(
+        vec![(20210101, img!(0))],
+        sut.get_objects_only_on_peer(&[0])?
+    );
+    assert!(sut.get_objects_only_on_peer(&[1])?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_put_blob_verifies_object_id() -> anyhow::Result<()> {
+    let sut: LocalStorage = LocalStorage::test_new()?;
+
+    let id = object_id(b"photo");
+    sut.put_blob(&id, b"photo")?;
+    assert_eq!(Some(b"photo".to_vec()), sut.get_blob(&id)?);
+
+    let wrong_id = object_id(b"another photo");
+    let err = sut.put_blob(&wrong_id, b"photo").unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::ObjectIdMismatch { .. })
+    ));
+    assert_eq!(None, sut.get_blob(&wrong_id)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_peers_table() -> anyhow::Result<()> {
+    let sut: LocalStorage = LocalStorage::test_new()?;
+
+    sut.put_peer(&[1], None, None)?;
+    sut.put_peer(&[2], Some("10.0.0.2:7000"), None)?;
+    sut.put_peer(&[2], Some("10.0.0.3:7000"), None)?;
+    // A known address moves to the front
+    sut.put_peer(&[2], Some("10.0.0.2:7000"), None)?;
+    sut.record_peer_sync(&[2], 1_700_000_000, vec![(2022, vec![7])])?;
+    // Re-adding the peer keeps its sync metadata
+    sut.put_peer(&[2], None, None)?;
+
+    let peers = sut.get_peers()?;
+    assert_eq!(
+        vec![vec![1], vec![2]],
+        peers.iter().map(|p| p.id.clone()).collect::<Vec<_>>()
+    );
+    assert!(peers[0].addresses.is_empty());
+    assert_eq!(None, peers[0].last_sync);
+
+    let peer = sut.get_peer(&[2])?.unwrap();
+    assert_eq!(vec!["10.0.0.2:7000", "10.0.0.3:7000"], peer.addresses);
+    assert_eq!(Some(1_700_000_000), peer.last_sync);
+    assert_eq!(vec![(2022, vec![7])], peer.years_checksums);
+    assert_eq!(&[2], peer.public_key());
+    assert_eq!(None, sut.get_peer(&[3])?);
+
+    Ok(())
+}
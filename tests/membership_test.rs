@@ -0,0 +1,208 @@
+mod common;
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use photo_sync_tst::catalog::{CatalogNode, RemotePeer};
+use photo_sync_tst::faulty_peer::{Fault, FaultyPeer};
+use photo_sync_tst::membership::{MemberState, MembershipConfig, PeerConnector};
+use photo_sync_tst::metrics::PeerMethod;
+use photo_sync_tst::transport::{NetworkConnector, NetworkPeer, PeerServer};
+
+/// Connects members to the in-process nodes
+#[derive(Default)]
+struct Registry {
+    nodes: Mutex<Vec<Weak<CatalogNode>>>,
+}
+
+impl Registry {
+    fn node(self: &Arc<Self>, name: &str) -> Result<Arc<CatalogNode>> {
+        let node = Arc::new(CatalogNode::test_new(name)?);
+        node.set_peer_connector(self.clone());
+        self.nodes.lock().unwrap().push(Arc::downgrade(&node));
+        Ok(node)
+    }
+}
+
+impl PeerConnector for Registry {
+    fn connect(&self, peer: &[u8], _addresses: &[String]) -> Result<Arc<dyn RemotePeer>> {
+        let nodes = self.nodes.lock().unwrap();
+        let node = nodes
+            .iter()
+            .filter_map(|n| n.upgrade())
+            .find(|n| n.id() == peer)
+            .ok_or_else(|| anyhow!("Unknown member"))?;
+        Ok(node)
+    }
+}
+
+fn sorted(mut ids: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_members_spread_through_gossip() -> Result<()> {
+    let registry = Arc::new(Registry::default());
+    let nodes = (0..4)
+        .map(|i| registry.node(&format!("n{}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    // Each node knows only the next one
+    for pair in nodes.windows(2) {
+        pair[0].add_peer(pair[1].clone())?;
+    }
+
+    for _ in 0..20 {
+        nodes.iter().for_each(|n| n.gossip_round());
+    }
+
+    for node in &nodes {
+        let others = nodes
+            .iter()
+            .map(|n| n.id())
+            .filter(|id| *id != node.id())
+            .collect();
+        assert_eq!(sorted(others), sorted(node.peer_ids()));
+        assert_eq!(4, node.membership().members().len());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_served_gossip_connects_members_in_next_round() -> Result<()> {
+    let registry = Arc::new(Registry::default());
+    let n1 = registry.node("n1")?;
+    let n2 = registry.node("n2")?;
+    let n3 = registry.node("n3")?;
+    n1.add_peer(n3.clone())?;
+
+    // Serving the gossip only merges the members, without waiting for connections
+    n2.gossip(n1.membership().members())?;
+    assert!(n2.peer_ids().is_empty());
+    assert!(n2.membership().members().iter().any(|m| m.peer == n3.id()));
+
+    n2.gossip_round();
+    assert!(n2.peer_ids().contains(&n3.id()));
+    Ok(())
+}
+
+#[test]
+fn test_dead_peer_is_detected_and_skipped() -> Result<()> {
+    let registry = Arc::new(Registry::default());
+    let n1 = registry.node("n1")?;
+    let n2 = registry.node("n2")?;
+    let dead = Arc::new(
+        FaultyPeer::new(registry.node("n3")?)
+            .with_probability(PeerMethod::Gossip, Fault::Fail, 1.0)
+            .with_probability(PeerMethod::Probe, Fault::Fail, 1.0)
+            .with_probability(PeerMethod::GetHashAlgorithm, Fault::Fail, 1.0),
+    );
+    n1.membership().set_config(MembershipConfig {
+        suspect_timeout: Duration::ZERO,
+        ..MembershipConfig::default()
+    });
+    n1.add_peer(n2.clone())?;
+    n1.add_peer(dead.clone())?;
+    n2.add_peer(dead.clone())?;
+
+    for _ in 0..50 {
+        n1.gossip_round();
+        if n1.membership().state_of(&dead.id()) == Some(MemberState::Dead) {
+            break;
+        }
+    }
+    assert_eq!(
+        Some(MemberState::Dead),
+        n1.membership().state_of(&dead.id())
+    );
+
+    // The dead peer is not synchronized with, so the sync doesn't fail
+    let calls = dead.calls(PeerMethod::GetHashAlgorithm);
+    n1.sync_with_peers()?;
+    assert_eq!(calls, dead.calls(PeerMethod::GetHashAlgorithm));
+    Ok(())
+}
+
+#[test]
+fn test_peer_reachable_by_others_is_not_suspected() -> Result<()> {
+    let registry = Arc::new(Registry::default());
+    let n1 = registry.node("n1")?;
+    let n2 = registry.node("n2")?;
+    let n3 = registry.node("n3")?;
+    // Only the connection between n1 and n3 is broken
+    let broken = Arc::new(FaultyPeer::new(n3.clone()).with_probability(
+        PeerMethod::Gossip,
+        Fault::Fail,
+        1.0,
+    ));
+    n1.add_peer(n2.clone())?;
+    n1.add_peer(broken.clone())?;
+    n2.add_peer(n3.clone())?;
+
+    while broken.calls(PeerMethod::Gossip) < 3 {
+        n1.gossip_round();
+    }
+
+    assert_eq!(Some(MemberState::Alive), n1.membership().state_of(&n3.id()));
+    Ok(())
+}
+
+#[test]
+fn test_sync_picks_random_subset_of_peers() -> Result<()> {
+    let node = CatalogNode::test_new("n")?;
+    for i in 0..4 {
+        node.add_peer(Arc::new(CatalogNode::test_new(&format!("p{}", i))?))?;
+    }
+    node.membership().set_config(MembershipConfig {
+        sync_fanout: Some(2),
+        ..MembershipConfig::default()
+    });
+
+    node.sync_with_peers()?;
+
+    assert_eq!(
+        (2, 0),
+        node.metrics().peer_calls(PeerMethod::GetHashAlgorithm)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_members_are_connected_over_network() -> Result<()> {
+    let nodes = (0..3)
+        .map(|i| Ok(Arc::new(CatalogNode::test_new(&format!("net{}", i))?)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut servers = Vec::new();
+    for node in &nodes {
+        for other in &nodes {
+            if other.id() != node.id() {
+                node.storage().authorize_peer(&other.id(), other.name())?;
+            }
+        }
+        let server = PeerServer::start(node.clone(), "127.0.0.1:0")?;
+        node.membership()
+            .set_address(server.local_addr().to_string());
+        node.set_peer_connector(Arc::new(NetworkConnector::new(node)));
+        servers.push(server);
+    }
+    // net0 knows only net1, that knows net2
+    nodes[0].add_peer(Arc::new(NetworkPeer::connect(
+        &nodes[0],
+        servers[1].local_addr(),
+        &nodes[1].id(),
+    )?))?;
+    nodes[1].add_peer(Arc::new(NetworkPeer::connect(
+        &nodes[1],
+        servers[2].local_addr(),
+        &nodes[2].id(),
+    )?))?;
+
+    for _ in 0..10 {
+        nodes.iter().for_each(|n| n.gossip_round());
+    }
+
+    assert!(nodes[0].peer_ids().contains(&nodes[2].id()));
+    assert!(nodes[2].peer_ids().contains(&nodes[0].id()));
+    Ok(())
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogEvent, CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::identity::{NodeIdentity, Proposal};
+use photo_sync_tst::metadata::ObjectMetadata;
+
+#[test]
+fn test_merge_is_deterministic() {
+    let a = ObjectMetadata::default()
+        .with_mime_type("image/jpeg")
+        .with_dimensions(640, 480)
+        .with_extra("camera", "x100");
+    let b = ObjectMetadata::default()
+        .with_mime_type("image/png")
+        .with_taken_at(1_600_000_000)
+        .with_extra("camera", "a7")
+        .with_extra("lens", "35mm");
+
+    let (mut ab, mut ba) = (a.clone(), b.clone());
+    assert!(ab.merge(&b));
+    assert!(ba.merge(&a));
+    assert_eq!(ab, ba);
+    assert_eq!(
+        ObjectMetadata::default()
+            .with_mime_type("image/png")
+            .with_dimensions(640, 480)
+            .with_taken_at(1_600_000_000)
+            .with_extra("camera", "x100")
+            .with_extra("lens", "35mm"),
+        ab
+    );
+    // Merging the same record again changes nothing
+    assert!(!ab.merge(&a));
+}
+
+#[test]
+fn test_metadata_is_synced() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    node.add_peer(peer.clone())?;
+    let id = node.ingest_photo(20200101, b"photo")?;
+    node.sync_with_peers()?;
+
+    let size = ObjectMetadata::default().with_size(5);
+    assert_eq!(
+        vec![(id.clone(), size.clone())],
+        peer.get_metadata(20200101)?
+    );
+    // Records come along with the object IDs, without a separate call
+    assert_eq!(
+        vec![(id.clone(), size.clone())],
+        node.get_data(20200101)?.unwrap().metadata
+    );
+
+    // Records changed after the object has been synced are synced too
+    let events = peer.subscribe();
+    node.merge_metadata(
+        20200101,
+        &[(
+            id.clone(),
+            ObjectMetadata::default().with_file_name("a.jpg"),
+        )],
+    )?;
+    node.sync_with_peers()?;
+
+    let record = size.with_file_name("a.jpg");
+    assert_eq!(vec![(id.clone(), record)], peer.get_metadata(20200101)?);
+    assert_eq!(node.get_years_checksums()?, peer.get_years_checksums()?);
+    assert!(events.try_iter().any(|e| matches!(
+        e,
+        CatalogEvent::DayChanged(change) if change.metadata == vec![id.clone()]
+    )));
+    Ok(())
+}
+
+#[test]
+fn test_conflicting_records_converge() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    node.add_peer(peer.clone())?;
+    let jpeg = ObjectMetadata::default().with_mime_type("image/jpeg");
+    let png = ObjectMetadata::default().with_mime_type("image/png");
+    node.add_photos_with_metadata(20200101, &[img!(1)], &[(img!(1), jpeg)])?;
+    peer.add_photos_with_metadata(20200101, &[img!(1)], &[(img!(1), png.clone())])?;
+
+    node.sync_with_peers()?;
+
+    assert_eq!(vec![(img!(1), png)], node.get_metadata(20200101)?);
+    assert_eq!(node.get_metadata(20200101)?, peer.get_metadata(20200101)?);
+    assert_eq!(node.get_years_checksums()?, peer.get_years_checksums()?);
+    Ok(())
+}
+
+#[test]
+fn test_records_of_unknown_objects_are_dropped() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let checksum = node.add_photos(20200101, &[img!(1)])?;
+    let record = ObjectMetadata::default().with_size(1);
+
+    assert_eq!(
+        checksum,
+        node.merge_metadata(20200101, &[(img!(2), record.clone())])?
+    );
+    assert!(node.get_metadata(20200101)?.is_empty());
+
+    // Records are part of the day checksum
+    assert_ne!(
+        checksum,
+        node.merge_metadata(20200101, &[(img!(1), record)])?
+    );
+    node.storage().verify_consistency()?;
+    Ok(())
+}
+
+#[test]
+fn test_proposal_signs_metadata() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let proposer = NodeIdentity::generate();
+    let record = ObjectMetadata::default().with_size(1);
+    let proposal = Proposal::new_with_metadata(
+        &proposer,
+        20200101,
+        vec![(img!(1), vec![])],
+        vec![],
+        vec![(img!(1), record)],
+    );
+
+    let mut tampered = proposal.clone();
+    tampered.metadata[0].1.size = Some(2);
+    let err = node.propose(&tampered).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::InvalidProposalSignature { .. })
+    ));
+
+    node.propose(&proposal)?;
+    assert_eq!(proposal.metadata, node.get_metadata(20200101)?);
+    Ok(())
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::CatalogNode;
+use photo_sync_tst::faulty_peer::{Fault, FaultyPeer};
+use photo_sync_tst::metrics::{MetricsServer, PeerMethod};
+
+#[test]
+fn test_sync_is_counted() -> Result<()> {
+    let n1 = Arc::new(CatalogNode::test_new("n1")?);
+    let n2 = Arc::new(CatalogNode::test_new("n2")?);
+    let broken = Arc::new(
+        FaultyPeer::new(Arc::new(CatalogNode::test_new("n3")?))
+            .with_script(PeerMethod::GetHashAlgorithm, vec![Some(Fault::Fail)]),
+    );
+    n1.add_peer(n2.clone())?;
+    n1.add_peer(broken)?;
+    n2.add_photos(20200101, &[img!(1), img!(2)])?;
+    n1.metrics().enable_byte_counters();
+
+    assert!(n1.sync_with_peers().is_err());
+
+    let metrics = n1.metrics();
+    assert_eq!(1, metrics.sync_rounds());
+    assert_eq!(1, metrics.failed_peer_syncs());
+    assert_eq!(1, metrics.sync_duration().count);
+    assert_eq!((2, 1), metrics.peer_calls(PeerMethod::GetHashAlgorithm));
+    assert_eq!((1, 0), metrics.peer_calls(PeerMethod::GetData));
+    assert!(metrics.bytes_received() > 0);
+
+    let text = n1.render_metrics()?;
+    assert!(text.contains("photo_sync_objects 2\n"));
+    assert!(text.contains("photo_sync_days 1\n"));
+    assert!(text.contains("photo_sync_peers 2\n"));
+    assert!(text.contains("photo_sync_sync_rounds_total 1\n"));
+    assert!(text.contains("photo_sync_sync_duration_seconds_count 1\n"));
+    assert!(text.contains("photo_sync_peer_call_errors_total{method=\"hash_algorithm\"} 1\n"));
+    Ok(())
+}
+
+#[test]
+fn test_metrics_are_served_over_http() -> Result<()> {
+    let node = Arc::new(CatalogNode::test_new("n1")?);
+    node.add_photos(20200101, &[img!(1)])?;
+    let server = MetricsServer::start(node, "127.0.0.1:0")?;
+
+    let mut stream = TcpStream::connect(server.local_addr())?;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("photo_sync_objects 1\n"));
+    Ok(())
+}
@@ -0,0 +1,153 @@
+mod common;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use photo_sync_tst::catalog::{CatalogNode, RemotePeer};
+use photo_sync_tst::membership::PeerConnector;
+use photo_sync_tst::sync_options::{PeerOptions, SyncDirection};
+
+/// Connects to the given in-process nodes by their IDs
+struct Nodes(Vec<Arc<CatalogNode>>);
+
+impl PeerConnector for Nodes {
+    fn connect(&self, peer: &[u8], _addresses: &[String]) -> Result<Arc<dyn RemotePeer>> {
+        let node = self
+            .0
+            .iter()
+            .find(|n| n.id() == peer)
+            .ok_or_else(|| anyhow!("Unknown peer"))?;
+        Ok(node.clone())
+    }
+}
+
+/// Database file that is removed after the test
+struct TempDb(PathBuf);
+
+impl TempDb {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}-{}.redb", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        TempDb(path)
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn test_peers_are_restored_after_restart() -> Result<()> {
+    let db = TempDb::new("peer_restore");
+    let p1 = Arc::new(CatalogNode::test_new("p1")?);
+    let p2 = Arc::new(CatalogNode::test_new("p2")?);
+    p1.add_photos(20200101, &[img!(1)])?;
+
+    let node_id = {
+        let node = CatalogNode::new("node", &db.0)?;
+        node.add_peer(p1.clone())?;
+        node.add_peer(p2.clone())?;
+        node.sync_with_peers()?;
+        node.id()
+    };
+
+    // p2 can't be connected, but it stays recorded for the next attempt
+    let connector = Nodes(vec![p1.clone()]);
+    let node = CatalogNode::open_with_connector("node", &db.0, |_| Arc::new(connector))?;
+    assert_eq!(node_id, node.id());
+    assert_eq!(vec![p1.id()], node.peer_ids());
+    assert_eq!(2, node.storage().get_peers()?.len());
+
+    let record = node.storage().get_peer(&p1.id())?.unwrap();
+    assert!(record.last_sync.is_some());
+    assert_eq!(p1.get_years_checksums()?, record.years_checksums);
+
+    // Already added peers are not restored twice
+    node.set_peer_connector(Arc::new(Nodes(vec![p1.clone(), p2.clone()])));
+    assert_eq!(1, node.restore_peers()?);
+    assert_eq!(vec![p1.id(), p2.id()], node.peer_ids());
+    Ok(())
+}
+
+#[test]
+fn test_restore_requires_connector() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    assert!(node.restore_peers().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_peer_direction_is_restored() -> Result<()> {
+    let db = TempDb::new("peer_direction_restore");
+    let p1 = Arc::new(CatalogNode::test_new("p1")?);
+    let p2 = Arc::new(CatalogNode::test_new("p2")?);
+    {
+        let node = CatalogNode::new("node", &db.0)?;
+        let pull_only = PeerOptions {
+            direction: SyncDirection::PullOnly,
+            ..PeerOptions::default()
+        };
+        node.add_peer_with_options(p1.clone(), Some("10.0.0.1:7000"), pull_only)?;
+        node.add_peer(p2.clone())?;
+    }
+
+    let connector = Nodes(vec![p1.clone(), p2.clone()]);
+    let node = CatalogNode::open_with_connector("node", &db.0, |_| Arc::new(connector))?;
+    assert_eq!(2, node.peer_ids().len());
+    let direction = |peer: &CatalogNode| node.peer_options(&peer.id()).unwrap().direction;
+    assert_eq!(SyncDirection::PullOnly, direction(&p1));
+    assert_eq!(SyncDirection::Bidirectional, direction(&p2));
+    let record = node.storage().get_peer(&p1.id())?.unwrap();
+    assert_eq!(vec!["10.0.0.1:7000"], record.addresses);
+    assert_eq!(SyncDirection::PullOnly, record.direction);
+    Ok(())
+}
+
+#[test]
+fn test_peer_added_without_options_keeps_recorded_ones() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    let push_only = PeerOptions {
+        direction: SyncDirection::PushOnly,
+        ..PeerOptions::default()
+    };
+    // Recorded before a restart, and connected again e.g. after the peer is learned from the gossip
+    node.storage()
+        .put_peer(&peer.id(), None, Some(&push_only))?;
+    node.add_peer_with_address(peer.clone(), "10.0.0.1:7000")?;
+    assert_eq!(Some(push_only.clone()), node.peer_options(&peer.id()));
+    assert_eq!(
+        push_only,
+        node.storage().get_peer(&peer.id())?.unwrap().options()
+    );
+
+    // Removal forgets the options as well
+    node.remove_peer(&peer.id())?;
+    node.add_peer(peer.clone())?;
+    assert_eq!(Some(PeerOptions::default()), node.peer_options(&peer.id()));
+    Ok(())
+}
+
+#[test]
+fn test_removed_peer_is_not_restored() -> Result<()> {
+    let db = TempDb::new("peer_remove");
+    let p1 = Arc::new(CatalogNode::test_new("p1")?);
+    let p2 = Arc::new(CatalogNode::test_new("p2")?);
+    let connector = || Nodes(vec![p1.clone(), p2.clone()]);
+    {
+        let node = CatalogNode::open_with_connector("node", &db.0, |_| Arc::new(connector()))?;
+        node.add_peer(p1.clone())?;
+        node.add_peer(p2.clone())?;
+        assert!(node.remove_peer(&p1.id())?);
+        assert!(!node.remove_peer(&p1.id())?);
+        assert_eq!(vec![p2.id()], node.peer_ids());
+    }
+
+    let node = CatalogNode::open_with_connector("node", &db.0, |_| Arc::new(connector()))?;
+    assert_eq!(vec![p2.id()], node.peer_ids());
+    assert_eq!(None, node.storage().get_peer(&p1.id())?);
+    Ok(())
+}
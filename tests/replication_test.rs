@@ -0,0 +1,104 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, RemotePeer};
+use photo_sync_tst::replication::ReplicationPolicy;
+
+fn connected_nodes(names: &[&str]) -> Result<Vec<Arc<CatalogNode>>> {
+    let nodes = names
+        .iter()
+        .map(|n| CatalogNode::test_new(n).map(Arc::new))
+        .collect::<Result<Vec<_>>>()?;
+    for a in &nodes {
+        for b in &nodes {
+            if !Arc::ptr_eq(a, b) {
+                a.add_peer(b.clone())?;
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+#[test]
+fn test_replication_report() -> Result<()> {
+    let nodes = connected_nodes(&["s1", "s2", "s3"])?;
+    let photo_2020 = nodes[0].ingest_photo(20200505, b"photo 2020")?;
+    let photo_2021 = nodes[0].ingest_photo(20210505, b"photo 2021")?;
+
+    // By default a single copy is enough
+    assert!(nodes[0].replication_report()?.under_replicated.is_empty());
+
+    nodes[0].set_replication_policy(ReplicationPolicy::new(2).with_year(2020, 3));
+    let report = nodes[0].replication_report()?;
+    assert_eq!(2, report.under_replicated.len());
+
+    let entry_2020 = &report.under_replicated[0];
+    assert_eq!(photo_2020, entry_2020.data);
+    assert_eq!(vec![nodes[0].id()], entry_2020.holders);
+    assert_eq!(3, entry_2020.target);
+    assert_eq!(2, entry_2020.suggested.len());
+    assert!(!entry_2020.suggested.contains(&nodes[0].id()));
+
+    let entry_2021 = &report.under_replicated[1];
+    assert_eq!(photo_2021, entry_2021.data);
+    assert_eq!(1, entry_2021.suggested.len());
+
+    Ok(())
+}
+
+#[test]
+fn test_repair_replication() -> Result<()> {
+    let nodes = connected_nodes(&["s1", "s2", "s3"])?;
+    for node in &nodes {
+        node.set_replication_policy(ReplicationPolicy::new(2));
+    }
+    let photo = nodes[0].ingest_photo(20200505, b"photo")?;
+    nodes[0].sync_with_peers()?;
+
+    // Every node repairs what it is suggested to fetch, then new labels are spread by sync
+    let repaired: usize = nodes
+        .iter()
+        .map(|n| n.repair_replication())
+        .sum::<Result<usize>>()?;
+    assert_eq!(1, repaired);
+    for node in &nodes {
+        node.sync_with_peers()?;
+    }
+
+    for node in &nodes {
+        assert!(node.replication_report()?.under_replicated.is_empty());
+        let holders = &node.get_data(20200505)?.unwrap().photos[0].1;
+        assert_eq!(2, holders.len());
+    }
+    let new_holder = nodes
+        .iter()
+        .find(|n| n.id() != nodes[0].id() && n.get_blob(&photo).unwrap().is_some());
+    assert!(new_holder.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_background_repair() -> Result<()> {
+    let nodes = connected_nodes(&["s1", "s2"])?;
+    nodes[1].set_replication_policy(ReplicationPolicy::new(2));
+    let photo = nodes[0].ingest_photo(20200505, b"photo")?;
+    nodes[0].sync_with_peers()?;
+
+    let repair = nodes[1].start_background_repair(Duration::from_millis(10));
+    let mut fetched = None;
+    for _ in 0..100 {
+        fetched = nodes[1].get_blob(&photo)?;
+        if fetched.is_some() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    repair.stop();
+
+    assert_eq!(Some(b"photo".to_vec()), fetched);
+    Ok(())
+}
@@ -0,0 +1,485 @@
+//! Deterministic simulation of a network of catalog nodes.
+//!
+//! Nodes are in-memory [`CatalogNode`]s connected one with each other through [`SimPeer`],
+//! that routes every call through the [`SimNetwork`].
+//! The network injects latency, drops, partitions and reordering of proposals.
+//! All the randomness comes from a single RNG seeded by the simulation seed,
+//! and the nodes are driven from a single thread, so a failing seed can be replayed.
+#![allow(dead_code)]
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, ensure, Result};
+use photo_sync_tst::album::Album;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::hashing::HashAlgorithm;
+use photo_sync_tst::identity::{AlbumProposal, LocationClaim, Proposal};
+use photo_sync_tst::local_storage::{ChangeFeed, Checksum, Data, DayData};
+use photo_sync_tst::membership::MemberUpdate;
+use photo_sync_tst::opaque_date::{Year, YearMonth, YearMonthDay};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: usize,
+    /// Number of random operations before the network is healed
+    pub steps: usize,
+    pub drop_probability: f64,
+    /// Probability of a network partition to happen (or heal) on each step
+    pub partition_probability: f64,
+    pub max_latency_ms: u64,
+    /// Calls that take longer than this fail, though the proposals still can be delivered later
+    pub timeout_ms: u64,
+    /// Number of sync rounds (every node syncs once per round) the network must converge in after healing
+    pub max_convergence_rounds: usize,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            nodes: 4,
+            steps: 60,
+            drop_probability: 0.05,
+            partition_probability: 0.1,
+            max_latency_ms: 100,
+            timeout_ms: 80,
+            max_convergence_rounds: 2,
+        }
+    }
+}
+
+/// Proposal that was sent, but is not delivered to the destination yet
+struct InFlight {
+    deliver_at: u64,
+    seq: u64,
+    to: usize,
+    proposal: Proposal,
+}
+
+struct NetState {
+    rng: ChaCha8Rng,
+    config: SimConfig,
+    faults_enabled: bool,
+    /// Virtual time, advanced by the latency of the calls
+    clock_ms: u64,
+    /// Partition group of each node, nodes can talk only within a group
+    groups: Vec<usize>,
+    in_flight: Vec<InFlight>,
+    next_seq: u64,
+    nodes: Vec<Arc<CatalogNode>>,
+    log: Vec<String>,
+}
+
+pub struct SimNetwork {
+    state: Mutex<NetState>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, config: SimConfig) -> Arc<Self> {
+        Arc::new(SimNetwork {
+            state: Mutex::new(NetState {
+                rng: ChaCha8Rng::seed_from_u64(seed),
+                groups: vec![0; config.nodes],
+                config,
+                faults_enabled: true,
+                clock_ms: 0,
+                in_flight: Vec::new(),
+                next_seq: 0,
+                nodes: Vec::new(),
+                log: Vec::new(),
+            }),
+        })
+    }
+
+    /// Creates the nodes and connects every node with every other node.
+    pub fn spawn_nodes(self: &Arc<Self>) -> Result<Vec<Arc<CatalogNode>>> {
+        let count = self.state.lock().unwrap().config.nodes;
+        let nodes = (0..count)
+            .map(|i| CatalogNode::test_new(&format!("n{}", i)).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        for (from, node) in nodes.iter().enumerate() {
+            for (to, target) in nodes.iter().enumerate() {
+                if from != to {
+                    node.add_peer(Arc::new(SimPeer {
+                        net: self.clone(),
+                        from,
+                        to,
+                        target: target.clone(),
+                    }))?;
+                }
+            }
+        }
+        self.state.lock().unwrap().nodes = nodes.clone();
+        Ok(nodes)
+    }
+
+    pub fn with_rng<T>(&self, f: impl FnOnce(&mut ChaCha8Rng) -> T) -> T {
+        f(&mut self.state.lock().unwrap().rng)
+    }
+
+    pub fn log(&self, entry: String) {
+        let mut state = self.state.lock().unwrap();
+        let entry = format!("[{:>6}ms] {}", state.clock_ms, entry);
+        state.log.push(entry);
+    }
+
+    pub fn dump_log(&self) -> String {
+        self.state.lock().unwrap().log.join("\n")
+    }
+
+    pub fn set_faults_enabled(&self, enabled: bool) {
+        self.state.lock().unwrap().faults_enabled = enabled;
+    }
+
+    /// Splits nodes into two random groups
+    pub fn partition(&self) {
+        let mut state = self.state.lock().unwrap();
+        let count = state.groups.len();
+        let groups = (0..count).map(|_| state.rng.gen_range(0..2)).collect();
+        state.groups = groups;
+        let entry = format!("partition {:?}", state.groups);
+        state.log.push(entry);
+    }
+
+    pub fn heal(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.groups.iter_mut().for_each(|g| *g = 0);
+        state.log.push("heal".into());
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.groups.iter().any(|g| *g != state.groups[0])
+    }
+
+    /// Advances the virtual clock and delivers proposals that are due.
+    pub fn advance(&self, ms: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.clock_ms += ms;
+        deliver_due(&mut state)
+    }
+
+    /// Delivers all the proposals in flight.
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.clock_ms += state.config.max_latency_ms;
+        deliver_due(&mut state)
+    }
+
+    /// Checks if the call from one node to another passes through the network,
+    /// and advances the clock by the latency of the call.
+    /// Returns the latency.
+    fn transmit(&self, from: usize, to: usize) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        if !state.faults_enabled {
+            return Ok(0);
+        }
+        if state.groups[from] != state.groups[to] {
+            return Err(anyhow!("n{} -> n{}: partitioned", from, to));
+        }
+        let drop_probability = state.config.drop_probability;
+        if state.rng.gen_bool(drop_probability) {
+            return Err(anyhow!("n{} -> n{}: dropped", from, to));
+        }
+        let max_latency_ms = state.config.max_latency_ms;
+        let latency = state.rng.gen_range(0..=max_latency_ms);
+        state.clock_ms += latency;
+        deliver_due(&mut state)?;
+        if latency > state.config.timeout_ms {
+            return Err(anyhow!("n{} -> n{}: timeout", from, to));
+        }
+        Ok(latency)
+    }
+
+    /// Puts the proposal in flight, it is delivered when the clock passes the delivery time,
+    /// so that proposals sent later may arrive earlier.
+    fn send_proposal(&self, to: usize, proposal: &Proposal) {
+        let mut state = self.state.lock().unwrap();
+        let latency = if state.faults_enabled {
+            let max_latency_ms = state.config.max_latency_ms;
+            state.rng.gen_range(0..=max_latency_ms)
+        } else {
+            0
+        };
+        let deliver_at = state.clock_ms + latency;
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.in_flight.push(InFlight {
+            deliver_at,
+            seq,
+            to,
+            proposal: proposal.clone(),
+        });
+    }
+}
+
+fn deliver_due(state: &mut NetState) -> Result<()> {
+    let clock_ms = state.clock_ms;
+    let (mut due, pending): (Vec<InFlight>, Vec<InFlight>) = state
+        .in_flight
+        .drain(..)
+        .partition(|m| m.deliver_at <= clock_ms);
+    state.in_flight = pending;
+    due.sort_by_key(|m| (m.deliver_at, m.seq));
+    for message in due {
+        let entry = format!(
+            "deliver #{} to n{}: day {}",
+            message.seq, message.to, message.proposal.ymd
+        );
+        state.log.push(entry);
+        state.nodes[message.to].propose(&message.proposal)?;
+    }
+    Ok(())
+}
+
+/// Connection from one simulated node to another.
+pub struct SimPeer {
+    net: Arc<SimNetwork>,
+    from: usize,
+    to: usize,
+    target: Arc<CatalogNode>,
+}
+
+impl RemotePeer for SimPeer {
+    fn id(&self) -> Vec<u8> {
+        self.target.id()
+    }
+
+    fn notify_added_by(&self, peer: Arc<dyn RemotePeer>) {
+        self.target.notify_added_by(peer)
+    }
+
+    fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.hash_algorithm()
+    }
+
+    fn get_years_checksums(&self) -> Result<Vec<(Year, Checksum)>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_years_checksums()
+    }
+
+    fn get_months_checksum(&self, y: Year) -> Result<Vec<(YearMonth, Checksum)>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_months_checksum(y)
+    }
+
+    fn get_days_checksum(&self, ym: YearMonth) -> Result<Vec<(YearMonthDay, Checksum)>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_days_checksum(ym)
+    }
+
+    fn get_existing_days_in_range(
+        &self,
+        ymd_from: YearMonthDay,
+        ymd_to: YearMonthDay,
+    ) -> Result<Vec<YearMonthDay>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_existing_days_in_range(ymd_from, ymd_to)
+    }
+
+    fn get_range_checksum(&self, ymd_from: YearMonthDay, ymd_to: YearMonthDay) -> Result<Checksum> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_range_checksum(ymd_from, ymd_to)
+    }
+
+    fn get_data(&self, ymd: u32) -> Result<Option<DayData>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_data(ymd)
+    }
+
+    fn get_location_claims(&self, ymd: YearMonthDay) -> Result<Vec<LocationClaim>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_location_claims(ymd)
+    }
+
+    /// Proposals are asynchronous messages,
+    /// so the resulting checksum is not known at the moment of sending.
+    fn propose(&self, proposal: &Proposal) -> Result<Vec<u8>> {
+        self.net.transmit(self.from, self.to)?;
+        self.net.send_proposal(self.to, proposal);
+        Ok(Vec::new())
+    }
+
+    fn get_blob(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_blob(id)
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<ChangeFeed> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.changes_since(seq)
+    }
+
+    fn gossip(&self, members: Vec<MemberUpdate>) -> Result<Vec<MemberUpdate>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.gossip(members)
+    }
+
+    fn probe(&self, target: &[u8]) -> Result<bool> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.probe(target)
+    }
+
+    fn get_albums_checksums(&self) -> Result<Vec<(String, Checksum)>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_albums_checksums()
+    }
+
+    fn get_album(&self, name: &str) -> Result<Option<Album>> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.get_album(name)
+    }
+
+    fn merge_album(&self, proposal: &AlbumProposal) -> Result<Checksum> {
+        self.net.transmit(self.from, self.to)?;
+        self.target.merge_album(proposal)
+    }
+}
+
+/// Runs random operations on the simulated network, then heals the network
+/// and checks that all the nodes converge to the same catalog.
+pub fn run_simulation(seed: u64, config: &SimConfig) -> Result<()> {
+    let (result, log) = simulate_with_log(seed, config);
+    result.map_err(|e| anyhow!("{}\nEvent log:\n{}", e, log))
+}
+
+/// Runs the simulation and returns its full event log, followed by the outcome.
+pub fn trace_simulation(seed: u64, config: &SimConfig) -> String {
+    let (result, log) = simulate_with_log(seed, config);
+    format!("{}\nOutcome: {:?}", log, result.map_err(|e| e.to_string()))
+}
+
+fn simulate_with_log(seed: u64, config: &SimConfig) -> (Result<()>, String) {
+    let net = SimNetwork::new(seed, config.clone());
+    let result = net
+        .spawn_nodes()
+        .and_then(|nodes| simulate(&net, &nodes, config));
+    (result, net.dump_log())
+}
+
+fn simulate(net: &SimNetwork, nodes: &[Arc<CatalogNode>], config: &SimConfig) -> Result<()> {
+    // Every object added anywhere, with the node that added it
+    let mut added: Vec<(YearMonthDay, Data, usize)> = Vec::new();
+
+    for _ in 0..config.steps {
+        let (op, node_idx, partition_roll) = net.with_rng(|r| {
+            (
+                r.gen_range(0..10),
+                r.gen_range(0..nodes.len()),
+                r.gen_bool(config.partition_probability),
+            )
+        });
+        if partition_roll {
+            if net.is_partitioned() {
+                net.heal();
+            } else {
+                net.partition();
+            }
+        }
+        match op {
+            // A new photo, days are taken from a narrow range to have collisions
+            0..=3 => {
+                let (ymd, id) = net.with_rng(|r| {
+                    let ymd = 20200000 + r.gen_range(1..=2) * 100 + r.gen_range(1..=3);
+                    (ymd, vec![r.gen::<u8>(), r.gen::<u8>()])
+                });
+                net.log(format!("n{} adds {:?} to {}", node_idx, id, ymd));
+                nodes[node_idx].add_photos(ymd, std::slice::from_ref(&id))?;
+                added.push((ymd, id, node_idx));
+            }
+            // A node obtains a copy of a photo known in the network
+            4 if !added.is_empty() => {
+                let idx = net.with_rng(|r| r.gen_range(0..added.len()));
+                let (ymd, id, _) = added[idx].clone();
+                net.log(format!("n{} copies {:?} of {}", node_idx, id, ymd));
+                nodes[node_idx].add_photos(ymd, std::slice::from_ref(&id))?;
+                added.push((ymd, id, node_idx));
+            }
+            // Sync may fail because of the network faults, it's retried later
+            5..=8 => {
+                let res = nodes[node_idx].sync_with_peers();
+                net.log(format!(
+                    "n{} syncs: {:?}",
+                    node_idx,
+                    res.map_err(|e| describe_error(nodes, &e))
+                ));
+            }
+            _ => {
+                let ms = net.with_rng(|r| r.gen_range(0..=config.max_latency_ms));
+                net.advance(ms)?;
+            }
+        }
+    }
+
+    net.log("healing the network".into());
+    net.heal();
+    net.set_faults_enabled(false);
+    net.flush()?;
+    for round in 0..config.max_convergence_rounds {
+        for (i, node) in nodes.iter().enumerate() {
+            node.sync_with_peers().map_err(|e| {
+                let e = describe_error(nodes, &e);
+                anyhow!("n{} failed to sync in round {}: {}", i, round, e)
+            })?;
+            net.flush()?;
+        }
+    }
+
+    check_convergence(nodes)?;
+    for (ymd, id, node_idx) in added {
+        let photos = nodes[0].get_data(ymd)?.unwrap_or_default().photos;
+        let labeled = photos
+            .iter()
+            .any(|(d, peers)| *d == id && peers.contains(&nodes[node_idx].id()));
+        ensure!(labeled, "{:?} of n{} is lost on {}", id, node_idx, ymd);
+    }
+    Ok(())
+}
+
+/// Node keys are random, so peers are referred by node names to keep the log reproducible.
+fn describe_error(nodes: &[Arc<CatalogNode>], e: &anyhow::Error) -> String {
+    match e.downcast_ref::<DistStoreError>() {
+        Some(DistStoreError::PartialSync { failed }) => {
+            let names = nodes
+                .iter()
+                .filter(|n| failed.contains(&n.id()))
+                .map(|n| n.name())
+                .collect::<Vec<_>>();
+            format!("Synchronization failed with peers {:?}", names)
+        }
+        _ => e.to_string(),
+    }
+}
+
+/// Checks that all the nodes have same checksums on all levels.
+pub fn check_convergence(nodes: &[Arc<CatalogNode>]) -> Result<()> {
+    let reference = &nodes[0];
+    let years = reference.get_years_checksums()?;
+    for (i, node) in nodes.iter().enumerate().skip(1) {
+        ensure!(
+            years == node.get_years_checksums()?,
+            "years of n{} differ from n0",
+            i
+        );
+        for (y, _) in &years {
+            let months = reference.get_months_checksum(*y)?;
+            ensure!(
+                months == node.get_months_checksum(*y)?,
+                "months of {} on n{} differ from n0",
+                y,
+                i
+            );
+            for (ym, _) in &months {
+                ensure!(
+                    reference.get_days_checksum(*ym)? == node.get_days_checksum(*ym)?,
+                    "days of {} on n{} differ from n0",
+                    ym,
+                    i
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+mod sim;
+
+use sim::{run_simulation, trace_simulation, SimConfig};
+
+/// Runs the simulation for a range of seeds.
+/// A failing seed can be replayed with `SIM_SEED=<seed> cargo test --test simulation_test`,
+/// and more seeds can be checked with `SIM_SEEDS=<count>`.
+#[test]
+fn test_random_operations_converge() {
+    let seeds: Vec<u64> = match std::env::var("SIM_SEED") {
+        Ok(seed) => vec![seed.parse().expect("SIM_SEED must be a number")],
+        Err(_) => {
+            let count = std::env::var("SIM_SEEDS")
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(20);
+            (0..count).collect()
+        }
+    };
+    let config = SimConfig::default();
+    for seed in seeds {
+        if let Err(e) = run_simulation(seed, &config) {
+            panic!(
+                "Simulation failed for seed {}, replay with SIM_SEED={}\n{}",
+                seed, seed, e
+            );
+        }
+    }
+}
+
+#[test]
+fn test_simulation_is_deterministic() {
+    let config = SimConfig {
+        drop_probability: 0.5,
+        ..SimConfig::default()
+    };
+    // Every call, delivery and partition is in the trace, so the runs must match step by step
+    let first = trace_simulation(7, &config);
+    let second = trace_simulation(7, &config);
+    assert!(first.contains("syncs"));
+    assert_eq!(first, second);
+}
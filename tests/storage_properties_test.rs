@@ -0,0 +1,129 @@
+//! Properties of the local storage merge, that the synchronization relies on:
+//! merges of day data are commutative, associative and idempotent,
+//! and checksums don't depend on the order the data came in.
+
+use photo_sync_tst::local_storage::{Checksum, LocalStorage, Photo};
+use photo_sync_tst::opaque_date::{Year, YearMonth, YearMonthDay};
+use proptest::prelude::*;
+
+/// Single call of `add_photos_to_day`
+type Batch = (YearMonthDay, Vec<Photo>);
+
+/// Days are taken from a narrow range, so that batches often hit the same days and months
+fn ymd_strategy() -> impl Strategy<Value = YearMonthDay> {
+    (2019u32..=2020, 1u32..=2, 1u32..=3).prop_map(|(y, m, d)| y * 10000 + m * 100 + d)
+}
+
+/// Small IDs and labels, so that same objects appear in different batches
+fn photo_strategy() -> impl Strategy<Value = Photo> {
+    (
+        prop::collection::vec(0u8..6, 1..=2),
+        prop::collection::vec(prop::collection::vec(0u8..3, 1), 0..=3),
+    )
+}
+
+fn batch_strategy() -> impl Strategy<Value = Batch> {
+    (
+        ymd_strategy(),
+        prop::collection::vec(photo_strategy(), 0..=4),
+    )
+}
+
+/// Everything that is visible to other peers
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    years: Vec<(Year, Checksum)>,
+    months: Vec<(YearMonth, Checksum)>,
+    days: Vec<(YearMonthDay, Checksum)>,
+    photos: Vec<(YearMonthDay, Option<Vec<Photo>>)>,
+}
+
+fn apply(batches: &[Batch]) -> Snapshot {
+    let storage = LocalStorage::test_new().unwrap();
+    for (ymd, photos) in batches {
+        storage.add_photos_to_day(*ymd, photos).unwrap();
+    }
+    snapshot(&storage)
+}
+
+fn snapshot(storage: &LocalStorage) -> Snapshot {
+    let years = storage.get_years_checksums().unwrap();
+    let mut months = Vec::new();
+    let mut days = Vec::new();
+    for (y, _) in &years {
+        for (ym, checksum) in storage.get_months_checksum(*y).unwrap() {
+            days.extend(storage.get_days_checksum(ym).unwrap());
+            months.push((ym, checksum));
+        }
+    }
+    let photos = days
+        .iter()
+        .map(|(ymd, _)| (*ymd, storage.get_photos(*ymd).unwrap()))
+        .collect();
+    Snapshot {
+        years,
+        months,
+        days,
+        photos,
+    }
+}
+
+/// Joins two batches for the same day into one
+fn union(a: &[Photo], b: &[Photo]) -> Vec<Photo> {
+    [a, b].concat()
+}
+
+proptest! {
+    // Each case creates fresh databases, which is the slow part of these tests
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn merge_is_commutative(a in batch_strategy(), b in batch_strategy()) {
+        prop_assert_eq!(apply(&[a.clone(), b.clone()]), apply(&[b, a]));
+    }
+
+    #[test]
+    fn merge_is_associative(
+        ymd in ymd_strategy(),
+        a in prop::collection::vec(photo_strategy(), 0..=4),
+        b in prop::collection::vec(photo_strategy(), 0..=4),
+        c in prop::collection::vec(photo_strategy(), 0..=4),
+    ) {
+        // (a + b) + c == a + (b + c)
+        let left = apply(&[(ymd, union(&a, &b)), (ymd, c.clone())]);
+        let right = apply(&[(ymd, a), (ymd, union(&b, &c))]);
+        prop_assert_eq!(left, right);
+    }
+
+    #[test]
+    fn merge_is_idempotent(batches in prop::collection::vec(batch_strategy(), 1..=5)) {
+        let twice = [batches.clone(), batches.clone()].concat();
+        prop_assert_eq!(apply(&batches), apply(&twice));
+    }
+
+    #[test]
+    fn checksums_do_not_depend_on_order(
+        batches in prop::collection::vec(batch_strategy(), 1..=6).prop_shuffle(),
+        seed in any::<u64>(),
+    ) {
+        let mut shuffled = batches.clone();
+        // Reverse or rotate, driven by the seed, to get another order of the same batches
+        if seed % 2 == 0 {
+            shuffled.reverse();
+        } else {
+            shuffled.rotate_left(seed as usize % batches.len());
+        }
+        prop_assert_eq!(apply(&batches), apply(&shuffled));
+    }
+
+    #[test]
+    fn labels_and_ids_are_sorted_and_unique(batches in prop::collection::vec(batch_strategy(), 1..=5)) {
+        for (_, photos) in apply(&batches).photos {
+            let photos = photos.unwrap();
+            prop_assert!(photos.windows(2).all(|w| w[0].0 < w[1].0));
+            for (_, peers) in photos {
+                prop_assert!(peers.windows(2).all(|w| w[0] < w[1]));
+            }
+        }
+    }
+}
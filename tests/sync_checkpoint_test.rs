@@ -0,0 +1,68 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, RemotePeer};
+use photo_sync_tst::faulty_peer::{Fault, FaultyPeer};
+use photo_sync_tst::local_storage::TransferDirection;
+use photo_sync_tst::metrics::PeerMethod;
+
+const DAYS: [u32; 5] = [20200101, 20200102, 20200103, 20200104, 20200105];
+
+/// Node and peer that have different photos on the same days.
+/// The third proposal to the peer fails, interrupting the first sync.
+fn diverged_nodes() -> Result<(CatalogNode, Arc<FaultyPeer<CatalogNode>>)> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    for (i, ymd) in DAYS.iter().enumerate() {
+        node.add_photos(*ymd, &[img!(i as u8)])?;
+        peer.add_photos(*ymd, &[img!(10 + i as u8)])?;
+    }
+    let faulty = Arc::new(
+        FaultyPeer::new(peer).with_script(PeerMethod::Propose, vec![None, None, Some(Fault::Fail)]),
+    );
+    node.add_peer(faulty.clone())?;
+    Ok((node, faulty))
+}
+
+#[test]
+fn test_interrupted_sync_resumes() -> Result<()> {
+    let (node, peer) = diverged_nodes()?;
+    assert!(node.sync_with_peers().is_err());
+    assert_eq!(3, peer.calls(PeerMethod::Propose));
+
+    // The first day changes after it has been transferred, so it has to be transferred again
+    node.add_photos(DAYS[0], &[img!(100)])?;
+    node.sync_with_peers()?;
+
+    assert_eq!(3 + 4, peer.calls(PeerMethod::Propose));
+    assert_eq!(node.get_years_checksums()?, peer.get_years_checksums()?);
+    // Checkpoints are not needed once the sync has completed
+    assert_eq!(
+        None,
+        node.storage()
+            .get_sync_checkpoint(&peer.id(), TransferDirection::Push, DAYS[0])?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_resumed_tree_walk_skips_pulled_days() -> Result<()> {
+    let (node, peer) = diverged_nodes()?;
+    // Without the change logs, the days are transferred by the checksum tree walk
+    node.storage().truncate_change_log(u64::MAX)?;
+    peer.inner().storage().truncate_change_log(u64::MAX)?;
+    assert!(node.sync_with_peers().is_err());
+    assert_eq!(5, peer.calls(PeerMethod::GetData));
+
+    node.storage().truncate_change_log(u64::MAX)?;
+    peer.inner().storage().truncate_change_log(u64::MAX)?;
+    node.sync_with_peers()?;
+
+    // All the days have been pulled already, and only the days that were not pushed are pushed
+    assert_eq!(5, peer.calls(PeerMethod::GetData));
+    assert_eq!(3 + 3, peer.calls(PeerMethod::Propose));
+    assert_eq!(node.get_years_checksums()?, peer.get_years_checksums()?);
+    Ok(())
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, PeerSyncPlan, RemotePeer};
+use photo_sync_tst::faulty_peer::FaultyPeer;
+use photo_sync_tst::identity::{NodeIdentity, Proposal};
+use photo_sync_tst::metrics::PeerMethod;
+use photo_sync_tst::sync_options::{PeerOptions, SyncDirection};
+
+fn with_direction(direction: SyncDirection) -> PeerOptions {
+    PeerOptions {
+        direction,
+        ..PeerOptions::default()
+    }
+}
+
+/// Node and a peer that both have a day of their own and a day they disagree on
+fn node_and_peer() -> Result<(CatalogNode, Arc<CatalogNode>)> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    node.add_photos(20200101, &[img!(1)])?;
+    node.add_photos(20200303, &[img!(3)])?;
+    peer.add_photos(20210101, &[img!(2)])?;
+    peer.add_photos(20200303, &[img!(4)])?;
+    Ok((node, peer))
+}
+
+#[test]
+fn test_pull_only_peer() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let peer_before = peer.get_years_checksums()?;
+    node.add_peer_with_options(peer.clone(), None, with_direction(SyncDirection::PullOnly))?;
+
+    node.sync_with_peers()?;
+
+    assert_eq!(peer_before, peer.get_years_checksums()?);
+    assert_eq!((3, 4), node.storage().count_days_and_objects()?);
+    Ok(())
+}
+
+#[test]
+fn test_push_only_peer() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let node_before = node.get_years_checksums()?;
+    node.add_peer_with_options(peer.clone(), None, with_direction(SyncDirection::PushOnly))?;
+
+    node.sync_with_peers()?;
+
+    assert_eq!(node_before, node.get_years_checksums()?);
+    assert_eq!((3, 4), peer.storage().count_days_and_objects()?);
+    Ok(())
+}
+
+#[test]
+fn test_one_way_resync_transfers_nothing() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let faulty = Arc::new(FaultyPeer::new(peer.clone()));
+    node.add_peer_with_options(
+        faulty.clone(),
+        None,
+        with_direction(SyncDirection::PushOnly),
+    )?;
+    node.sync_with_peers()?;
+    let proposed = faulty.calls(PeerMethod::Propose);
+    assert_eq!(2, proposed);
+
+    // The trees still differ, but the pushed days are not proposed again
+    node.sync_with_peers()?;
+    assert_eq!(proposed, faulty.calls(PeerMethod::Propose));
+    assert_eq!(0, faulty.calls(PeerMethod::GetData));
+    Ok(())
+}
+
+#[test]
+fn test_plan_sync_respects_direction() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    node.add_peer_with_options(peer.clone(), None, with_direction(SyncDirection::PullOnly))?;
+
+    assert_eq!(
+        vec![PeerSyncPlan {
+            peer: peer.id(),
+            pull: vec![20200303, 20210101],
+            push: vec![],
+        }],
+        node.plan_sync()?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_read_only_node_refuses_proposals() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    node.set_read_only(true);
+    let node = Arc::new(node);
+    peer.add_peer(node.clone())?;
+
+    let proposer = NodeIdentity::generate();
+    let proposal = Proposal::new(&proposer, 20210101, vec![(img!(5), vec![])], vec![]);
+    let err = node.propose(&proposal).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::ReadOnlyNode)
+    ));
+    let err = peer.sync_with_peers().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::PartialSync { failed }) if failed == &vec![node.id()]
+    ));
+    assert_eq!((2, 2), node.storage().count_days_and_objects()?);
+
+    // The read-only node can still pull from its peers
+    node.add_peer_with_options(peer.clone(), None, with_direction(SyncDirection::PullOnly))?;
+    node.sync_with_peers()?;
+    assert_eq!((3, 4), node.storage().count_days_and_objects()?);
+    Ok(())
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, PeerSyncPlan, RemotePeer};
+use photo_sync_tst::sync_options::{CancellationToken, SyncOptions, SyncProgress};
+use photo_sync_tst::trace_context::Partition;
+
+/// Node and a peer that has photos on five days the node doesn't have
+fn node_and_peer() -> Result<(CatalogNode, Arc<CatalogNode>)> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    for (i, ymd) in [20200101, 20200102, 20210301, 20210302, 20210303]
+        .into_iter()
+        .enumerate()
+    {
+        peer.add_photos(ymd, &[img!(i as u8)])?;
+    }
+    node.add_photos(20220101, &[img!(100)])?;
+    node.add_peer(peer.clone())?;
+    Ok((node, peer))
+}
+
+#[test]
+fn test_progress_is_reported() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let (tx, rx) = mpsc::channel();
+
+    node.sync_with_peers_with(&SyncOptions::default().with_progress(tx))?;
+
+    let reports: Vec<SyncProgress> = rx.try_iter().collect();
+    assert!(reports.iter().all(|r| r.peer == peer.id()));
+    assert!(reports
+        .windows(2)
+        .all(|w| w[0].completed_days <= w[1].completed_days));
+    assert!(reports
+        .iter()
+        .any(|r| r.partition == Some(Partition::Day(20210302))));
+    let last = reports.last().unwrap();
+    // 5 days pulled and 1 pushed
+    assert_eq!((6, 6), (last.total_days, last.completed_days));
+    Ok(())
+}
+
+#[test]
+fn test_cancelled_sync_leaves_catalog_consistent() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let token = CancellationToken::new();
+    let options = SyncOptions::default()
+        .with_cancellation(token.clone())
+        .with_progress(move |progress: &SyncProgress| {
+            if progress.completed_days == 2 {
+                token.cancel();
+            }
+        });
+
+    let err = node.sync_with_peers_with(&options).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::SyncCancelled)
+    ));
+    node.storage().verify_consistency()?;
+    // Own day, and the two days pulled before the cancellation
+    assert_eq!(1 + 2, node.storage().count_days_and_objects()?.0);
+
+    // The next sync completes what the cancelled one has started
+    node.sync_with_peers()?;
+    assert_eq!(node.get_years_checksums()?, peer.get_years_checksums()?);
+    Ok(())
+}
+
+#[test]
+fn test_cancellation_is_checked_between_albums() -> Result<()> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    node.add_peer(peer.clone())?;
+    node.create_album("a")?;
+    node.create_album("b")?;
+    let token = CancellationToken::new();
+    let entered = AtomicUsize::new(0);
+    let options = SyncOptions::default()
+        .with_cancellation(token.clone())
+        .with_progress(move |progress: &SyncProgress| {
+            // The start of the sync, then the first album
+            if progress.partition.is_none() && entered.fetch_add(1, Ordering::SeqCst) == 1 {
+                token.cancel();
+            }
+        });
+
+    let err = node.sync_with_peers_with(&options).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::SyncCancelled)
+    ));
+    assert!(peer.get_album("a")?.is_some());
+    assert!(peer.get_album("b")?.is_none());
+    Ok(())
+}
+
+#[test]
+fn test_plan_sync_transfers_nothing() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let before = (node.get_years_checksums()?, peer.get_years_checksums()?);
+
+    let plan = node.plan_sync()?;
+
+    assert_eq!(
+        vec![PeerSyncPlan {
+            peer: peer.id(),
+            pull: vec![20200101, 20200102, 20210301, 20210302, 20210303],
+            push: vec![20220101],
+        }],
+        plan
+    );
+    assert_eq!(
+        before,
+        (node.get_years_checksums()?, peer.get_years_checksums()?)
+    );
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Properties of the synchronization: whatever the nodes had before,
+//! after a sync the nodes agree on checksums of all levels and on the data.
+
+mod common;
+mod sim;
+
+use std::sync::Arc;
+
+use photo_sync_tst::catalog::{CatalogNode, RemotePeer};
+use photo_sync_tst::opaque_date::YearMonthDay;
+use proptest::prelude::*;
+
+/// Additions of object IDs made on a node before the sync
+type Additions = Vec<(YearMonthDay, Vec<u8>)>;
+
+fn additions_strategy() -> impl Strategy<Value = Additions> {
+    prop::collection::vec(
+        (
+            (2019u32..=2020, 1u32..=2, 1u32..=3).prop_map(|(y, m, d)| y * 10000 + m * 100 + d),
+            prop::collection::vec(0u8..8, 1..=3),
+        ),
+        0..=6,
+    )
+}
+
+fn node_with(name: &str, additions: &Additions) -> Arc<CatalogNode> {
+    let node = Arc::new(CatalogNode::test_new(name).unwrap());
+    for (ymd, ids) in additions {
+        let ids: Vec<_> = ids.iter().map(|i| img!(*i)).collect();
+        node.add_photos(*ymd, &ids).unwrap();
+    }
+    node
+}
+
+proptest! {
+    // Each case creates fresh nodes, which is the slow part of these tests
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn pairwise_sync_converges(a in additions_strategy(), b in additions_strategy()) {
+        let n1 = node_with("n1", &a);
+        let n2 = node_with("n2", &b);
+        n1.add_peer(n2.clone()).unwrap();
+        n2.add_peer(n1.clone()).unwrap();
+
+        n1.sync_with_peers().unwrap();
+
+        let nodes = [n1.clone(), n2.clone()];
+        prop_assert!(sim::check_convergence(&nodes).is_ok());
+        for (ymd, _) in a.iter().chain(b.iter()) {
+            prop_assert_eq!(n1.get_data(*ymd).unwrap(), n2.get_data(*ymd).unwrap());
+        }
+    }
+
+    #[test]
+    fn repeated_sync_changes_nothing(a in additions_strategy(), b in additions_strategy()) {
+        let n1 = node_with("n1", &a);
+        let n2 = node_with("n2", &b);
+        n1.add_peer(n2.clone()).unwrap();
+        n2.add_peer(n1.clone()).unwrap();
+
+        n1.sync_with_peers().unwrap();
+        let before = n1.get_years_checksums().unwrap();
+        n2.sync_with_peers().unwrap();
+        prop_assert_eq!(before.clone(), n1.get_years_checksums().unwrap());
+        prop_assert_eq!(before, n2.get_years_checksums().unwrap());
+    }
+}
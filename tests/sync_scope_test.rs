@@ -0,0 +1,179 @@
+mod common;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, PeerSyncPlan, RemotePeer};
+use photo_sync_tst::faulty_peer::FaultyPeer;
+use photo_sync_tst::metrics::PeerMethod;
+use photo_sync_tst::opaque_date::YearMonthDay;
+use photo_sync_tst::sync_options::{PeerOptions, SyncOptions, SyncScope};
+
+fn with_scope(scope: SyncScope) -> PeerOptions {
+    PeerOptions {
+        scope,
+        ..PeerOptions::default()
+    }
+}
+
+/// Days the node has a photo on
+fn days(node: &CatalogNode) -> Result<Vec<YearMonthDay>> {
+    let mut result = Vec::new();
+    for (y, _) in node.get_years_checksums()? {
+        for (ym, _) in node.get_months_checksum(y)? {
+            result.extend(node.get_days_checksum(ym)?.into_iter().map(|(ymd, _)| ymd));
+        }
+    }
+    Ok(result)
+}
+
+/// Node and a peer with days around the bounds of the 2020-03-15 - 2021-06-15 range
+fn node_and_peer() -> Result<(CatalogNode, Arc<CatalogNode>)> {
+    let node = CatalogNode::test_new("node")?;
+    let peer = Arc::new(CatalogNode::test_new("peer")?);
+    for (i, ymd) in [20190101, 20200310, 20200320, 20210610, 20210620, 20220101]
+        .into_iter()
+        .enumerate()
+    {
+        peer.add_photos(ymd, &[img!(i as u8)])?;
+    }
+    for (i, ymd) in [20200301, 20200316, 20210615, 20210616]
+        .into_iter()
+        .enumerate()
+    {
+        node.add_photos(ymd, &[img!(100 + i as u8)])?;
+    }
+    Ok((node, peer))
+}
+
+#[test]
+fn test_peer_date_range() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    node.add_peer_with_options(
+        peer.clone(),
+        None,
+        with_scope(SyncScope::date_range(20200315, 20210615)),
+    )?;
+
+    node.sync_with_peers()?;
+
+    assert_eq!(
+        vec![20200301, 20200316, 20200320, 20210610, 20210615, 20210616],
+        days(&node)?
+    );
+    assert_eq!(
+        vec![20190101, 20200310, 20200316, 20200320, 20210610, 20210615, 20210620, 20220101],
+        days(&peer)?
+    );
+
+    // The days in the scope are equal now, so the partially covered years and months are not transferred again
+    let plan = node.plan_sync()?;
+    assert_eq!(
+        vec![PeerSyncPlan {
+            peer: peer.id(),
+            ..PeerSyncPlan::default()
+        }],
+        plan
+    );
+    Ok(())
+}
+
+#[test]
+fn test_sync_years() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    node.add_peer(peer.clone())?;
+
+    let options = SyncOptions::default().with_scope(SyncScope::years([2019, 2021]));
+    assert_eq!(
+        vec![PeerSyncPlan {
+            peer: peer.id(),
+            pull: vec![20190101, 20210610, 20210620],
+            push: vec![20210615, 20210616],
+        }],
+        node.plan_sync_with(&options)?
+    );
+    node.sync_with_peers_with(&options)?;
+
+    assert_eq!(
+        vec![20190101, 20200301, 20200316, 20210610, 20210615, 20210616, 20210620],
+        days(&node)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_peer_and_sync_scopes_are_combined() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    node.add_peer_with_options(
+        peer.clone(),
+        None,
+        with_scope(SyncScope::date_range(20200315, 20210615)),
+    )?;
+
+    let options = SyncOptions::default().with_scope(SyncScope::years([2021]));
+    assert_eq!(
+        vec![PeerSyncPlan {
+            peer: peer.id(),
+            pull: vec![20210610],
+            push: vec![20210615],
+        }],
+        node.plan_sync_with(&options)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_changed_days_out_of_scope_are_not_transferred() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    node.add_peer_with_options(peer.clone(), None, with_scope(SyncScope::years([2020])))?;
+    node.sync_with_peers()?;
+
+    // Both days are in the change log of the peer, but only one is in the scope
+    peer.add_photos(20200101, &[img!(200)])?;
+    peer.add_photos(20230101, &[img!(201)])?;
+    node.sync_with_peers()?;
+
+    let synced = days(&node)?;
+    assert!(synced.contains(&20200101));
+    assert!(!synced.contains(&20230101));
+    Ok(())
+}
+
+#[test]
+fn test_partial_partitions_equal_in_scope_are_not_walked() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let faulty = Arc::new(FaultyPeer::new(peer.clone()));
+    node.add_peer_with_options(
+        faulty.clone(),
+        None,
+        with_scope(SyncScope::date_range(20200315, 20210615)),
+    )?;
+    node.sync_with_peers()?;
+
+    // The year and the month of the day differ now, but not in the scope
+    peer.add_photos(20200311, &[img!(200)])?;
+    let months_calls = faulty.calls(PeerMethod::GetMonthsChecksum);
+    let days_calls = faulty.calls(PeerMethod::GetDaysChecksum);
+    let plan = node.plan_sync()?;
+
+    assert!(plan[0].pull.is_empty() && plan[0].push.is_empty());
+    assert_eq!(months_calls, faulty.calls(PeerMethod::GetMonthsChecksum));
+    assert_eq!(days_calls, faulty.calls(PeerMethod::GetDaysChecksum));
+
+    // A day in the scope of the same month is still found
+    peer.add_photos(20200317, &[img!(201)])?;
+    assert_eq!(vec![20200317], node.plan_sync()?[0].pull);
+    Ok(())
+}
+
+#[test]
+fn test_peer_scope_is_persisted() -> Result<()> {
+    let (node, peer) = node_and_peer()?;
+    let scope = SyncScope::date_range(20200315, 20210615);
+    node.add_peer_with_options(peer.clone(), None, with_scope(scope.clone()))?;
+
+    let record = node.storage().get_peer(&peer.id())?.unwrap();
+    assert_eq!(scope, record.scope);
+    assert_eq!(Some(with_scope(scope)), node.peer_options(&peer.id()));
+    Ok(())
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::Result;
+use photo_sync_tst::catalog::CatalogNode;
+use photo_sync_tst::transport::{NetworkPeer, PeerServer};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+/// Span name along with its fields, including the recorded later
+#[derive(Debug, Clone)]
+struct SpanRecord {
+    name: &'static str,
+    fields: BTreeMap<&'static str, String>,
+}
+
+impl SpanRecord {
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(|v| v.as_str())
+    }
+}
+
+struct FieldsVisitor<'a>(&'a mut BTreeMap<&'static str, String>);
+
+impl Visit for FieldsVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+}
+
+/// Layer that keeps all the spans created in the process
+#[derive(Clone, Default)]
+struct Capture {
+    spans: Arc<Mutex<Vec<SpanRecord>>>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Capture {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = BTreeMap::new();
+        attrs.record(&mut FieldsVisitor(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        ctx.span(id).unwrap().extensions_mut().insert(spans.len());
+        spans.push(SpanRecord {
+            name: attrs.metadata().name(),
+            fields,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let index = *span.extensions().get::<usize>().unwrap();
+        values.record(&mut FieldsVisitor(
+            &mut self.spans.lock().unwrap()[index].fields,
+        ));
+    }
+}
+
+/// Spans are created in server threads as well, so the subscriber has to be global
+fn captured() -> &'static Capture {
+    static CAPTURE: OnceLock<Capture> = OnceLock::new();
+    CAPTURE.get_or_init(|| {
+        let capture = Capture::default();
+        tracing::subscriber::set_global_default(Registry::default().with(capture.clone())).unwrap();
+        capture
+    })
+}
+
+/// Spans that belong to the trace of the sync made by given node
+fn spans_of_sync(node: &CatalogNode) -> (String, Vec<SpanRecord>) {
+    let spans = captured().spans.lock().unwrap().clone();
+    let trace_id = spans
+        .iter()
+        .find(|s| s.name == "sync_with_peers" && s.field("node") == Some(node.name()))
+        .and_then(|s| s.field("trace_id"))
+        .unwrap()
+        .to_string();
+    (trace_id, spans)
+}
+
+#[test]
+fn test_sync_spans_carry_partition_fields() -> Result<()> {
+    captured();
+    let n1 = Arc::new(CatalogNode::test_new("traced1")?);
+    let n2 = Arc::new(CatalogNode::test_new("traced2")?);
+    n1.add_peer(n2.clone())?;
+    n2.add_photos(20200101, &[img!(1)])?;
+    n2.add_photos(20210303, &[img!(2)])?;
+    // The change log would transfer the days without walking the tree
+    n2.storage().truncate_change_log(2)?;
+
+    n1.sync_with_peers()?;
+
+    let (_, spans) = spans_of_sync(&n1);
+    // Spans of other tests are captured as well, so they are told apart by the peer ID
+    let peer = n2
+        .id()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let peer = Some(peer.as_str());
+    assert!(spans
+        .iter()
+        .any(|s| s.name == "sync_with_peer" && s.field("peer") == peer));
+    assert!(spans.iter().any(|s| s.name == "missing_days"
+        && s.field("src") == peer
+        && s.field("year") == Some("2021")
+        && s.field("month").is_none()));
+    assert!(spans.iter().any(|s| s.name == "transfer_day"
+        && s.field("year") == Some("2021")
+        && s.field("month") == Some("202103")
+        && s.field("day") == Some("20210303")));
+    assert!(spans.iter().any(|s| s.name == "remote_call"
+        && s.field("method") == Some("get_data")
+        && s.field("peer") == peer
+        && s.field("day") == Some("20200101")));
+    Ok(())
+}
+
+#[test]
+fn test_trace_id_is_carried_over_network() -> Result<()> {
+    captured();
+    let n1 = Arc::new(CatalogNode::test_new("traced_client")?);
+    let n2 = Arc::new(CatalogNode::test_new("traced_server")?);
+    n1.storage().authorize_peer(&n2.id(), n2.name())?;
+    n2.storage().authorize_peer(&n1.id(), n1.name())?;
+    let server = PeerServer::start(n2.clone(), "127.0.0.1:0")?;
+    n1.add_peer(Arc::new(NetworkPeer::connect(
+        &n1,
+        server.local_addr(),
+        &n2.id(),
+    )?))?;
+    n2.add_photos(20200101, &[img!(1)])?;
+
+    n1.sync_with_peers()?;
+
+    let (trace_id, spans) = spans_of_sync(&n1);
+    let served = spans
+        .iter()
+        .filter(|s| s.name == "serve_request" && s.field("trace_id") == Some(trace_id.as_str()))
+        .collect::<Vec<_>>();
+    assert!(served
+        .iter()
+        .all(|s| s.field("node") == Some("traced_server")));
+    assert!(served.iter().any(|s| s.field("method") == Some("get_data")));
+    Ok(())
+}
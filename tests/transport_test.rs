@@ -0,0 +1,245 @@
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use photo_sync_tst::catalog::{CatalogNode, DistStoreError, RemotePeer};
+use photo_sync_tst::identity::{NodeIdentity, Proposal};
+use photo_sync_tst::secure_channel::SecureChannel;
+use photo_sync_tst::transport::{NetworkPeer, PeerServer};
+
+fn authorize_each_other(a: &CatalogNode, b: &CatalogNode) -> Result<()> {
+    a.storage().authorize_peer(&b.id(), b.name())?;
+    b.storage().authorize_peer(&a.id(), a.name())?;
+    Ok(())
+}
+
+#[test]
+fn test_sync_over_network() -> Result<()> {
+    let node1 = Arc::new(CatalogNode::test_new("s1")?);
+    let node2 = Arc::new(CatalogNode::test_new("s2")?);
+    authorize_each_other(&node1, &node2)?;
+    let server2 = PeerServer::start(node2.clone(), "127.0.0.1:0")?;
+
+    let remote2 = Arc::new(NetworkPeer::connect(
+        &node1,
+        server2.local_addr(),
+        &node2.id(),
+    )?);
+    assert_eq!(node2.id(), remote2.id());
+    node1.add_peer(remote2.clone())?;
+
+    node1.add_photos(20210711, &[img!(0)])?;
+    let photo = node2.ingest_photo(20210712, b"photo")?;
+    node2.create_album("trip")?;
+    node2.add_to_album("trip", std::slice::from_ref(&photo))?;
+    node1.sync_with_peers()?;
+
+    assert_eq!(node1.get_years_checksums()?, node2.get_years_checksums()?);
+    assert_eq!(
+        Some(vec![(img!(0), vec![node1.id()])]),
+        node2.get_data(20210711)?.map(|day| day.photos)
+    );
+    assert_eq!(Some(b"photo".to_vec()), remote2.get_blob(&photo)?);
+    assert_eq!(node2.get_album("trip")?, node1.get_album("trip")?);
+    assert_eq!(
+        node1.get_albums_checksums()?,
+        remote2.get_albums_checksums()?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_catalog_errors_are_typed() -> Result<()> {
+    let node1 = Arc::new(CatalogNode::test_new("s1")?);
+    let node2 = Arc::new(CatalogNode::test_new("s2")?);
+    authorize_each_other(&node1, &node2)?;
+    let server2 = PeerServer::start(node2.clone(), "127.0.0.1:0")?;
+    node2.set_read_only(true);
+
+    let remote2 = NetworkPeer::connect(&node1, server2.local_addr(), &node2.id())?;
+    node1.create_album("trip")?;
+    let err = remote2
+        .merge_album(&node1.propose_album("trip")?.unwrap())
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::ReadOnlyNode)
+    ));
+
+    // Other errors of the catalog are typed too, e.g. a proposal of someone else
+    let proposal = Proposal::new(&NodeIdentity::generate(), 20210711, vec![], vec![]);
+    let err = remote2.propose(&proposal).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DistStoreError>(),
+        Some(DistStoreError::InvalidProposalSignature { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_unauthorized_client_is_rejected() -> Result<()> {
+    let node1 = Arc::new(CatalogNode::test_new("s1")?);
+    let node2 = Arc::new(CatalogNode::test_new("s2")?);
+    // Client trusts the server, but the server doesn't know the client
+    node1.storage().authorize_peer(&node2.id(), "s2")?;
+    let server2 = PeerServer::start(node2.clone(), "127.0.0.1:0")?;
+
+    let res = NetworkPeer::connect(&node1, server2.local_addr(), &node2.id());
+    assert!(res.is_err());
+
+    // Once authorised, the client can connect
+    node2.storage().authorize_peer(&node1.id(), "s1")?;
+    let remote2 = NetworkPeer::connect(&node1, server2.local_addr(), &node2.id())?;
+    assert!(remote2.get_years_checksums()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_server_identity_is_pinned() -> Result<()> {
+    let node1 = Arc::new(CatalogNode::test_new("s1")?);
+    let node2 = Arc::new(CatalogNode::test_new("s2")?);
+    let impostor = Arc::new(CatalogNode::test_new("impostor")?);
+    authorize_each_other(&node1, &node2)?;
+    authorize_each_other(&node1, &impostor)?;
+
+    // The impostor listens on the address where node1 expects node2
+    let server = PeerServer::start(impostor.clone(), "127.0.0.1:0")?;
+    let res = NetworkPeer::connect(&node1, server.local_addr(), &node2.id());
+    assert!(res.is_err());
+
+    // Connecting to a peer which is not in the allow-list is refused before any network interaction
+    let stranger = CatalogNode::test_new("stranger")?;
+    let res = NetworkPeer::connect(&node1, server.local_addr(), &stranger.id());
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_large_messages() -> Result<()> {
+    let node1 = Arc::new(CatalogNode::test_new("s1")?);
+    let node2 = Arc::new(CatalogNode::test_new("s2")?);
+    authorize_each_other(&node1, &node2)?;
+    let server2 = PeerServer::start(node2.clone(), "127.0.0.1:0")?;
+
+    // Larger than a single Noise message
+    let content = (0..200_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+    let photo = node2.ingest_photo(20210712, &content)?;
+
+    let remote2 = NetworkPeer::connect(&node1, server2.local_addr(), &node2.id())?;
+    assert_eq!(Some(content), remote2.get_blob(&photo)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_notified_server_connects_back() -> Result<()> {
+    let node1 = Arc::new(CatalogNode::test_new("s1")?);
+    let node2 = Arc::new(CatalogNode::test_new("s2")?);
+    authorize_each_other(&node1, &node2)?;
+    let server1 = PeerServer::start(node1.clone(), "127.0.0.1:0")?;
+    let server2 = PeerServer::start(node2.clone(), "127.0.0.1:0")?;
+    node1
+        .membership()
+        .set_address(server1.local_addr().to_string());
+
+    let remote2: Arc<dyn RemotePeer> = Arc::new(NetworkPeer::connect(
+        &node1,
+        server2.local_addr(),
+        &node2.id(),
+    )?);
+    node1.add_peer(remote2.clone())?;
+    remote2.notify_added_by(node1.clone());
+    // A repeated notification doesn't add the peer twice
+    remote2.notify_added_by(node1.clone());
+
+    // The server connects back in the background, the peer is recorded after it is added
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut record = node2.storage().get_peer(&node1.id())?;
+    while record.is_none() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+        record = node2.storage().get_peer(&node1.id())?;
+    }
+    assert_eq!(vec![node1.id()], node2.peer_ids());
+    let record = record.unwrap();
+    assert_eq!(vec![server1.local_addr().to_string()], record.addresses);
+    // The server side can now start a sync on its own
+    node1.add_photos(20210711, &[img!(0)])?;
+    node2.sync_with_peers()?;
+    assert_eq!(node1.get_years_checksums()?, node2.get_years_checksums()?);
+    Ok(())
+}
+
+#[test]
+fn test_stalled_handshake_is_disconnected() -> Result<()> {
+    let node = Arc::new(CatalogNode::test_new("s1")?);
+    let server =
+        PeerServer::start_with_io_timeout(node.clone(), "127.0.0.1:0", Duration::from_millis(200))?;
+
+    // Sends a part of the first handshake frame, and stalls
+    let mut stream = TcpStream::connect(server.local_addr())?;
+    stream.write_all(&[0, 32, 1])?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let started = Instant::now();
+    let res = stream.read(&mut [0u8; 1]);
+    assert!(matches!(res, Ok(0)) || res.is_err_and(|e| e.kind() == ErrorKind::ConnectionReset));
+    assert!(started.elapsed() < Duration::from_secs(10));
+    Ok(())
+}
+
+#[test]
+fn test_stalled_response_times_out() -> Result<()> {
+    let node = Arc::new(CatalogNode::test_new("s1")?);
+    let server = NodeIdentity::generate();
+    let server_id = server.peer_id();
+    node.storage().authorize_peer(&server_id, "stalled")?;
+
+    // Completes the handshake, reads the request, and never answers
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server_thread = thread::spawn(move || -> Result<()> {
+        let (stream, _) = listener.accept()?;
+        let mut channel = SecureChannel::accept(stream, &server, |_| true)?;
+        channel.recv()?;
+        // Closed by the client once it gives up
+        let _ = channel.recv();
+        Ok(())
+    });
+
+    let remote = NetworkPeer::connect_with_request_timeout(
+        &node,
+        addr,
+        &server_id,
+        Duration::from_millis(200),
+    )?;
+    let started = Instant::now();
+    assert!(remote.get_years_checksums().is_err());
+    assert!(started.elapsed() < Duration::from_secs(10));
+    // The connection is closed, so a late response can't be taken for the next one
+    assert!(remote.get_years_checksums().is_err());
+    server_thread.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn test_dropped_server_closes_connections() -> Result<()> {
+    let node1 = Arc::new(CatalogNode::test_new("s1")?);
+    let node2 = Arc::new(CatalogNode::test_new("s2")?);
+    authorize_each_other(&node1, &node2)?;
+    let server2 = PeerServer::start(node2.clone(), "127.0.0.1:0")?;
+    let remote2 = NetworkPeer::connect(&node1, server2.local_addr(), &node2.id())?;
+    assert!(remote2.get_years_checksums()?.is_empty());
+
+    // Threads of the open connections are gone along with the server
+    drop(server2);
+    assert_eq!(1, Arc::strong_count(&node2));
+    assert!(remote2.get_years_checksums().is_err());
+    Ok(())
+}